@@ -1,6 +1,7 @@
 DEST_DIR=$HOME/bin/modern-auth-service
 EXECUTABLE_NAME=modern-auth-service
 SERVICE_NAME=modern-auth-service.service
+ACTIVATION_FILE=modern-auth-service.json
 
 set -e
 
@@ -44,6 +45,9 @@ sudo mkdir -p "$DEST_DIR"
 sudo cp "$PWD/target/release/$EXECUTABLE_NAME" "$DEST_DIR"
 
 sudo cp "$PWD/linux/systemd/$SERVICE_NAME" "/etc/systemd/system/$SERVICE_NAME"
+# Lets the broker start the service when its object is called
+mkdir -p "$HOME/service-activation"
+cp "$PWD/linux/service-activation/$ACTIVATION_FILE" "$HOME/service-activation/"
 sudo chmod +x "$DEST_DIR/$EXECUTABLE_NAME"
 
 sudo systemctl daemon-reload
@@ -2,6 +2,7 @@ REMOTE_PC=lleonardo@enzotechcomputersolutions.com
 REMOTE_DEST_DIR=/home/lleonardo/bin/modern-auth-service
 SSH_KEY_PATH=$HOME/.ssh/linuxubuntu-enzo-tech-webserver.pem
 SERVICE_NAME=modern-auth-service.service
+ACTIVATION_FILE=modern-auth-service.json
 EXECUTABLE_NAME=modern-auth-service
 
 set -e
@@ -25,6 +26,7 @@ ssh -i $SSH_KEY_PATH $REMOTE_PC \
 echo "Copying new files to remote destination . . ."
 scp -i $SSH_KEY_PATH $PWD/target/release/$EXECUTABLE_NAME $REMOTE_PC:$REMOTE_DEST_DIR
 scp -i $SSH_KEY_PATH $PWD/linux/systemd/$SERVICE_NAME $REMOTE_PC:$REMOTE_DEST_DIR
+scp -i $SSH_KEY_PATH $PWD/linux/service-activation/$ACTIVATION_FILE $REMOTE_PC:$REMOTE_DEST_DIR
 
 # Set executable permission
 echo "Setting of permissions . . ."
@@ -35,6 +37,8 @@ ssh -i $SSH_KEY_PATH $REMOTE_PC \
 echo "Installing dependencies and systemd . . ."
 ssh -i $SSH_KEY_PATH $REMOTE_PC \
     "sudo mv $REMOTE_DEST_DIR/$SERVICE_NAME /etc/systemd/system/; \
+     mkdir -p ~/service-activation; \
+     mv $REMOTE_DEST_DIR/$ACTIVATION_FILE ~/service-activation/; \
      sudo systemctl daemon-reexec; \
      sudo systemctl daemon-reload; \
      sudo systemctl enable $SERVICE_NAME; \
@@ -1,15 +1,28 @@
 pub mod curl;
+pub mod redact;
 pub mod reqwest;
 
-use std::{future::Future, pin::Pin};
+use std::{
+    error::Error,
+    fmt::Display,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
-use oauth2::{AsyncHttpClient, HttpRequest, HttpResponse};
+use oauth2::{
+    AsyncHttpClient, ErrorResponseType, HttpRequest, HttpResponse, RequestTokenError,
+    StandardErrorResponse,
+};
 use strum_macros::{Display, EnumString};
 
 use crate::{
     http_client::{curl::Curl, reqwest::Reqwest},
     interface::Interface,
+    metrics::METRICS,
     oauth2::error::OAuth2Error,
+    telemetry::Span,
 };
 
 #[derive(Clone)]
@@ -18,6 +31,8 @@ where
     I: Interface + Clone + Send + Sync + 'static,
 {
     interface: I,
+    /// The last response that was not a success, shared by the clones.
+    error_response: Arc<Mutex<Option<HttpResponse>>>,
 }
 
 impl<I> OAuth2Client<I>
@@ -25,7 +40,29 @@ where
     I: Interface + Clone + Send + Sync + 'static,
 {
     pub fn new(interface: I) -> Self {
-        Self { interface }
+        Self {
+            interface,
+            error_response: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// `err` with what the provider's error response said, when the request
+    /// failed on one.
+    pub fn error<E, O>(&self, err: RequestTokenError<E, StandardErrorResponse<O>>) -> OAuth2Error
+    where
+        E: Error + 'static,
+        O: ErrorResponseType + 'static + ToString + Clone + Display,
+    {
+        let answered = matches!(
+            err,
+            RequestTokenError::ServerResponse(_) | RequestTokenError::Parse(..)
+        );
+        let response = self.error_response.lock().unwrap().take();
+        let error = OAuth2Error::from(err);
+        match response {
+            Some(response) if answered => error.with_response(&response),
+            _ => error,
+        }
     }
 }
 
@@ -39,8 +76,12 @@ where
 
     fn call(&'c self, request: HttpRequest) -> Self::Future {
         let interface = self.interface.clone();
+        let error_response = self.error_response.clone();
         Box::pin(async move {
             let result = interface.http_request(request).await?;
+            if !result.status().is_success() {
+                *error_response.lock().unwrap() = Some(result.clone());
+            }
             Ok(result)
         })
     }
@@ -51,3 +92,40 @@ pub enum HttpClient {
     Curl(Curl),
     Reqwest(Reqwest),
 }
+
+impl HttpClient {
+    pub async fn send(&self, mut request: HttpRequest) -> Result<HttpResponse, OAuth2Error> {
+        let host = request.uri().host().unwrap_or_default().to_string();
+        let mut span = Span::client(request.method().as_str());
+        span.set_attribute("http.request.method", request.method().as_str());
+        span.set_attribute("server.address", host.as_str());
+        span.set_attribute("url.path", request.uri().path());
+        span.inject(&mut request);
+
+        let started = Instant::now();
+        let result = match self {
+            HttpClient::Curl(curl) => curl.send(request).await,
+            HttpClient::Reqwest(reqwest) => reqwest.send(request).await,
+        };
+        let status = match &result {
+            Ok(response) => response.status().as_str().to_string(),
+            Err(_) => "error".to_string(),
+        };
+        match &result {
+            Ok(response) => {
+                span.set_attribute("http.response.status_code", response.status().as_u16());
+                if response.status().is_server_error() {
+                    span.fail(status.as_str());
+                }
+            }
+            Err(err) => span.fail(err.error_code_desc.as_str()),
+        }
+        METRICS
+            .http_requests
+            .inc(&[("host", &host), ("status", &status)]);
+        METRICS
+            .http_request_duration
+            .observe(&[("host", &host)], started.elapsed());
+        result
+    }
+}
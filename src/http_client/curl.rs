@@ -1,6 +1,9 @@
 use curl_http_client::{collector::Collector, dep::async_curl::CurlActor, http_client::HttpClient};
 
-use crate::oauth2::error::OAuth2Error;
+use crate::{
+    http_client::redact::{redact_body, redact_headers},
+    oauth2::error::OAuth2Error,
+};
 
 #[derive(Clone)]
 pub struct Curl {
@@ -21,9 +24,9 @@ impl Curl {
         request: oauth2::HttpRequest,
     ) -> Result<oauth2::HttpResponse, OAuth2Error> {
         log::debug!("Request Url: {}", request.uri());
-        log::debug!("Request Header: {:?}", request.headers());
+        log::debug!("Request Header: {}", redact_headers(request.headers()));
         log::debug!("Request Method: {}", request.method());
-        log::debug!("Request Body: {}", String::from_utf8_lossy(request.body()));
+        log::debug!("Request Body: {}", redact_body(request.body()));
 
         let response = HttpClient::new(Collector::RamAndHeaders(Vec::new(), Vec::new()))
             .request(request)?
@@ -33,11 +36,8 @@ impl Curl {
             .map(|resp| resp.unwrap_or_default());
 
         log::debug!("Response Status: {}", response.status());
-        log::debug!("Response Header: {:?}", response.headers());
-        log::debug!(
-            "Response Body: {}",
-            String::from_utf8_lossy(response.body())
-        );
+        log::debug!("Response Header: {}", redact_headers(response.headers()));
+        log::debug!("Response Body: {}", redact_body(response.body()));
         Ok(response)
     }
 }
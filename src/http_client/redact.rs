@@ -0,0 +1,192 @@
+use http::{HeaderMap, HeaderName};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// Form and JSON fields whose values are credentials and must never be logged.
+const SENSITIVE_FIELDS: &[&str] = &[
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "device_code",
+    "code",
+    "code_verifier",
+    "client_secret",
+    "client_assertion",
+    "assertion",
+    "password",
+    "token",
+    "token_secret",
+    "control_secret",
+];
+
+/// Headers that carry credentials or session state.
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+/// Number of hex characters of the SHA-256 kept in a fingerprint.
+const FINGERPRINT_LEN: usize = 8;
+
+/// Replaces a secret with a short, stable fingerprint so two log lines can
+/// still be correlated without revealing the value itself.
+pub fn fingerprint(secret: &str) -> String {
+    let digest = Sha256::digest(secret.as_bytes());
+    let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+    format!("<redacted:{}>", &hex[..FINGERPRINT_LEN])
+}
+
+fn is_sensitive_field(name: &str) -> bool {
+    SENSITIVE_FIELDS
+        .iter()
+        .any(|field| field.eq_ignore_ascii_case(name))
+}
+
+fn is_sensitive_header(name: &HeaderName) -> bool {
+    SENSITIVE_HEADERS
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name.as_str()))
+}
+
+/// Formats headers for logging, fingerprinting credential-bearing headers.
+/// The authentication scheme (e.g. `Bearer`, `Basic`) is kept visible.
+pub fn redact_headers(headers: &HeaderMap) -> String {
+    let entries: Vec<String> = headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            let value = if is_sensitive_header(name) {
+                match value.split_once(' ') {
+                    Some((scheme, credentials)) if name.as_str().ends_with("authorization") => {
+                        format!("{scheme} {}", fingerprint(credentials))
+                    }
+                    _ => fingerprint(&value),
+                }
+            } else {
+                value.into_owned()
+            };
+            format!("{:?}: {:?}", name.as_str(), value)
+        })
+        .collect();
+    format!("{{{}}}", entries.join(", "))
+}
+
+/// Formats a request or response body for logging. JSON documents and
+/// `application/x-www-form-urlencoded` bodies have their credential fields
+/// replaced by fingerprints; anything else is logged as is.
+pub fn redact_body(body: &[u8]) -> String {
+    if let Ok(mut json) = serde_json::from_slice::<Value>(body) {
+        redact_json(&mut json);
+        return json.to_string();
+    }
+
+    let text = String::from_utf8_lossy(body);
+    if is_form_encoded(&text) {
+        redact_form(&text)
+    } else {
+        text.into_owned()
+    }
+}
+
+/// Returns a copy of a JSON value with credential fields fingerprinted.
+pub fn redact_value(value: &Value) -> Value {
+    let mut value = value.clone();
+    redact_json(&mut value);
+    value
+}
+
+fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_sensitive_field(key) {
+                    if let Some(secret) = value.as_str() {
+                        *value = Value::String(fingerprint(secret));
+                    } else if !value.is_null() {
+                        *value = Value::String(fingerprint(&value.to_string()));
+                    }
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+fn is_form_encoded(text: &str) -> bool {
+    !text.is_empty()
+        && text.contains('=')
+        && !text
+            .chars()
+            .any(|c| c.is_whitespace() || c == '{' || c == '<')
+}
+
+fn redact_form(text: &str) -> String {
+    text.split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) if is_sensitive_field(key) => {
+                format!("{key}={}", fingerprint(value))
+            }
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue};
+
+    use super::{fingerprint, redact_body, redact_headers};
+
+    #[test]
+    fn test_redact_form_body() {
+        let body = b"grant_type=refresh_token&refresh_token=secret-refresh&client_id=abc&client_secret=s3cr3t";
+        let redacted = redact_body(body);
+
+        assert!(!redacted.contains("secret-refresh"));
+        assert!(!redacted.contains("s3cr3t"));
+        assert!(redacted.contains("grant_type=refresh_token"));
+        assert!(redacted.contains("client_id=abc"));
+        assert!(redacted.contains(&format!("refresh_token={}", fingerprint("secret-refresh"))));
+    }
+
+    #[test]
+    fn test_redact_json_body() {
+        let body = br#"{"token_type":"Bearer","access_token":"at-123","nested":{"id_token":"eyJ.x.y"},"expires_in":3600}"#;
+        let redacted = redact_body(body);
+
+        assert!(!redacted.contains("at-123"));
+        assert!(!redacted.contains("eyJ.x.y"));
+        assert!(redacted.contains("\"token_type\":\"Bearer\""));
+        assert!(redacted.contains("\"expires_in\":3600"));
+    }
+
+    #[test]
+    fn test_redact_plain_body_untouched() {
+        assert_eq!(redact_body(b"Not Found"), "Not Found");
+    }
+
+    #[test]
+    fn test_redact_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer at-123"));
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        let redacted = redact_headers(&headers);
+
+        assert!(!redacted.contains("at-123"));
+        assert!(redacted.contains(&format!("Bearer {}", fingerprint("at-123"))));
+        assert!(redacted.contains("application/json"));
+    }
+
+    #[test]
+    fn test_fingerprint_is_stable_and_short() {
+        assert_eq!(fingerprint("abc"), fingerprint("abc"));
+        assert_ne!(fingerprint("abc"), fingerprint("abd"));
+        assert_eq!(fingerprint("abc"), "<redacted:ba7816bf>");
+    }
+}
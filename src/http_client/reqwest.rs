@@ -1,7 +1,10 @@
 use oauth2::{HttpRequest, HttpResponse, http::Response};
 use reqwest::Client;
 
-use crate::oauth2::error::OAuth2Error;
+use crate::{
+    http_client::redact::{redact_body, redact_headers},
+    oauth2::error::OAuth2Error,
+};
 
 #[derive(Clone)]
 pub struct Reqwest {
@@ -19,9 +22,9 @@ impl Default for Reqwest {
 impl Reqwest {
     pub async fn send(&self, request: HttpRequest) -> Result<HttpResponse, OAuth2Error> {
         log::debug!("Request Url: {}", request.uri());
-        log::debug!("Request Header: {:?}", request.headers());
+        log::debug!("Request Header: {}", redact_headers(request.headers()));
         log::debug!("Request Method: {}", request.method());
-        log::debug!("Request Body: {}", String::from_utf8_lossy(request.body()));
+        log::debug!("Request Body: {}", redact_body(request.body()));
 
         // Build the Reqwest request
         let mut req_builder = self
@@ -43,8 +46,8 @@ impl Reqwest {
         let body = resp.bytes().await?.to_vec();
 
         log::debug!("Response Status: {status}");
-        log::debug!("Response Header: {headers:?}");
-        log::debug!("Response Body: {}", String::from_utf8_lossy(&body));
+        log::debug!("Response Header: {}", redact_headers(&headers));
+        log::debug!("Response Body: {}", redact_body(&body));
 
         // Build http::Response
         let mut builder = Response::builder().status(status);
@@ -10,10 +10,13 @@ use oauth2::{HttpRequest, HttpResponse};
 use serde_json::Value;
 
 use crate::oauth2::error::OAuth2Error;
+use crate::service::broker::BrokerHealth;
 
 #[async_trait]
 pub trait Interface {
     fn token_directory(&self) -> PathBuf;
+    fn http_backend(&self) -> String;
     async fn http_request(&self, request: HttpRequest) -> Result<HttpResponse, OAuth2Error>;
     async fn send_event(&self, obj: &str, event: &str, result: &Value) -> std::io::Result<()>;
+    async fn broker_health(&self) -> BrokerHealth;
 }
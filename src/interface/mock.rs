@@ -1,18 +1,116 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use async_trait::async_trait;
+use http::{HeaderValue, Method, Response, StatusCode};
 use oauth2::{HttpRequest, HttpResponse};
 use serde_json::Value;
 use tempfile::TempDir;
 
-use crate::oauth2::error::OAuth2Error;
+use crate::http_client::HttpClient;
+use crate::oauth2::error::{ErrorCodes, OAuth2Error};
+use crate::service::broker::BrokerHealth;
 
 use super::Interface;
 
+/// An event captured from [`Interface::send_event`].
+#[derive(Clone, Debug)]
+pub struct SentEvent {
+    pub object: String,
+    pub event: String,
+    pub result: Value,
+}
+
+/// A scripted answer for requests matching a method, URL and body.
+///
+/// Responses are handed out in order; the last one is repeated once the
+/// sequence is exhausted, which suits endpoints that are polled.
+pub struct Route {
+    method: Option<Method>,
+    url: String,
+    body: Vec<String>,
+    responses: VecDeque<HttpResponse>,
+}
+
+impl Route {
+    /// Matches any method whose URL contains `url`.
+    pub fn any(url: &str) -> Self {
+        Self {
+            method: None,
+            url: url.to_string(),
+            body: Vec::new(),
+            responses: VecDeque::new(),
+        }
+    }
+
+    pub fn get(url: &str) -> Self {
+        Self {
+            method: Some(Method::GET),
+            ..Self::any(url)
+        }
+    }
+
+    pub fn post(url: &str) -> Self {
+        Self {
+            method: Some(Method::POST),
+            ..Self::any(url)
+        }
+    }
+
+    /// Additionally requires the request body to contain `needle`.
+    pub fn body_contains(mut self, needle: &str) -> Self {
+        self.body.push(needle.to_string());
+        self
+    }
+
+    pub fn respond(mut self, response: HttpResponse) -> Self {
+        self.responses.push_back(response);
+        self
+    }
+
+    pub fn respond_json(self, status: StatusCode, body: &str) -> Self {
+        self.respond(json_response(status, body))
+    }
+
+    fn matches(&self, request: &HttpRequest) -> bool {
+        let body = String::from_utf8_lossy(request.body());
+        self.method.as_ref().is_none_or(|m| m == request.method())
+            && request.uri().to_string().contains(&self.url)
+            && self.body.iter().all(|needle| body.contains(needle))
+    }
+
+    fn next_response(&mut self) -> Option<HttpResponse> {
+        if self.responses.len() > 1 {
+            self.responses.pop_front()
+        } else {
+            self.responses.front().cloned()
+        }
+    }
+}
+
+/// Builds a response with a JSON content type, as OAuth2 endpoints return.
+pub fn json_response(status: StatusCode, body: &str) -> HttpResponse {
+    let mut response = Response::new(body.as_bytes().to_vec());
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        "content-type",
+        HeaderValue::from_static("application/json; charset=utf-8"),
+    );
+    response
+}
+
 #[derive(Clone)]
 pub struct Mock {
     token_directory: Arc<TempDir>,
     mock_response: HttpResponse,
+    routes: Arc<Mutex<Vec<Route>>>,
+    requests: Arc<Mutex<Vec<HttpRequest>>>,
+    events: Arc<Mutex<Vec<SentEvent>>>,
+    http_client: Option<HttpClient>,
 }
 
 #[async_trait]
@@ -21,12 +119,46 @@ impl Interface for Mock {
         self.token_directory.path().join("token")
     }
 
-    async fn http_request(&self, _request: HttpRequest) -> Result<HttpResponse, OAuth2Error> {
-        Ok(self.mock_response.clone())
+    fn http_backend(&self) -> String {
+        self.http_client
+            .as_ref()
+            .map_or("Mock".to_string(), ToString::to_string)
+    }
+
+    async fn http_request(&self, request: HttpRequest) -> Result<HttpResponse, OAuth2Error> {
+        self.requests.lock().unwrap().push(request.clone());
+
+        if let Some(http_client) = &self.http_client {
+            return http_client.send(request).await;
+        }
+        let mut routes = self.routes.lock().unwrap();
+        if routes.is_empty() {
+            return Ok(self.mock_response.clone());
+        }
+        routes
+            .iter_mut()
+            .find(|route| route.matches(&request))
+            .and_then(Route::next_response)
+            .ok_or(OAuth2Error::new(
+                ErrorCodes::RequestError,
+                format!("No mock route for {} {}", request.method(), request.uri()),
+            ))
     }
-    async fn send_event(&self, _obj: &str, _event: &str, _result: &Value) -> std::io::Result<()> {
+    async fn send_event(&self, obj: &str, event: &str, result: &Value) -> std::io::Result<()> {
+        self.events.lock().unwrap().push(SentEvent {
+            object: obj.to_string(),
+            event: event.to_string(),
+            result: result.clone(),
+        });
         Ok(())
     }
+
+    async fn broker_health(&self) -> BrokerHealth {
+        BrokerHealth {
+            connected: true,
+            ..Default::default()
+        }
+    }
 }
 
 impl Mock {
@@ -35,11 +167,68 @@ impl Mock {
             token_directory: Arc::new(TempDir::with_prefix_in("tests", ".").unwrap()),
 
             mock_response: HttpResponse::new(Vec::new()),
+            routes: Arc::new(Mutex::new(Vec::new())),
+            requests: Arc::new(Mutex::new(Vec::new())),
+            events: Arc::new(Mutex::new(Vec::new())),
+            http_client: None,
         }
     }
 
+    /// Sends requests over the network with a real backend instead of the
+    /// scripted routes, e.g. to talk to the test authorization server.
+    pub fn with_http_client(mut self, http_client: HttpClient) -> Self {
+        self.http_client = Some(http_client);
+        self
+    }
+
     pub fn set_mock_response(mut self, response: HttpResponse) -> Self {
         self.mock_response = response;
         self
     }
+
+    /// Registers a route. Routes are matched in registration order and, once
+    /// any route exists, unmatched requests fail instead of falling back to
+    /// the fixed mock response.
+    pub fn route(self, route: Route) -> Self {
+        self.routes.lock().unwrap().push(route);
+        self
+    }
+
+    /// All requests received so far, in order.
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Requests whose URL contains `url`.
+    pub fn requests_to(&self, url: &str) -> Vec<HttpRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.uri().to_string().contains(url))
+            .collect()
+    }
+
+    /// All events published so far, in order.
+    pub fn events(&self) -> Vec<SentEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Events published under the given name.
+    pub fn events_named(&self, event: &str) -> Vec<SentEvent> {
+        self.events()
+            .into_iter()
+            .filter(|sent| sent.event == event)
+            .collect()
+    }
+
+    /// Waits until an event with the given name has been published.
+    pub async fn wait_for_event(&self, event: &str, timeout: Duration) -> Option<SentEvent> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while tokio::time::Instant::now() < deadline {
+            if let Some(sent) = self.events_named(event).into_iter().next() {
+                return Some(sent);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        None
+    }
 }
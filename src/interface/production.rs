@@ -2,7 +2,6 @@ use std::path::PathBuf;
 
 use async_trait::async_trait;
 use directories::UserDirs;
-use ipc_broker::client::IPCClient;
 use oauth2::{HttpRequest, HttpResponse};
 use serde_json::Value;
 
@@ -10,13 +9,14 @@ use crate::interface::Interface;
 use crate::{
     http_client::HttpClient,
     oauth2::error::{ErrorCodes, OAuth2Error},
+    service::broker::{BrokerConnection, BrokerHealth},
 };
 
 #[derive(Clone)]
 pub struct Production {
     token_directory: PathBuf,
     http_client: HttpClient,
-    connector: IPCClient,
+    broker: BrokerConnection,
 }
 
 #[async_trait]
@@ -25,20 +25,25 @@ impl Interface for Production {
         self.token_directory.clone()
     }
 
+    fn http_backend(&self) -> String {
+        self.http_client.to_string()
+    }
+
     async fn http_request(&self, request: HttpRequest) -> Result<HttpResponse, OAuth2Error> {
-        match &self.http_client {
-            HttpClient::Curl(curl) => curl.send(request).await,
-            HttpClient::Reqwest(reqwest) => reqwest.send(request).await,
-        }
+        self.http_client.send(request).await
     }
 
     async fn send_event(&self, object: &str, event: &str, result: &Value) -> std::io::Result<()> {
-        self.connector.publish(object, event, result).await
+        self.broker.publish(object, event, result).await
+    }
+
+    async fn broker_health(&self) -> BrokerHealth {
+        self.broker.health().await
     }
 }
 
 impl Production {
-    pub fn new(connector: IPCClient, http_client: HttpClient) -> Result<Self, OAuth2Error> {
+    pub fn new(broker: BrokerConnection, http_client: HttpClient) -> Result<Self, OAuth2Error> {
         let token_directory = UserDirs::new().ok_or(OAuth2Error::new(
             ErrorCodes::DirectoryError,
             "No valid directory".to_string(),
@@ -50,7 +55,7 @@ impl Production {
         Ok(Self {
             token_directory,
             http_client,
-            connector,
+            broker,
         })
     }
 }
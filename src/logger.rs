@@ -1,8 +1,48 @@
-use std::io;
+pub mod control;
+pub mod file;
+pub mod filter;
+#[cfg(target_os = "linux")]
+pub mod journald;
 
-use chrono::Local;
+use std::{
+    future::Future,
+    io::{self, Write},
+    num::ParseIntError,
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use chrono::{Local, SecondsFormat};
 use fern::Dispatch;
-use log::LevelFilter;
+use log::{
+    LevelFilter, Log, Metadata, Record,
+    kv::{self, Key, VisitSource},
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::logger::{
+    file::{RotatingFile, Rotation},
+    filter::LevelFilters,
+};
+
+/// Environment variable holding level filters, e.g.
+/// `info,modern_auth_service::openid=debug`. See [`LevelFilters`].
+pub const LOG_ENV: &str = "AUTH_SERVICE_LOG";
+/// `text` (default) or `json`, one object per line.
+pub const LOG_FORMAT_ENV: &str = "AUTH_SERVICE_LOG_FORMAT";
+/// `stdout` (default), `journald`, or the path of a log file.
+pub const LOG_OUTPUT_ENV: &str = "AUTH_SERVICE_LOG_OUTPUT";
+/// Bytes after which the log file is rotated, `0` for no limit.
+pub const LOG_MAX_SIZE_ENV: &str = "AUTH_SERVICE_LOG_MAX_SIZE";
+/// `never` (default), `hourly` or `daily`.
+pub const LOG_ROTATE_ENV: &str = "AUTH_SERVICE_LOG_ROTATE";
+/// Rotated log files kept.
+pub const LOG_KEEP_ENV: &str = "AUTH_SERVICE_LOG_KEEP";
+
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: usize = 5;
 
 fn logging_level() -> LevelFilter {
     // 1. Check for debug files near executable
@@ -26,45 +66,429 @@ fn logging_level() -> LevelFilter {
     }
 }
 
-pub fn setup_logger() {
-    let level_filter = logging_level();
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
 
-    if let Err(e) = Dispatch::new()
-        .format(move |out, message, record| {
-            let file = record.file().unwrap_or("unknown_file");
-            let line = record.line().map_or(0, |l| l);
-
-            match level_filter {
-                LevelFilter::Off
-                | LevelFilter::Error
-                | LevelFilter::Warn
-                | LevelFilter::Debug
-                | LevelFilter::Trace => {
-                    out.finish(format_args!(
-                        "[{}][{}]: {} <{}:{}>",
-                        Local::now().format("%b-%d-%Y %H:%M:%S.%f"),
-                        record.level(),
-                        message,
-                        file,
-                        line,
-                    ));
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            value => Err(format!("Invalid log format {value:?}.")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogOutput {
+    Stdout,
+    Journald,
+    File {
+        path: PathBuf,
+        max_size: u64,
+        rotation: Rotation,
+        keep: usize,
+    },
+}
+
+fn number<T: FromStr<Err = ParseIntError>>(value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|err| format!("Invalid number {value:?}: {err}."))
+}
+
+/// The variable `name` parsed by `parse`, or `default` if unset or invalid.
+fn setting<T>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    default: T,
+    warnings: &mut Vec<String>,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> T {
+    let Some(value) = var(name) else {
+        return default;
+    };
+    parse(&value).unwrap_or_else(|err| {
+        warnings.push(format!("{name}: {err} Using the default."));
+        default
+    })
+}
+
+/// How the service logs, read from the `AUTH_SERVICE_LOG*` environment
+/// variables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    pub filters: LevelFilters,
+    pub format: LogFormat,
+    pub output: LogOutput,
+    /// Invalid settings, logged once the logger is up.
+    warnings: Vec<String>,
+}
+
+impl LogConfig {
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let mut warnings = Vec::new();
+        let level = logging_level();
+        let filters = setting(
+            &var,
+            LOG_ENV,
+            LevelFilters::new(level),
+            &mut warnings,
+            |value| LevelFilters::parse(value, level),
+        );
+        let format = setting(
+            &var,
+            LOG_FORMAT_ENV,
+            LogFormat::Text,
+            &mut warnings,
+            str::parse,
+        );
+        let max_size = setting(
+            &var,
+            LOG_MAX_SIZE_ENV,
+            DEFAULT_MAX_SIZE,
+            &mut warnings,
+            number,
+        );
+        let rotation = setting(
+            &var,
+            LOG_ROTATE_ENV,
+            Rotation::Never,
+            &mut warnings,
+            str::parse,
+        );
+        let keep = setting(&var, LOG_KEEP_ENV, DEFAULT_KEEP, &mut warnings, number);
+
+        let output = match var(LOG_OUTPUT_ENV).as_deref().map(str::trim) {
+            None | Some("" | "stdout") => LogOutput::Stdout,
+            Some("journald") => LogOutput::Journald,
+            Some(path) => LogOutput::File {
+                path: path.into(),
+                max_size,
+                rotation,
+                keep,
+            },
+        };
+        Self {
+            filters,
+            format,
+            output,
+            warnings,
+        }
+    }
+}
+
+/// Fields added to every record logged while handling a call or a login.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct LogFields {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+}
+
+tokio::task_local! {
+    static FIELDS: LogFields;
+}
+
+/// Runs `future` with `fields` added to the records it logs.
+pub async fn scope<F: Future>(fields: LogFields, future: F) -> F::Output {
+    FIELDS.scope(fields, future).await
+}
+
+struct Collect<'a>(&'a mut Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Collect<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+/// The fields of the current call or login, then those of the record, e.g.
+/// `log::error!(error_code = "invalid_grant"; "...")`.
+fn fields(record: &Record) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    let _ = FIELDS.try_with(|current| {
+        if let Ok(Value::Object(current)) = serde_json::to_value(current) {
+            for (name, value) in current {
+                if let Value::String(value) = value {
+                    fields.push((name, value));
+                }
+            }
+        }
+    });
+    let _ = record.key_values().visit(&mut Collect(&mut fields));
+    fields
+}
+
+/// The human readable format, with a timestamp unless only `info` and above
+/// are logged.
+fn text(record: &Record, level_filter: LevelFilter) -> String {
+    let file = record.file().unwrap_or("unknown_file");
+    let line = record.line().map_or(0, |l| l);
+
+    match level_filter {
+        LevelFilter::Off
+        | LevelFilter::Error
+        | LevelFilter::Warn
+        | LevelFilter::Debug
+        | LevelFilter::Trace => format!(
+            "[{}][{}]: {} <{}:{}>",
+            Local::now().format("%b-%d-%Y %H:%M:%S.%f"),
+            record.level(),
+            record.args(),
+            file,
+            line,
+        ),
+        LevelFilter::Info => format!(
+            "[{}]: {} <{}:{}>",
+            record.level(),
+            record.args(),
+            file,
+            line,
+        ),
+    }
+}
+
+fn json(record: &Record, fields: Vec<(String, String)>) -> String {
+    let mut object = Map::new();
+    object.insert(
+        "timestamp".into(),
+        Local::now()
+            .to_rfc3339_opts(SecondsFormat::Micros, false)
+            .into(),
+    );
+    object.insert("level".into(), record.level().as_str().into());
+    object.insert("target".into(), record.target().into());
+    object.insert("message".into(), record.args().to_string().into());
+    for (name, value) in fields {
+        object.insert(name, value.into());
+    }
+    if let Some(file) = record.file() {
+        object.insert("file".into(), file.into());
+    }
+    if let Some(line) = record.line() {
+        object.insert("line".into(), line.into());
+    }
+    Value::Object(object).to_string()
+}
+
+enum Output {
+    Stdout,
+    File(Mutex<RotatingFile>),
+    #[cfg(target_os = "linux")]
+    Journald(journald::Journald),
+}
+
+impl Output {
+    /// Opens `output`, falling back to stdout with the reason.
+    fn open(output: &LogOutput) -> (Self, Option<String>) {
+        match output {
+            LogOutput::Stdout => (Output::Stdout, None),
+            #[cfg(target_os = "linux")]
+            LogOutput::Journald => match journald::Journald::connect() {
+                Ok(journald) => (Output::Journald(journald), None),
+                Err(err) => (
+                    Output::Stdout,
+                    Some(format!("Cannot log to journald: {err}")),
+                ),
+            },
+            #[cfg(not(target_os = "linux"))]
+            LogOutput::Journald => (
+                Output::Stdout,
+                Some("journald is only available on Linux.".into()),
+            ),
+            LogOutput::File {
+                path,
+                max_size,
+                rotation,
+                keep,
+            } => match RotatingFile::open(path, *max_size, *rotation, *keep) {
+                Ok(file) => (Output::File(Mutex::new(file)), None),
+                Err(err) => (
+                    Output::Stdout,
+                    Some(format!("Cannot log to {}: {err}", path.display())),
+                ),
+            },
+        }
+    }
+}
+
+struct Sink {
+    format: LogFormat,
+    level_filter: LevelFilter,
+    output: Output,
+}
+
+impl Sink {
+    fn line(&self, record: &Record, fields: Vec<(String, String)>) -> String {
+        match self.format {
+            LogFormat::Text => text(record, self.level_filter),
+            LogFormat::Json => json(record, fields),
+        }
+    }
+}
+
+impl Log for Sink {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let fields = fields(record);
+        match &self.output {
+            Output::Stdout => {
+                let _ = writeln!(io::stdout().lock(), "{}", self.line(record, fields));
+            }
+            Output::File(file) => {
+                let line = self.line(record, fields);
+                if let Err(err) = file.lock().unwrap().write_line(&line) {
+                    eprintln!("Failed to write the log file: {err}");
                 }
-                LevelFilter::Info => {
-                    out.finish(format_args!(
-                        "[{}]: {} <{}:{}>",
-                        record.level(),
-                        message,
-                        file,
-                        line,
-                    ));
+            }
+            #[cfg(target_os = "linux")]
+            Output::Journald(journald) => {
+                if journald.send(record, &fields).is_err() {
+                    let _ = writeln!(io::stdout().lock(), "{}", self.line(record, fields));
                 }
             }
-        })
-        .level(level_filter)
-        .chain(io::stdout())
+        }
+    }
+
+    fn flush(&self) {
+        match &self.output {
+            Output::File(file) => {
+                let _ = file.lock().unwrap().flush();
+            }
+            _ => {
+                let _ = io::stdout().flush();
+            }
+        }
+    }
+}
+
+pub fn setup_logger() {
+    let LogConfig {
+        filters,
+        format,
+        output,
+        mut warnings,
+    } = LogConfig::from_env();
+    let (output, problem) = Output::open(&output);
+    warnings.extend(problem);
+    let level_filter = filters.default;
+
+    let sink = Sink {
+        format,
+        level_filter,
+        output,
+    };
+    if let Err(e) = Dispatch::new()
+        .filter(control::enabled)
+        .level(LevelFilter::Trace)
+        .chain(Box::new(sink) as Box<dyn Log>)
         .apply()
     {
         log::error!("Logger initialization failed: {e}");
+        return;
+    }
+    // Only records some module logs reach the filter.
+    control::install(filters);
+    for warning in warnings {
+        log::warn!("{warning}");
     }
     log::debug!("Enabled log {level_filter}.");
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_log_config_from_vars() {
+        let vars = HashMap::from([
+            (LOG_ENV, "warn,modern_auth_service::openid=debug"),
+            (LOG_FORMAT_ENV, "json"),
+            (LOG_OUTPUT_ENV, "/var/log/auth.log"),
+            (LOG_ROTATE_ENV, "daily"),
+            (LOG_KEEP_ENV, "many"),
+        ]);
+        let config = LogConfig::from_vars(|name| vars.get(name).map(|value| value.to_string()));
+        assert_eq!(config.filters.default, LevelFilter::Warn);
+        assert_eq!(
+            config
+                .filters
+                .level_for("modern_auth_service::openid::cache"),
+            LevelFilter::Debug
+        );
+        assert_eq!(config.format, LogFormat::Json);
+        assert_eq!(
+            config.output,
+            LogOutput::File {
+                path: "/var/log/auth.log".into(),
+                max_size: DEFAULT_MAX_SIZE,
+                rotation: Rotation::Daily,
+                keep: DEFAULT_KEEP,
+            }
+        );
+        assert_eq!(config.warnings.len(), 1);
+        assert!(config.warnings[0].starts_with(LOG_KEEP_ENV));
+
+        let config = LogConfig::from_vars(|_| None);
+        assert_eq!(config.format, LogFormat::Text);
+        assert_eq!(config.output, LogOutput::Stdout);
+        assert!(config.warnings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_json_line_has_fields() {
+        let fields = LogFields {
+            session: Some("abc".into()),
+            provider: Some("Example".into()),
+            method: Some("requestToken".into()),
+            ..Default::default()
+        };
+        let error_code = "invalid_grant";
+        let kvs = [("error_code", error_code)];
+        let line = scope(fields, async {
+            let record = Record::builder()
+                .args(format_args!("Refresh failed."))
+                .level(log::Level::Error)
+                .target("modern_auth_service::oauth2")
+                .file(Some("src/oauth2.rs"))
+                .line(Some(7))
+                .key_values(&kvs)
+                .build();
+            json(&record, super::fields(&record))
+        })
+        .await;
+
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "ERROR");
+        assert_eq!(value["message"], "Refresh failed.");
+        assert_eq!(value["session"], "abc");
+        assert_eq!(value["provider"], "Example");
+        assert_eq!(value["method"], "requestToken");
+        assert_eq!(value["error_code"], "invalid_grant");
+        assert_eq!(value["line"], 7);
+        assert!(value.get("process").is_none());
+        assert!(value["timestamp"].as_str().is_some());
+    }
+}
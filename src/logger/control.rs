@@ -0,0 +1,391 @@
+// Log levels changed while the service runs, through the `setLogLevel`
+// method, instead of restarting it with other settings.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug, Formatter},
+    sync::{LazyLock, RwLock},
+    time::{Duration, Instant},
+};
+
+use log::{LevelFilter, Metadata};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+use super::{FIELDS, filter::LevelFilters};
+use crate::oauth2::{
+    error::{ErrorCodes, OAuth2Error, OAuth2Result},
+    provider::InputParameters,
+};
+
+/// Secret a caller of `setLogLevel` passes as `control_secret`. Nobody may
+/// call it when it is not set.
+pub const LOG_CONTROL_SECRET_ENV: &str = "AUTH_SERVICE_LOG_CONTROL_SECRET";
+
+/// How long a session or process is traced for unless told otherwise.
+const DEFAULT_TRACE_DURATION: Duration = Duration::from_secs(600);
+/// Longest a session or process is traced for.
+pub const MAX_TRACE_DURATION: Duration = Duration::from_secs(3600);
+
+/// Logins or calls traced whatever the levels, by `session` or `process`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceTarget {
+    Session(String),
+    Process(String),
+}
+
+#[derive(Debug, Clone)]
+struct Trace {
+    target: TraceTarget,
+    until: Instant,
+}
+
+/// The `control_secret` a caller passes to `setLogLevel`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ControlSecret(pub String);
+
+impl Debug for ControlSecret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("ControlSecret([redacted])")
+    }
+}
+
+/// A temporary trace, as reported by `setLogLevel`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TraceStatus {
+    #[serde(flatten)]
+    pub target: TraceTarget,
+    pub remaining_seconds: u64,
+}
+
+/// The levels in effect, as reported by `setLogLevel`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogLevels {
+    pub default: String,
+    pub modules: BTreeMap<String, String>,
+    pub traces: Vec<TraceStatus>,
+}
+
+struct Control {
+    levels: LevelFilters,
+    traces: Vec<Trace>,
+}
+
+static CONTROL: LazyLock<RwLock<Control>> = LazyLock::new(|| {
+    RwLock::new(Control {
+        levels: LevelFilters::new(LevelFilter::Info),
+        traces: Vec::new(),
+    })
+});
+
+impl Control {
+    fn enabled(&self, metadata: &Metadata, now: Instant) -> bool {
+        if self.levels.enabled(metadata) {
+            return true;
+        }
+        // Only this service's own records, not those of its dependencies.
+        if self.traces.is_empty() || !metadata.target().starts_with(env!("CARGO_CRATE_NAME")) {
+            return false;
+        }
+        FIELDS
+            .try_with(|fields| {
+                self.traces.iter().any(|trace| {
+                    trace.until > now
+                        && match &trace.target {
+                            TraceTarget::Session(id) => fields.session.as_ref() == Some(id),
+                            TraceTarget::Process(name) => fields.process.as_ref() == Some(name),
+                        }
+                })
+            })
+            .unwrap_or_default()
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        if self.traces.is_empty() {
+            self.levels.max()
+        } else {
+            LevelFilter::Trace
+        }
+    }
+
+    fn trace(&mut self, target: TraceTarget, duration: Duration, now: Instant) {
+        self.traces.retain(|trace| trace.target != target);
+        self.traces.push(Trace {
+            target,
+            until: now + duration.min(MAX_TRACE_DURATION),
+        });
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.traces.retain(|trace| trace.until > now);
+    }
+
+    fn report(&self, now: Instant) -> LogLevels {
+        LogLevels {
+            default: self.levels.default.to_string(),
+            modules: self
+                .levels
+                .modules
+                .iter()
+                .map(|(module, level)| (module.clone(), level.to_string()))
+                .collect(),
+            traces: self
+                .traces
+                .iter()
+                .filter(|trace| trace.until > now)
+                .map(|trace| TraceStatus {
+                    target: trace.target.clone(),
+                    remaining_seconds: trace.until.duration_since(now).as_secs(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Whether a record passes the current levels.
+pub fn enabled(metadata: &Metadata) -> bool {
+    CONTROL.read().unwrap().enabled(metadata, Instant::now())
+}
+
+/// Replaces the levels, e.g. with those configured at startup.
+pub fn install(levels: LevelFilters) {
+    let mut control = CONTROL.write().unwrap();
+    control.levels = levels;
+    log::set_max_level(control.max_level());
+}
+
+/// Applies `directives` over the current levels, e.g.
+/// `debug,modern_auth_service::openid=trace`.
+pub fn set_levels(directives: &str) -> Result<LogLevels, String> {
+    let report = {
+        let mut control = CONTROL.write().unwrap();
+        let changes = LevelFilters::parse(directives, control.levels.default)?;
+        control.levels.default = changes.default;
+        for (module, level) in changes.modules {
+            control.levels.set(&module, level);
+        }
+        log::set_max_level(control.max_level());
+        control.report(Instant::now())
+    };
+    // Not while holding the levels, which logging reads.
+    log::info!("Log levels set to {directives:?}.");
+    Ok(report)
+}
+
+/// Logs everything about `target` for `duration`, then goes back to the
+/// levels. Needs a Tokio runtime to revert.
+pub fn trace(target: TraceTarget, duration: Duration) -> LogLevels {
+    let duration = duration.min(MAX_TRACE_DURATION);
+    log::info!("Tracing {target:?} for {duration:?}.");
+    let report = {
+        let mut control = CONTROL.write().unwrap();
+        control.trace(target, duration, Instant::now());
+        log::set_max_level(LevelFilter::Trace);
+        control.report(Instant::now())
+    };
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        let mut control = CONTROL.write().unwrap();
+        control.expire(Instant::now());
+        log::set_max_level(control.max_level());
+    });
+    report
+}
+
+pub fn levels() -> LogLevels {
+    CONTROL.read().unwrap().report(Instant::now())
+}
+
+/// Checks `secret` against the one configured in [`LOG_CONTROL_SECRET_ENV`],
+/// in constant time. The `process` a caller passes is its own word, so
+/// proves nothing.
+fn authorize(secret: Option<&ControlSecret>, expected: Option<&str>) -> OAuth2Result<()> {
+    match (secret, expected) {
+        (Some(secret), Some(expected))
+            if !expected.is_empty()
+                && bool::from(secret.0.as_bytes().ct_eq(expected.as_bytes())) =>
+        {
+            Ok(())
+        }
+        _ => Err(OAuth2Error::new(
+            ErrorCodes::Forbidden,
+            format!("setLogLevel needs the secret configured in {LOG_CONTROL_SECRET_ENV}."),
+        )),
+    }
+}
+
+/// The `setLogLevel` method, for callers passing the secret of
+/// [`LOG_CONTROL_SECRET_ENV`]: applies `log_levels`, traces `trace_session`
+/// and `trace_process` for a while, and reports the levels in effect.
+pub fn set_log_level(param: &InputParameters) -> OAuth2Result<LogLevels> {
+    let process = param.process.as_deref();
+    let expected = std::env::var(LOG_CONTROL_SECRET_ENV).ok();
+    if let Err(err) = authorize(param.control_secret.as_ref(), expected.as_deref()) {
+        log::warn!("setLogLevel denied to {process:?}.");
+        return Err(err);
+    }
+    log::info!(
+        "setLogLevel by {process:?}: log_levels {:?}, trace_session {:?}, trace_process {:?}, trace_seconds {:?}.",
+        param.log_levels,
+        param.trace_session,
+        param.trace_process,
+        param.trace_seconds
+    );
+    apply(param)
+}
+
+fn apply(param: &InputParameters) -> OAuth2Result<LogLevels> {
+    if let Some(seconds) = param.trace_seconds
+        && seconds > MAX_TRACE_DURATION.as_secs()
+    {
+        return Err(OAuth2Error::new(
+            ErrorCodes::InvalidParameters,
+            format!("trace_seconds is at most {}.", MAX_TRACE_DURATION.as_secs()),
+        ));
+    }
+    let mut report = match &param.log_levels {
+        Some(directives) => set_levels(directives)
+            .map_err(|err| OAuth2Error::new(ErrorCodes::InvalidParameters, err))?,
+        None => levels(),
+    };
+    let duration = param
+        .trace_seconds
+        .map_or(DEFAULT_TRACE_DURATION, Duration::from_secs);
+    if let Some(session) = &param.trace_session {
+        report = trace(TraceTarget::Session(session.clone()), duration);
+    }
+    if let Some(process) = &param.trace_process {
+        report = trace(TraceTarget::Process(process.clone()), duration);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+    use crate::logger::{LogFields, scope};
+
+    #[tokio::test]
+    async fn test_temporary_trace_by_session() {
+        let mut control = Control {
+            levels: LevelFilters::new(LevelFilter::Info),
+            traces: Vec::new(),
+        };
+        let now = Instant::now();
+        control.trace(
+            TraceTarget::Session("abc".into()),
+            Duration::from_secs(60),
+            now,
+        );
+        assert_eq!(control.max_level(), LevelFilter::Trace);
+
+        let metadata = |target| {
+            Metadata::builder()
+                .level(Level::Trace)
+                .target(target)
+                .build()
+        };
+        let session = |id: &str| LogFields {
+            session: Some(id.into()),
+            ..Default::default()
+        };
+        let own = metadata("modern_auth_service::oauth2");
+        assert!(!control.enabled(&own, now));
+        assert!(scope(session("abc"), async { control.enabled(&own, now) }).await);
+        assert!(!scope(session("other"), async { control.enabled(&own, now) }).await);
+        // Dependencies stay at their level.
+        let dependency = metadata("ipc_broker::client");
+        assert!(!scope(session("abc"), async { control.enabled(&dependency, now) }).await);
+
+        let report = control.report(now);
+        assert_eq!(report.default, "INFO");
+        assert_eq!(report.traces[0].remaining_seconds, 60);
+        assert_eq!(
+            serde_json::to_value(&report.traces[0]).unwrap(),
+            serde_json::json!({ "session": "abc", "remaining_seconds": 60 })
+        );
+
+        // Past its deadline the trace no longer applies, and is then dropped.
+        let later = now + Duration::from_secs(61);
+        assert!(!scope(session("abc"), async { control.enabled(&own, later) }).await);
+        control.expire(later);
+        assert_eq!(control.max_level(), LevelFilter::Info);
+
+        control.trace(
+            TraceTarget::Process("app".into()),
+            Duration::from_secs(86400),
+            now,
+        );
+        assert_eq!(
+            control.report(now).traces[0].remaining_seconds,
+            MAX_TRACE_DURATION.as_secs()
+        );
+    }
+
+    #[test]
+    fn test_set_log_level_needs_the_secret() {
+        let secret = |value: &str| ControlSecret(value.into());
+        let expected = Some("s3cret");
+        assert!(authorize(Some(&secret("s3cret")), expected).is_ok());
+        let err = authorize(Some(&secret("s3cre")), expected).unwrap_err();
+        assert_eq!(err.error_code, ErrorCodes::Forbidden);
+        assert!(authorize(Some(&secret("s3cret!")), expected).is_err());
+        assert!(authorize(None, expected).is_err());
+        assert!(authorize(Some(&secret("s3cret")), None).is_err());
+        assert!(authorize(Some(&secret("")), Some("")).is_err());
+        assert_eq!(
+            format!("{:?}", secret("s3cret")),
+            "ControlSecret([redacted])"
+        );
+
+        // Nobody is allowed unless configured, whatever process they claim.
+        let param: InputParameters = serde_json::from_value(serde_json::json!({
+            "process": "admin-tool",
+            "control_secret": "s3cret",
+            "log_levels": "trace",
+        }))
+        .unwrap();
+        assert_eq!(
+            set_log_level(&param).unwrap_err().error_code,
+            ErrorCodes::Forbidden
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_log_level() {
+        let param: InputParameters = serde_json::from_value(serde_json::json!({
+            "log_levels": "modern_auth_service::logger::control=loud",
+        }))
+        .unwrap();
+        let err = apply(&param).unwrap_err();
+        assert_eq!(err.error_code, ErrorCodes::InvalidParameters);
+        let param: InputParameters = serde_json::from_value(serde_json::json!({
+            "trace_session": "set-log-level-test",
+            "trace_seconds": 86400,
+        }))
+        .unwrap();
+        let err = apply(&param).unwrap_err();
+        assert_eq!(err.error_code, ErrorCodes::InvalidParameters);
+
+        let param: InputParameters = serde_json::from_value(serde_json::json!({
+            "log_levels": "modern_auth_service::logger::control=debug",
+            "trace_session": "set-log-level-test",
+            "trace_seconds": 30,
+        }))
+        .unwrap();
+        let report = apply(&param).unwrap();
+        assert_eq!(
+            report.modules["modern_auth_service::logger::control"],
+            "DEBUG"
+        );
+        let trace = report
+            .traces
+            .iter()
+            .find(|trace| trace.target == TraceTarget::Session("set-log-level-test".into()))
+            .unwrap();
+        assert!((29..=30).contains(&trace.remaining_seconds));
+    }
+}
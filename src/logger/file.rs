@@ -0,0 +1,168 @@
+// A log file rotated by size and by time, keeping a number of old files as
+// `<path>.1` (the newest) to `<path>.<keep>`.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Local};
+
+/// When a log file is started afresh regardless of its size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            value => Err(format!("Invalid log rotation {value:?}.")),
+        }
+    }
+}
+
+impl Rotation {
+    /// The period `time` falls in; a file is rotated when it changes.
+    fn period(&self, time: DateTime<Local>) -> String {
+        match self {
+            Rotation::Never => String::new(),
+            Rotation::Hourly => time.format("%Y%m%d%H").to_string(),
+            Rotation::Daily => time.format("%Y%m%d").to_string(),
+        }
+    }
+}
+
+pub struct RotatingFile {
+    path: PathBuf,
+    /// Bytes after which the file is rotated, `0` for no limit.
+    max_size: u64,
+    rotation: Rotation,
+    /// Rotated files kept.
+    keep: usize,
+    /// Closed while rotating, as Windows renames no open file.
+    file: Option<File>,
+    size: u64,
+    period: String,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, rotation: Rotation, keep: usize) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // A file left from an earlier period is rotated on the first write.
+        let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            rotation,
+            keep,
+            file: Some(file),
+            size: metadata.len(),
+            period: rotation.period(modified.into()),
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self, period: String) -> io::Result<()> {
+        self.file = None;
+        for index in (1..self.keep).rev() {
+            match fs::rename(self.rotated(index), self.rotated(index + 1)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = Some(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&self.path)?,
+        );
+        self.size = 0;
+        self.period = period;
+        Ok(())
+    }
+
+    /// Appends `line`, first rotating the file if it is full or its period
+    /// is over. A line is never split across files.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.write_line_at(line, Local::now())
+    }
+
+    fn write_line_at(&mut self, line: &str, now: DateTime<Local>) -> io::Result<()> {
+        let period = self.rotation.period(now);
+        let full = self.max_size > 0 && self.size + line.len() as u64 + 1 > self.max_size;
+        if self.file.is_none() || (self.size > 0 && (full || period != self.period)) {
+            self.rotate(period)?;
+        }
+        let file = self.file.as_mut().ok_or(io::ErrorKind::NotFound)?;
+        writeln!(file, "{line}")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    #[test]
+    fn test_rotation_by_size_and_time() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("logs").join("service.log");
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+
+        let mut file = RotatingFile::open(&path, 20, Rotation::Daily, 2).unwrap();
+        let now = Local::now();
+        for line in ["first line", "second line", "third line", "fourth line"] {
+            file.write_line_at(line, now).unwrap();
+        }
+        // Each line fills the file; only two rotated files are kept.
+        assert_eq!(read(path.clone()), "fourth line\n");
+        assert_eq!(read(file.rotated(1)), "third line\n");
+        assert_eq!(read(file.rotated(2)), "second line\n");
+        assert!(!file.rotated(3).exists());
+
+        let mut file = RotatingFile::open(&path, 0, Rotation::Daily, 2).unwrap();
+        file.write_line_at("same day", now).unwrap();
+        assert_eq!(read(path.clone()), "fourth line\nsame day\n");
+        file.write_line_at("next day", now + TimeDelta::days(1))
+            .unwrap();
+        assert_eq!(read(path.clone()), "next day\n");
+        assert_eq!(read(file.rotated(1)), "fourth line\nsame day\n");
+
+        assert_eq!("hourly".parse(), Ok(Rotation::Hourly));
+        assert!("weekly".parse::<Rotation>().is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use log::{LevelFilter, Metadata};
+
+/// A default level, and levels for modules overriding it.
+///
+/// Parsed from directives separated by commas, e.g.
+/// `info,modern_auth_service::openid=debug,ipc_broker=warn`: a bare level is
+/// the default, and `<module>=<level>` applies to the module and the modules
+/// under it, the most specific one winning.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelFilters {
+    pub default: LevelFilter,
+    pub modules: Vec<(String, LevelFilter)>,
+}
+
+impl LevelFilters {
+    pub fn new(default: LevelFilter) -> Self {
+        Self {
+            default,
+            modules: Vec::new(),
+        }
+    }
+
+    /// The level applying to `target`.
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    pub fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    /// The most verbose level of any module.
+    pub fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+
+    /// Sets the level of `module`, replacing any it had.
+    pub fn set(&mut self, module: &str, level: LevelFilter) {
+        self.modules.retain(|(name, _)| name != module);
+        self.modules.push((module.to_string(), level));
+    }
+
+    /// Parses directives, which override `default` in order.
+    pub fn parse(value: &str, default: LevelFilter) -> Result<Self, String> {
+        let mut filters = LevelFilters::new(default);
+        for directive in value.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let level = |level: &str| {
+                level
+                    .trim()
+                    .parse::<LevelFilter>()
+                    .map_err(|_| format!("Invalid log level {level:?} in {value:?}."))
+            };
+            match directive.split_once('=') {
+                Some((module, level_name)) => filters.set(module.trim(), level(level_name)?),
+                None => filters.default = level(directive)?,
+            }
+        }
+        Ok(filters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_filters_by_module() {
+        let filters = LevelFilters::parse(
+            "warn, modern_auth_service::openid=debug, \
+            modern_auth_service::openid::cache=trace, ipc_broker=off",
+            LevelFilter::Info,
+        )
+        .unwrap();
+        assert_eq!(filters.default, LevelFilter::Warn);
+        assert_eq!(filters.level_for("modern_auth_service"), LevelFilter::Warn);
+        assert_eq!(
+            filters.level_for("modern_auth_service::openid"),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            filters.level_for("modern_auth_service::openid::identity"),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            filters.level_for("modern_auth_service::openid::cache"),
+            LevelFilter::Trace
+        );
+        // A prefix of a name is not a parent module.
+        assert_eq!(
+            filters.level_for("modern_auth_service::openidx"),
+            LevelFilter::Warn
+        );
+        assert_eq!(filters.level_for("ipc_broker::client"), LevelFilter::Off);
+        assert_eq!(filters.max(), LevelFilter::Trace);
+
+        assert!(LevelFilters::parse("verbose", LevelFilter::Info).is_err());
+        assert!(LevelFilters::parse("openid=loud", LevelFilter::Info).is_err());
+        assert_eq!(
+            LevelFilters::parse("", LevelFilter::Debug).unwrap(),
+            LevelFilters::new(LevelFilter::Debug)
+        );
+    }
+}
@@ -0,0 +1,112 @@
+// Sends records to the systemd journal over its native protocol, so fields
+// such as the session can be filtered on, e.g. `journalctl SESSION=<id>`.
+
+use std::{io, os::unix::net::UnixDatagram};
+
+use log::{Level, Record};
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+pub struct Journald {
+    socket: UnixDatagram,
+}
+
+/// A journal field name: upper case letters, digits and underscores.
+fn field_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' => c.to_ascii_uppercase(),
+            'A'..='Z' | '0'..='9' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Appends a field. Values with a line break go in the binary form, their
+/// length ahead of them.
+fn push_field(datagram: &mut Vec<u8>, name: &str, value: &str) {
+    datagram.extend_from_slice(field_name(name).as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+/// The datagram of `record`, with `fields` as extra journal fields.
+fn encode(record: &Record, fields: &[(String, String)]) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    push_field(&mut datagram, "MESSAGE", &record.args().to_string());
+    push_field(
+        &mut datagram,
+        "PRIORITY",
+        &priority(record.level()).to_string(),
+    );
+    push_field(&mut datagram, "SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME"));
+    push_field(&mut datagram, "TARGET", record.target());
+    if let Some(file) = record.file() {
+        push_field(&mut datagram, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        push_field(&mut datagram, "CODE_LINE", &line.to_string());
+    }
+    for (name, value) in fields {
+        push_field(&mut datagram, name, value);
+    }
+    datagram
+}
+
+impl Journald {
+    pub fn connect() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET)?;
+        Ok(Self { socket })
+    }
+
+    pub fn send(&self, record: &Record, fields: &[(String, String)]) -> io::Result<()> {
+        self.socket.send(&encode(record, fields)).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_journal_fields() {
+        let fields = [
+            ("session".to_string(), "abc".to_string()),
+            ("error_code".to_string(), "invalid_grant".to_string()),
+        ];
+        let datagram = encode(
+            &Record::builder()
+                .args(format_args!("two\nlines"))
+                .level(Level::Warn)
+                .target("modern_auth_service::oauth2")
+                .build(),
+            &fields,
+        );
+
+        let mut message = b"MESSAGE\n".to_vec();
+        message.extend_from_slice(&9u64.to_le_bytes());
+        message.extend_from_slice(b"two\nlines\n");
+        assert!(datagram.starts_with(&message));
+        let text = String::from_utf8_lossy(&datagram);
+        assert!(text.contains("\nPRIORITY=4\n"));
+        assert!(text.contains("\nTARGET=modern_auth_service::oauth2\n"));
+        assert!(text.contains("\nSESSION=abc\n"));
+        assert!(text.ends_with("\nERROR_CODE=invalid_grant\n"));
+    }
+}
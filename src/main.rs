@@ -1,22 +1,34 @@
 mod http_client;
 mod interface;
 mod logger;
+mod metrics;
 #[allow(dead_code)]
 mod oauth2;
 mod openid;
+mod service;
 #[allow(dead_code)]
 mod shared_object;
 #[allow(dead_code)]
 mod task_manager;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
 
 use interface::production::Production;
+use metrics::endpoint;
+use oauth2::session::{self, EVENT_OBJECT};
 
-use ipc_broker::{client::IPCClient, worker::WorkerBuilder};
 use oauth2::error::OAuth2Result;
 
+use service::{
+    IdlePolicy, SHUTDOWN_GRACE,
+    broker::{self, BrokerConnection},
+    systemd, termination_signal,
+};
 use shared_object::DeviceCodeFlowObject;
 use task_manager::TaskManager;
-use tokio::sync::mpsc::unbounded_channel;
+use telemetry::otlp;
+use tokio::sync::{mpsc::unbounded_channel, watch};
 
 use crate::{
     http_client::{HttpClient, curl::Curl},
@@ -31,29 +43,60 @@ async fn main() -> OAuth2Result<()> {
 
     log::info!("Starting modern-auth-service v.{}", version);
 
+    if let Some(address) = endpoint::address_from_env() {
+        match endpoint::bind(address).await {
+            Ok(listener) => {
+                tokio::spawn(endpoint::serve(listener));
+            }
+            Err(err) => log::error!("Metrics endpoint not opened: {err}"),
+        }
+    }
+
+    otlp::start(otlp::endpoint_from_env());
+
     let (tx, rx) = unbounded_channel();
-    let connector = IPCClient::connect().await?;
+    let connection = BrokerConnection::start();
     let http_client = HttpClient::Curl(Curl::default());
-    let interface = Production::new(connector, http_client)?;
+    let interface = Production::new(connection.clone(), http_client)?;
     let object = DeviceCodeFlowObject::new(interface.clone(), tx.clone());
+    object.resume_logins();
+    let provider_errors = object.provider_errors();
 
-    let (builder, shutdown) = WorkerBuilder::new()
-        .add("oauth2.device.code.flow", object)
-        .with_graceful_shutdown();
-
-    let handle = tokio::spawn(async move { builder.spawn().await });
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    let mut handle = tokio::spawn(broker::serve(
+        connection.clone(),
+        EVENT_OBJECT,
+        object,
+        shutdown_rx,
+    ));
+    connection.wait_for_object(EVENT_OBJECT).await;
+    systemd::ready();
 
+    let idle_shutdown = shutdown.clone();
     let task_handle = tokio::spawn(async move {
-        let mut task = TaskManager::new(rx);
+        let mut task = TaskManager::new(rx)
+            .with_idle_policy(IdlePolicy::from_env())
+            .with_provider_errors(provider_errors)
+            .with_shared_events(session::shared_events_from_env());
 
         task.run(interface).await;
-        let _ = shutdown.send(true);
+        systemd::stopping("Shutting down");
+        let _ = idle_shutdown.send(true);
     });
 
-    handle.await??;
+    tokio::select! {
+        result = &mut handle => result??,
+        _ = termination_signal() => {
+            systemd::stopping("Terminating");
+            // The worker finishes the call in progress, then takes no more.
+            let _ = shutdown.send(true);
+            handle.await??;
+        }
+    }
 
-    let _ = tx.send(TaskMessage::Quit);
+    let _ = tx.send(TaskMessage::Shutdown(SHUTDOWN_GRACE));
     let _ = task_handle.await;
+    otlp::flush().await;
     log::info!("Stopping modern-auth-service v.{}", version);
 
     Ok(())
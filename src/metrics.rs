@@ -0,0 +1,256 @@
+pub mod endpoint;
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use crate::oauth2::error::OAuth2Error;
+
+/// Upper bounds in seconds of the latency histogram buckets.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Label names and values of one series, in a fixed order.
+type Labels = Vec<(&'static str, String)>;
+
+fn labels(pairs: &[(&'static str, &str)]) -> Labels {
+    pairs
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut pairs: Vec<_> = labels
+        .iter()
+        .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{le}\""));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    values: Mutex<BTreeMap<Labels, u64>>,
+}
+
+impl Counter {
+    fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, pairs: &[(&'static str, &str)]) {
+        *self
+            .values
+            .lock()
+            .unwrap()
+            .entry(labels(pairs))
+            .or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# TYPE {} counter", self.name);
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        for (labels, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}_total{} {value}",
+                self.name,
+                format_labels(labels, None)
+            );
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    /// Observations at most each bound of [`LATENCY_BUCKETS`].
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    values: Mutex<BTreeMap<Labels, Buckets>>,
+}
+
+impl Histogram {
+    fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, pairs: &[(&'static str, &str)], duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut values = self.values.lock().unwrap();
+        let buckets = values.entry(labels(pairs)).or_default();
+        buckets.counts.resize(LATENCY_BUCKETS.len(), 0);
+        for (count, bound) in buckets.counts.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        buckets.sum += seconds;
+        buckets.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+        let _ = writeln!(out, "# UNIT {} seconds", self.name);
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        for (labels, buckets) in self.values.lock().unwrap().iter() {
+            for (count, bound) in buckets.counts.iter().zip(LATENCY_BUCKETS) {
+                // `{:?}` keeps the fraction, as in `10.0`, which OpenMetrics asks for.
+                let le = format_labels(labels, Some(&format!("{bound:?}")));
+                let _ = writeln!(out, "{}_bucket{le} {count}", self.name);
+            }
+            let le = format_labels(labels, Some("+Inf"));
+            let _ = writeln!(out, "{}_bucket{le} {}", self.name, buckets.count);
+            let labels = format_labels(labels, None);
+            let _ = writeln!(out, "{}_sum{labels} {}", self.name, buckets.sum);
+            let _ = writeln!(out, "{}_count{labels} {}", self.name, buckets.count);
+        }
+    }
+}
+
+pub struct Metrics {
+    /// By `provider`.
+    pub logins_started: Counter,
+    /// By `provider`.
+    pub logins_completed: Counter,
+    /// By `provider` and `error_code`.
+    pub logins_failed: Counter,
+    /// Token endpoint answers while polling, by `result`.
+    pub polls: Counter,
+    /// By `result`.
+    pub refreshes: Counter,
+    pub invalid_grant_deletions: Counter,
+    /// By `token` type and `result`.
+    pub verifications: Counter,
+    pub discovery_duration: Histogram,
+    /// By `host` and `status`, `error` when no response came back.
+    pub http_requests: Counter,
+    /// By `host`.
+    pub http_request_duration: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            logins_started: Counter::new("auth_logins_started", "Device logins started."),
+            logins_completed: Counter::new(
+                "auth_logins_completed",
+                "Device logins that got a token.",
+            ),
+            logins_failed: Counter::new("auth_logins_failed", "Device logins that failed."),
+            polls: Counter::new("auth_token_polls", "Token endpoint polls of device logins."),
+            refreshes: Counter::new("auth_token_refreshes", "Access token refreshes."),
+            invalid_grant_deletions: Counter::new(
+                "auth_invalid_grant_deletions",
+                "Stored tokens deleted after the provider rejected their refresh token.",
+            ),
+            verifications: Counter::new("auth_token_verifications", "Token verifications."),
+            discovery_duration: Histogram::new(
+                "auth_discovery_duration_seconds",
+                "OpenID discovery and key set fetches.",
+            ),
+            http_requests: Counter::new("auth_http_requests", "HTTP requests to providers."),
+            http_request_duration: Histogram::new(
+                "auth_http_request_duration_seconds",
+                "HTTP request latency.",
+            ),
+        }
+    }
+
+    fn render(&self, out: &mut String) {
+        for counter in [
+            &self.logins_started,
+            &self.logins_completed,
+            &self.logins_failed,
+            &self.polls,
+            &self.refreshes,
+            &self.invalid_grant_deletions,
+            &self.verifications,
+            &self.http_requests,
+        ] {
+            counter.render(out);
+        }
+        for histogram in [&self.discovery_duration, &self.http_request_duration] {
+            histogram.render(out);
+        }
+    }
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Every metric in the OpenMetrics text format.
+pub fn render() -> String {
+    let mut out = String::new();
+    METRICS.render(&mut out);
+    out.push_str("# EOF\n");
+    out
+}
+
+/// The `result` label of an outcome: `ok`, or its error code.
+pub fn result_label<T>(result: &Result<T, OAuth2Error>) -> &str {
+    match result {
+        Ok(_) => "ok",
+        Err(err) => err.error_code.as_ref(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_openmetrics() {
+        let mut out = String::new();
+        let counter = Counter::new("test_requests", "Requests.");
+        counter.inc(&[("host", "example.com"), ("status", "200")]);
+        counter.inc(&[("host", "example.com"), ("status", "200")]);
+        counter.inc(&[("host", "a\"b"), ("status", "error")]);
+        counter.render(&mut out);
+
+        let histogram = Histogram::new("test_duration_seconds", "Latency.");
+        histogram.observe(&[], Duration::from_millis(30));
+        histogram.observe(&[], Duration::from_secs(20));
+        histogram.render(&mut out);
+
+        assert!(out.contains("# TYPE test_requests counter\n"));
+        assert!(out.contains("test_requests_total{host=\"example.com\",status=\"200\"} 2\n"));
+        assert!(out.contains("test_requests_total{host=\"a\\\"b\",status=\"error\"} 1\n"));
+        assert!(out.contains("test_duration_seconds_bucket{le=\"0.025\"} 0\n"));
+        assert!(out.contains("test_duration_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(out.contains("test_duration_seconds_bucket{le=\"10.0\"} 1\n"));
+        assert!(out.contains("test_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(out.contains("test_duration_seconds_count 2\n"));
+    }
+}
@@ -0,0 +1,127 @@
+// A minimal HTTP endpoint serving the metrics to a local scraper.
+
+use std::{io, net::SocketAddr, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Environment variable holding the loopback address to serve `/metrics`
+/// on, e.g. `127.0.0.1:9464`. Unset, no endpoint is opened.
+pub const METRICS_ADDR_ENV: &str = "AUTH_SERVICE_METRICS_ADDR";
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+/// Largest request head read before giving up on a client.
+const MAX_REQUEST: usize = 8192;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The address from `AUTH_SERVICE_METRICS_ADDR`. Only loopback addresses
+/// are accepted, as the metrics are not meant to leave the host.
+pub fn address_from_env() -> Option<SocketAddr> {
+    let value = std::env::var(METRICS_ADDR_ENV).ok()?;
+    match value.parse::<SocketAddr>() {
+        Ok(address) if address.ip().is_loopback() => Some(address),
+        Ok(address) => {
+            log::warn!("Metrics endpoint not opened: {address} is not a loopback address.");
+            None
+        }
+        Err(err) => {
+            log::warn!("Metrics endpoint not opened: invalid address {value:?}: {err}");
+            None
+        }
+    }
+}
+
+pub async fn bind(address: SocketAddr) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(address).await?;
+    log::info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    Ok(listener)
+}
+
+pub async fn serve(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    if let Err(err) = respond(stream).await {
+                        log::debug!("Metrics request failed: {err}");
+                    }
+                });
+            }
+            Err(err) => {
+                log::error!("Metrics endpoint: {err}");
+                // E.g. out of file descriptors; don't spin until some close.
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Request too large",
+            ));
+        }
+        let read = tokio::time::timeout(READ_TIMEOUT, stream.read(&mut buffer)).await??;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let head = String::from_utf8_lossy(&request);
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let (status, content_type, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", CONTENT_TYPE, super::render()),
+        _ => (
+            "404 Not Found",
+            "text/plain; charset=utf-8",
+            "Not found.\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_serves_metrics() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener));
+
+        let response = get(address, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(CONTENT_TYPE));
+        assert!(response.contains("# TYPE auth_logins_started counter"));
+        assert!(response.ends_with("# EOF\n"));
+
+        let response = get(address, "/").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
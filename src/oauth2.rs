@@ -1,6 +1,10 @@
 pub mod device_code_flow;
 pub mod error;
+pub mod pending;
+pub mod polling;
+pub mod prompt;
 pub mod provider;
+pub mod session;
 #[cfg(test)]
 mod tests;
 pub mod token_keeper;
@@ -2,6 +2,8 @@
 use std::{
     fmt::Display,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 // 3rd party crates
@@ -18,7 +20,7 @@ use oauth2::{
     },
 };
 
-use openidconnect::core::CoreIdToken;
+use openidconnect::{Nonce, core::CoreIdToken};
 use serde::{Deserialize, Serialize};
 use tokio::sync::{mpsc::UnboundedSender, oneshot};
 
@@ -26,13 +28,36 @@ use tokio::sync::{mpsc::UnboundedSender, oneshot};
 use crate::{
     http_client::OAuth2Client,
     interface::Interface,
+    logger::{self, LogFields},
+    metrics::METRICS,
     oauth2::{provider::InputParameters, token_keeper::TokenKeeper},
+    telemetry::{self, Span},
 };
 use crate::{
+    http_client::redact::redact_value,
     oauth2::error::{ErrorCodes, OAuth2Error, OAuth2Result},
+    oauth2::pending::{PendingLogin, unix_now},
+    oauth2::polling::PollingSchedule,
+    oauth2::prompt::LoginPrompt,
+    oauth2::session::{LoginSession, LoginState, LoginStatus, TokenSecret, polling_event},
+    openid::{cache::ProviderCache, identity::identify},
     task_manager::TaskMessage,
 };
 
+/// The device authorization response, plus the session its events carry.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoginResponse {
+    pub session_id: String,
+    /// Required by `requestToken` unless tokens are published in events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_secret: Option<TokenSecret>,
+    #[serde(flatten)]
+    pub device_authorization: StandardDeviceAuthorizationResponse,
+    /// Present when the login was started with `include_qr_code`.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<LoginPrompt>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CustomExtraFields {
     pub id_token: Option<CoreIdToken>,
@@ -66,11 +91,14 @@ pub trait DeviceCodeFlowTrait {
     async fn request_device_code<I: Interface + Send + Sync + Clone + 'static>(
         &self,
         scopes: Vec<Scope>,
+        nonce: Option<&str>,
         interface: I,
     ) -> OAuth2Result<StandardDeviceAuthorizationResponse>;
     async fn poll_access_token<I: Interface + Send + Sync + Clone + 'static>(
         &self,
         device_auth_response: StandardDeviceAuthorizationResponse,
+        session: &LoginSession,
+        schedule: PollingSchedule,
         interface: I,
     ) -> OAuth2Result<CustomTokenResponse>;
     async fn get_access_token<I: Interface + Send + Sync + Clone + 'static>(
@@ -94,6 +122,7 @@ impl DeviceCodeFlowTrait for DeviceCodeFlow {
     async fn request_device_code<I: Interface + Send + Sync + Clone + 'static>(
         &self,
         scopes: Vec<Scope>,
+        nonce: Option<&str>,
         interface: I,
     ) -> OAuth2Result<StandardDeviceAuthorizationResponse> {
         log::info!(
@@ -104,20 +133,26 @@ impl DeviceCodeFlowTrait for DeviceCodeFlow {
             client = client.set_client_secret(client_secret);
         }
         let http_client = OAuth2Client::new(interface);
-        let device_auth_response = client
+        let client = client
             .set_auth_type(oauth2::AuthType::RequestBody)
             .set_token_uri(self.token_endpoint.to_owned())
-            .set_device_authorization_url(self.device_auth_endpoint.to_owned())
-            .exchange_device_code()
-            .add_scopes(scopes)
+            .set_device_authorization_url(self.device_auth_endpoint.to_owned());
+        let mut request = client.exchange_device_code().add_scopes(scopes);
+        if let Some(nonce) = nonce {
+            request = request.add_extra_param("nonce", nonce);
+        }
+        let device_auth_response = request
             .request_async(&http_client)
-            .await?;
+            .await
+            .map_err(|err| http_client.error(err))?;
 
         Ok(device_auth_response)
     }
     async fn poll_access_token<I: Interface + Send + Sync + Clone + 'static>(
         &self,
         device_auth_response: StandardDeviceAuthorizationResponse,
+        session: &LoginSession,
+        schedule: PollingSchedule,
         interface: I,
     ) -> OAuth2Result<CustomTokenResponse> {
         let mut client = CustomClient::new(self.client_id.to_owned());
@@ -126,6 +161,11 @@ impl DeviceCodeFlowTrait for DeviceCodeFlow {
         }
         let http_client = OAuth2Client::new(interface.clone());
         let task_message = self.tx.clone();
+        let session = session.clone();
+        let max_duration = Duration::from_secs(schedule.max_duration);
+        // Shared by the request callback, which grows the interval on
+        // `slow_down`, and the sleep function, which follows it.
+        let schedule = Arc::new(Mutex::new(schedule));
         let token_result = client
             .set_auth_type(oauth2::AuthType::RequestBody)
             .set_token_uri(self.token_endpoint.to_owned())
@@ -134,20 +174,67 @@ impl DeviceCodeFlowTrait for DeviceCodeFlow {
                 &|request| {
                     let http_client = http_client.clone();
                     let task_message = task_message.clone();
+                    let session = session.clone();
+                    let schedule = schedule.clone();
                     async move {
-                        let result = http_client.call(request).await?;
+                        let mut span = Span::start("token.poll");
+                        let result = span
+                            .scope(http_client.call(request))
+                            .await
+                            .inspect_err(|err| span.fail(err.error_code.as_ref()))?;
 
                         let value: serde_json::Value = serde_json::from_slice(result.body())
                             .unwrap_or_else(|er| serde_json::json!({"error": er.to_string()}));
-                        let _ = task_message
-                            .send(TaskMessage::SendEvent("token.polling".into(), value));
+                        let poll_result = match value["error"].as_str() {
+                            Some(error) if polling_event(error).is_some() => error,
+                            Some(_) => "error",
+                            None => "ok",
+                        };
+                        METRICS.polls.inc(&[("result", poll_result)]);
+                        span.set_attribute("auth.poll.result", poll_result);
+                        if let Some(event) = value["error"].as_str().and_then(polling_event) {
+                            let polling = {
+                                let mut schedule = schedule.lock().unwrap();
+                                if value["error"] == "slow_down" {
+                                    schedule.slow_down();
+                                }
+                                schedule.clone()
+                            };
+                            if value["error"] == "slow_down" {
+                                let _ = task_message.send(TaskMessage::Reschedule(
+                                    session.clone(),
+                                    polling.clone(),
+                                ));
+                            }
+                            let _ = task_message.send(TaskMessage::SendSessionEvent(
+                                session.clone(),
+                                event.into(),
+                                serde_json::json!({
+                                    "error": value["error"],
+                                    "error_description": value["error_description"],
+                                    "polling": polling,
+                                }),
+                            ));
+                        }
+                        let value = if session.tokens_in_events {
+                            value
+                        } else {
+                            redact_value(&value)
+                        };
+                        let _ = task_message.send(TaskMessage::SendSessionEvent(
+                            session,
+                            "token.polling".into(),
+                            value,
+                        ));
                         Ok::<HttpResponse, OAuth2Error>(result)
                     }
                 },
-                tokio::time::sleep,
-                None,
+                // The schedule replaces the interval the oauth2 crate keeps.
+                |_| tokio::time::sleep(schedule.lock().unwrap().next_wait()),
+                Some(max_duration),
             )
-            .await?;
+            .await
+            .map_err(|err| http_client.error(err))?;
 
         log::info!("Access token successfuly retrieved from the endpoint.");
         Ok(token_result)
@@ -173,23 +260,50 @@ impl DeviceCodeFlowTrait for DeviceCodeFlow {
                         client = client.set_client_secret(client_secret);
                     }
                     let async_http_callback = OAuth2Client::new(interface.clone());
-                    let response = client
-                        .set_auth_type(oauth2::AuthType::RequestBody)
-                        .set_token_uri(self.token_endpoint.to_owned())
-                        .exchange_refresh_token(&ref_token)
-                        .request_async(&async_http_callback)
+                    let mut span = Span::start("token.refresh");
+                    let response = span
+                        .scope(
+                            client
+                                .set_auth_type(oauth2::AuthType::RequestBody)
+                                .set_token_uri(self.token_endpoint.to_owned())
+                                .exchange_refresh_token(&ref_token)
+                                .request_async(&async_http_callback),
+                        )
                         .await;
 
                     match response {
                         Ok(res) => {
+                            METRICS.refreshes.inc(&[("result", "ok")]);
+                            let identity = token_keeper.identity.take();
+                            let nonce = token_keeper.nonce.take();
+                            let token_secret_hash = token_keeper.token_secret_hash.take();
                             token_keeper = TokenKeeper::from(res);
+                            token_keeper.token_secret_hash = token_secret_hash;
+                            // Without a new ID token the user is still the same,
+                            // and the ID token the caller holds is still checked
+                            // against the login's nonce. A new one carries no
+                            // nonce unless the provider repeats it.
+                            let same_id_token = token_keeper.id_token.is_none();
+                            if same_id_token {
+                                token_keeper.identity = identity;
+                            }
+                            token_keeper.set_nonce(nonce, same_id_token);
                             token_keeper.set_directory(file_directory.to_path_buf());
                             token_keeper.save(file_name)?;
                             Ok(token_keeper)
                         }
                         Err(e) => {
-                            let error = OAuth2Error::from(e);
+                            let error = async_http_callback.error(e);
+                            span.fail(error.error_code.as_ref());
+                            log::warn!(
+                                error_code = error.error_code.as_ref();
+                                "Refreshing the access token failed: {error}"
+                            );
+                            METRICS
+                                .refreshes
+                                .inc(&[("result", error.error_code.as_ref())]);
                             if error.error_code == ErrorCodes::InvalidGrant {
+                                METRICS.invalid_grant_deletions.inc(&[]);
                                 let file = TokenKeeper::new(file_directory.to_path_buf());
                                 if let Err(e) = file.delete(file_name) {
                                     log::error!("{:?}", e);
@@ -217,6 +331,31 @@ impl DeviceCodeFlowTrait for DeviceCodeFlow {
 }
 
 impl DeviceCodeFlow {
+    pub fn from_provider(
+        provider: &InputParameters,
+        tx: UnboundedSender<TaskMessage>,
+    ) -> OAuth2Result<Self> {
+        Ok(Self::new(
+            provider.client_id.clone().ok_or(OAuth2Error::new(
+                ErrorCodes::ParseError,
+                "No Client ID supplied.".into(),
+            ))?,
+            provider.client_secret.clone(),
+            provider
+                .device_auth_endpoint
+                .clone()
+                .ok_or(OAuth2Error::new(
+                    ErrorCodes::ParseError,
+                    "No Device Auth URL supplied.".into(),
+                ))?,
+            provider.token_endpoint.clone().ok_or(OAuth2Error::new(
+                ErrorCodes::ParseError,
+                "No Token URL supplied.".into(),
+            ))?,
+            tx,
+        ))
+    }
+
     pub fn new(
         client_id: ClientId,
         client_secret: Option<ClientSecret>,
@@ -269,9 +408,37 @@ fn make_token_dir() -> Result<PathBuf, OAuth2Error> {
     Ok(directory)
 }
 
-fn make_filename(param: &InputParameters) -> Result<PathBuf, OAuth2Error> {
+/// Uses the caller's nonce, or generates one when an ID token is requested,
+/// so that `verifyIDToken` can later detect replayed ID tokens. Providers
+/// ignore it as an unknown parameter unless they support it.
+fn make_nonce(param: &InputParameters) -> Option<String> {
+    param.nonce.clone().or_else(|| {
+        param
+            .scopes
+            .as_ref()?
+            .iter()
+            .any(|scope| scope.as_str() == "openid")
+            .then(|| Nonce::new_random().secret().to_owned())
+    })
+}
+
+/// The token stored for `param`'s process and provider, as is.
+pub fn stored_token<I>(param: &InputParameters, interface: &I) -> Option<TokenKeeper>
+where
+    I: Interface + Send + Sync + 'static + Clone,
+{
+    let token_file = make_filename(param).ok()?;
+    let mut token_keeper = TokenKeeper::new(interface.token_directory());
+    token_keeper.read(&token_file).ok()?;
+    Some(token_keeper)
+}
+
+/// Ends the name of every token file.
+const TOKEN_FILE_SUFFIX: &str = "DeviceCodeFlow";
+
+pub fn make_filename(param: &InputParameters) -> Result<PathBuf, OAuth2Error> {
     Ok(PathBuf::from(format!(
-        "{}{}DeviceCodeFlow",
+        "{}{}{TOKEN_FILE_SUFFIX}",
         param.process.clone().ok_or(OAuth2Error::new(
             ErrorCodes::ParseError,
             "No Process Name supplied.".into(),
@@ -283,103 +450,268 @@ fn make_filename(param: &InputParameters) -> Result<PathBuf, OAuth2Error> {
     )))
 }
 
+/// Number of tokens stored, one per process and provider logged in.
+pub fn stored_sessions(directory: &Path) -> usize {
+    std::fs::read_dir(directory).map_or(0, |entries| {
+        entries
+            .flatten()
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with(TOKEN_FILE_SUFFIX)
+            })
+            .count()
+    })
+}
+
 pub async fn login<I>(
     provider: InputParameters,
     interface: I,
     tx: UnboundedSender<TaskMessage>,
-) -> Result<StandardDeviceAuthorizationResponse, OAuth2Error>
+    cache: &ProviderCache,
+) -> Result<LoginResponse, OAuth2Error>
 where
     I: Interface + Send + Sync + 'static + Clone,
 {
     log::trace!("login({:?})", provider);
+    let include_qr_code = provider.include_qr_code.unwrap_or_default();
+    let polling_policy = provider.polling_policy.clone().unwrap_or_default();
 
     let token_dir = interface.token_directory();
     let token_file = make_filename(&provider)?;
+    let nonce = make_nonce(&provider);
+    let session = LoginSession::new(&provider);
+    let token_secret = (!session.tokens_in_events).then(TokenSecret::new_random);
+    let token_secret_hash = token_secret.as_ref().map(TokenSecret::hash);
 
-    let device_code_flow = DeviceCodeFlow::new(
-        provider.client_id.ok_or(OAuth2Error::new(
-            ErrorCodes::ParseError,
-            "No Client ID supplied.".into(),
-        ))?,
-        provider.client_secret,
-        provider.device_auth_endpoint.ok_or(OAuth2Error::new(
-            ErrorCodes::ParseError,
-            "No Device Auth URL supplied.".into(),
-        ))?,
-        provider.token_endpoint.ok_or(OAuth2Error::new(
-            ErrorCodes::ParseError,
-            "No Token URL supplied.".into(),
-        ))?,
-        tx.clone(),
-    );
+    let device_code_flow = DeviceCodeFlow::from_provider(&provider, tx.clone())?;
 
     let (oneshot_tx, oneshot_rx) = oneshot::channel();
     tx.send(TaskMessage::Check(token_file.clone(), oneshot_tx))?;
     if let Ok(existing) = oneshot_rx.await
         && existing
     {
-        tx.send(TaskMessage::Abort(token_file.clone()))?;
-        log::info!("task aborted ...");
+        tx.send(TaskMessage::Supersede(token_file.clone()))?;
+        log::info!("task superseded ...");
     }
 
     let device_auth_response = device_code_flow
         .request_device_code(
-            provider.scopes.ok_or(OAuth2Error::new(
+            provider.scopes.clone().ok_or(OAuth2Error::new(
                 ErrorCodes::ParseError,
                 "No Scopes supplied.".into(),
             ))?,
+            nonce.as_deref(),
             interface.clone(),
         )
         .await?;
 
-    let result = device_auth_response.clone();
+    let result = LoginResponse {
+        session_id: session.session_id.clone(),
+        token_secret,
+        prompt: include_qr_code.then(|| LoginPrompt::new(&device_auth_response)),
+        device_authorization: device_auth_response.clone(),
+    };
+    let pending = PendingLogin::new(
+        &provider,
+        session.session_id,
+        device_auth_response.clone(),
+        nonce,
+        token_secret_hash,
+        polling_policy.schedule(&device_auth_response),
+    );
+    // Polling still works without the file, only not across a restart.
+    if let Err(err) = pending.save(&token_dir, &token_file) {
+        log::error!("Pending login not saved: {err}");
+    }
+    start_polling(pending, token_file, interface, tx, cache)?;
+    METRICS
+        .logins_started
+        .inc(&[("provider", provider.provider.as_deref().unwrap_or_default())]);
+
+    Ok(result)
+}
+
+/// Polls the token endpoint in the background for `pending`, and publishes
+/// the outcome as `login` always has.
+fn start_polling<I>(
+    pending: PendingLogin,
+    token_file: PathBuf,
+    interface: I,
+    tx: UnboundedSender<TaskMessage>,
+    cache: &ProviderCache,
+) -> OAuth2Result<()>
+where
+    I: Interface + Send + Sync + 'static + Clone,
+{
+    let provider = pending.provider;
+    let identity_request = provider
+        .include_identity
+        .unwrap_or_default()
+        .then(|| (provider.clone(), cache.clone()));
+    let device_code_flow = DeviceCodeFlow::from_provider(&provider, tx.clone())?;
+    let token_dir = interface.token_directory();
+    let nonce = pending.nonce;
+    let nonce_required = provider.nonce.is_some();
+    let token_secret_hash = pending.token_secret_hash;
+    let device_auth_response = pending.device_authorization;
+    let session = LoginSession::resume(&provider, pending.session_id);
+    let mut schedule = pending.polling;
+    schedule.max_duration = pending.expires_at.saturating_sub(unix_now());
+
     let token_file_clone = token_file.clone();
+    let status = LoginStatus::new(session.clone(), &device_auth_response, schedule.clone());
+    let fields = LogFields {
+        session: Some(session.session_id.clone()),
+        process: session.process.clone(),
+        provider: session.provider.clone(),
+        method: None,
+    };
+    let task_session = session;
     // Start polling at the background
     let inner_tx = tx.clone();
-    let handle = tokio::spawn(async move {
-        let result = device_code_flow
-            .poll_access_token(device_auth_response, interface.clone())
+    let handle = tokio::spawn(telemetry::propagate(logger::scope(fields, async move {
+        let session = task_session;
+        let mut span = Span::start("device_login.poll");
+        span.set_attribute("auth.session_id", session.session_id.as_str());
+        let result = span
+            .scope(device_code_flow.poll_access_token(
+                device_auth_response,
+                &session,
+                schedule,
+                interface.clone(),
+            ))
             .await;
+        span.record(&result);
+        drop(span);
 
+        let mut outcome = Ok(());
         let value = match result {
             Ok(token) => {
+                inner_tx
+                    .send(TaskMessage::Saving(
+                        token_file_clone.clone(),
+                        session.session_id.clone(),
+                    ))
+                    .unwrap_or_else(|e| {
+                        log::error!("{:?}", e);
+                    });
                 let mut token_keeper = TokenKeeper::from(token);
                 token_keeper.set_directory(token_dir);
+                token_keeper.set_nonce(nonce, nonce_required);
+                token_keeper.token_secret_hash = token_secret_hash;
+                if let Some((provider, cache)) = &identity_request {
+                    token_keeper.identity =
+                        identify(provider, &token_keeper, cache, interface.clone()).await;
+                }
                 if let Err(err) = token_keeper.save(&token_file_clone) {
+                    outcome = Err(err.clone());
                     JsonResult::<(), OAuth2Error>(Err(err)).into()
                 } else {
-                    JsonResult::<TokenKeeper, OAuth2Error>(Ok(token_keeper)).into()
+                    let completed = serde_json::json!({ "identity": token_keeper.identity });
+                    inner_tx
+                        .send(TaskMessage::SendSessionEvent(
+                            session.clone(),
+                            LoginState::Completed.event().into(),
+                            completed,
+                        ))
+                        .unwrap_or_else(|e| {
+                            log::error!("{:?}", e);
+                        });
+                    if session.tokens_in_events {
+                        JsonResult::<_, OAuth2Error>(Ok(token_keeper.for_caller())).into()
+                    } else {
+                        JsonResult::<_, OAuth2Error>(Ok(token_keeper.without_secrets())).into()
+                    }
                 }
             }
             Err(err) => {
-                log::error!("{err}");
+                log::error!(error_code = err.error_code.as_ref(); "{err}");
+                outcome = Err(err.clone());
                 JsonResult::<(), OAuth2Error>(Err(err)).into()
             }
         };
+        let provider = session.provider.as_deref().unwrap_or_default();
+        match &outcome {
+            Ok(()) => METRICS.logins_completed.inc(&[("provider", provider)]),
+            Err(err) => METRICS.logins_failed.inc(&[
+                ("provider", provider),
+                ("error_code", err.error_code.as_ref()),
+            ]),
+        }
+        if let Err(err) = &outcome {
+            inner_tx
+                .send(TaskMessage::SendSessionEvent(
+                    session.clone(),
+                    LoginState::from_result(&outcome).event().into(),
+                    serde_json::to_value(err).unwrap_or_default(),
+                ))
+                .unwrap_or_else(|e| {
+                    log::error!("{:?}", e);
+                });
+        }
 
         log::info!("Sending of event . . . .");
         // Sending to event result to the subscribers
         // Task is done, removing from the list
         inner_tx
-            .send(TaskMessage::SendEvent("token.ready".into(), value))
+            .send(TaskMessage::SendSessionEvent(
+                session.clone(),
+                "token.ready".into(),
+                value,
+            ))
             .unwrap_or_else(|e| {
                 log::error!("{:?}", e);
             });
         // Task is done, removing from the list
         inner_tx
-            .send(TaskMessage::PollingDone(token_file_clone))
+            .send(TaskMessage::PollingDone(
+                token_file_clone,
+                session.session_id.clone(),
+                outcome,
+            ))
             .unwrap_or_else(|e| {
                 log::error!("{:?}", e);
             });
         log::info!("Event Sent!!!. . . .");
-    });
+    })));
     // Send this polling task to the background
-    tx.send(TaskMessage::Add(token_file, handle))
+    tx.send(TaskMessage::Add(token_file, status, handle))
         .unwrap_or_else(|e| {
             log::error!("{:?}", e);
         });
+    Ok(())
+}
 
-    Ok(result)
+/// Resumes polling for the logins still pending when the service stopped.
+pub fn resume_logins<I>(interface: I, tx: UnboundedSender<TaskMessage>, cache: &ProviderCache)
+where
+    I: Interface + Send + Sync + 'static + Clone,
+{
+    let token_dir = interface.token_directory();
+    for (token_file, pending) in PendingLogin::load_all(&token_dir) {
+        if pending.remaining().is_zero() {
+            log::info!(
+                "Pending login {} expired while stopped.",
+                pending.session_id
+            );
+            PendingLogin::remove(&token_dir, &token_file, &pending.session_id);
+            continue;
+        }
+        log::info!("Resuming pending login {}.", pending.session_id);
+        let session_id = pending.session_id.clone();
+        if let Err(err) = start_polling(
+            pending,
+            token_file.clone(),
+            interface.clone(),
+            tx.clone(),
+            cache,
+        ) {
+            log::error!("{err}");
+            PendingLogin::remove(&token_dir, &token_file, &session_id);
+        }
+    }
 }
 
 pub async fn cancel(
@@ -393,15 +725,65 @@ pub async fn cancel(
     Ok(true)
 }
 
+/// Default and upper bound of how long `awaitLogin` waits, in seconds.
+const AWAIT_TIMEOUT: u64 = 30;
+const MAX_AWAIT_TIMEOUT: u64 = 300;
+
+pub async fn login_status(
+    provider: InputParameters,
+    tx: UnboundedSender<TaskMessage>,
+) -> Result<LoginStatus, OAuth2Error> {
+    log::trace!("loginStatus({:?})", provider);
+
+    let token_file = make_filename(&provider)?;
+    let (oneshot_tx, oneshot_rx) = oneshot::channel();
+    tx.send(TaskMessage::Status(token_file, oneshot_tx))?;
+    oneshot_rx.await.ok().flatten().ok_or(OAuth2Error::new(
+        ErrorCodes::InvalidParameters,
+        "No login started for this process and provider.".into(),
+    ))
+}
+
+/// Waits until the pending login finishes or `timeout` seconds pass, then
+/// reports its status.
+pub async fn await_login(
+    provider: InputParameters,
+    tx: UnboundedSender<TaskMessage>,
+) -> Result<LoginStatus, OAuth2Error> {
+    log::trace!("awaitLogin({:?})", provider);
+
+    let timeout = provider
+        .timeout
+        .unwrap_or(AWAIT_TIMEOUT)
+        .min(MAX_AWAIT_TIMEOUT);
+    let token_file = make_filename(&provider)?;
+    let (oneshot_tx, oneshot_rx) = oneshot::channel();
+    tx.send(TaskMessage::Await(token_file, oneshot_tx))?;
+
+    match tokio::time::timeout(Duration::from_secs(timeout), oneshot_rx).await {
+        Ok(status) => status.ok().flatten().ok_or(OAuth2Error::new(
+            ErrorCodes::InvalidParameters,
+            "No login started for this process and provider.".into(),
+        )),
+        Err(_) => login_status(provider, tx).await,
+    }
+}
+
 pub async fn request_token<I>(
     provider: InputParameters,
     interface: I,
     tx: UnboundedSender<TaskMessage>,
+    cache: &ProviderCache,
 ) -> Result<TokenKeeper, OAuth2Error>
 where
     I: Interface + Send + Sync + 'static + Clone,
 {
     log::trace!("requestToken({:?})", provider);
+    let include_identity = provider.include_identity.unwrap_or_default();
+    let identity_provider = include_identity.then(|| provider.clone());
+    if let Some(stored) = stored_token(&provider, &interface) {
+        stored.authorize(provider.token_secret.as_ref())?;
+    }
 
     let token_dir = interface.token_directory();
     let token_file = make_filename(&provider)?;
@@ -423,11 +805,21 @@ where
         tx,
     );
 
-    let token_keeper = device_code_flow
+    let mut token_keeper = device_code_flow
         .get_access_token(&token_dir, &token_file, interface.clone())
         .await?;
 
-    Ok(token_keeper)
+    if let Some(provider) = identity_provider {
+        if token_keeper.identity.is_none() && token_keeper.id_token.is_some() {
+            token_keeper.identity =
+                identify(&provider, &token_keeper, cache, interface.clone()).await;
+            token_keeper.save(&token_file)?;
+        }
+    } else {
+        token_keeper.identity = None;
+    }
+
+    Ok(token_keeper.for_caller())
 }
 
 pub async fn logout<I>(provider: InputParameters, interface: I) -> Result<bool, OAuth2Error>
@@ -4,22 +4,25 @@ use std::marker::PhantomData;
 use std::{error::Error, str::FromStr};
 
 use curl_http_client::collector::Collector;
-use http::header::InvalidHeaderValue;
+use http::{StatusCode, header::InvalidHeaderValue};
 use json_result::r#enum::JsonResult;
 use log::SetLoggerError;
 // 3rd party crates
 use oauth2::{
-    ConfigurationError, ErrorResponseType, RequestTokenError, StandardErrorResponse, url,
+    ConfigurationError, ErrorResponseType, HttpResponse, RequestTokenError, StandardErrorResponse,
+    url,
 };
 use openidconnect::{ClaimsVerificationError, DiscoveryError};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use strum_macros::{AsRefStr, EnumString};
 use tokio::sync::mpsc::error::SendError;
 use tokio::task::JoinError;
 
+use crate::http_client::redact::redact_value;
 use crate::task_manager::TaskMessage;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, EnumString, AsRefStr)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, EnumString, AsRefStr)]
 #[serde(rename_all = "snake_case")]
 #[strum(serialize_all = "snake_case")]
 pub enum ErrorCodes {
@@ -60,6 +63,9 @@ pub enum ErrorCodes {
     RemoteClient,
     ClaimsVerificationError,
     DiscoveryError,
+    Cancelled,
+    Superseded,
+    InternalError,
     OtherError,
 }
 
@@ -77,19 +83,156 @@ impl From<String> for ErrorCodes {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// What a client can do about an error.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// The user has to log in again, approve the login or try it again.
+    UserActionRequired,
+    /// The provider parameters or the client registration are wrong.
+    ConfigError,
+    /// A token failed validation, e.g. it expired, was replayed or is not
+    /// signed by the provider.
+    TokenValidation,
+    /// The same request may succeed later.
+    Transient,
+    /// The service itself failed.
+    Internal,
+}
+
+impl From<&ErrorCodes> for ErrorCategory {
+    fn from(code: &ErrorCodes) -> Self {
+        match code {
+            ErrorCodes::Unauthorized
+            | ErrorCodes::Forbidden
+            | ErrorCodes::AccessDenied
+            | ErrorCodes::InvalidGrant
+            | ErrorCodes::AuthorizationPending
+            | ErrorCodes::AuthorizationDeclined
+            | ErrorCodes::ExpiredToken
+            | ErrorCodes::InteractionRequired
+            | ErrorCodes::LoginRequired
+            | ErrorCodes::NoToken
+            | ErrorCodes::Cancelled
+            | ErrorCodes::Superseded => ErrorCategory::UserActionRequired,
+            ErrorCodes::BadRequest
+            | ErrorCodes::InvalidRequest
+            | ErrorCodes::UnauthorizedClient
+            | ErrorCodes::UnsupportedResponseType
+            | ErrorCodes::InvalidScope
+            | ErrorCodes::InvalidClient
+            | ErrorCodes::UnsupportedTokenType
+            | ErrorCodes::UnsupportedGrantType
+            | ErrorCodes::ConfigurationError
+            | ErrorCodes::UrlParseError
+            | ErrorCodes::InvalidParameters => ErrorCategory::ConfigError,
+            ErrorCodes::ClaimsVerificationError => ErrorCategory::TokenValidation,
+            ErrorCodes::ServerError
+            | ErrorCodes::TemporarilyUnavailable
+            | ErrorCodes::SlowDown
+            | ErrorCodes::RequestError
+            | ErrorCodes::HttpError
+            | ErrorCodes::CurlError
+            | ErrorCodes::ReqwestError
+            | ErrorCodes::DiscoveryError => ErrorCategory::Transient,
+            ErrorCodes::SerdeJsonParseError
+            | ErrorCodes::IoError
+            | ErrorCodes::ParseError
+            | ErrorCodes::LoggerError
+            | ErrorCodes::DirectoryError
+            | ErrorCodes::ChannelError
+            | ErrorCodes::RemoteClient
+            | ErrorCodes::InternalError
+            | ErrorCodes::OtherError => ErrorCategory::Internal,
+        }
+    }
+}
+
+/// What the provider's error response said beyond the error code and
+/// description, e.g. Microsoft's `trace_id` and `correlation_id`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ProviderDetails {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_codes: Option<Vec<u64>>,
+    /// The error response as the provider sent it, credentials redacted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider_response: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "SerializedError")]
 pub struct OAuth2Error {
     pub error_code: ErrorCodes,
     pub error_code_desc: String,
+    pub category: ErrorCategory,
+    /// Whether the same request may succeed if tried again later.
+    pub retryable: bool,
+    #[serde(flatten)]
+    pub details: Box<ProviderDetails>,
+}
+
+/// An error as serialized, also by versions without its category.
+#[derive(Deserialize)]
+struct SerializedError {
+    error_code: ErrorCodes,
+    error_code_desc: String,
+    category: Option<ErrorCategory>,
+    retryable: Option<bool>,
+    #[serde(flatten)]
+    details: Box<ProviderDetails>,
+}
+
+impl From<SerializedError> for OAuth2Error {
+    fn from(value: SerializedError) -> Self {
+        let mut error = OAuth2Error::new(value.error_code, value.error_code_desc);
+        error.category = value.category.unwrap_or(error.category);
+        error.retryable = value.retryable.unwrap_or(error.retryable);
+        error.details = value.details;
+        error
+    }
 }
 
 impl OAuth2Error {
     pub fn new(error_code: ErrorCodes, error_code_desc: String) -> Self {
+        let category = ErrorCategory::from(&error_code);
         Self {
             error_code,
             error_code_desc,
+            category,
+            retryable: category == ErrorCategory::Transient,
+            details: Box::default(),
         }
     }
+
+    /// Adds what the provider's error `response` tells about the error. A
+    /// rate limit or a server error can be retried, whatever its code.
+    pub fn with_response(mut self, response: &HttpResponse) -> Self {
+        let status = response.status();
+        self.details.http_status = Some(status.as_u16());
+        if let Ok(Value::Object(body)) = serde_json::from_slice::<Value>(response.body()) {
+            let text = |name| body.get(name).and_then(Value::as_str).map(str::to_string);
+            self.details.error_uri = self.details.error_uri.take().or(text("error_uri"));
+            self.details.trace_id = text("trace_id");
+            self.details.correlation_id = text("correlation_id");
+            self.details.error_codes = body
+                .get("error_codes")
+                .and_then(|codes| serde_json::from_value(codes.clone()).ok());
+            self.details.provider_response = Some(redact_value(&Value::Object(body)));
+        }
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            self.category = ErrorCategory::Transient;
+            self.retryable = true;
+        }
+        self
+    }
 }
 
 impl From<JoinError> for OAuth2Error {
@@ -137,7 +280,9 @@ where
                     .error_description()
                     .map(|val| val.to_owned())
                     .unwrap_or_else(String::new);
-                OAuth2Error::new(ErrorCodes::from(err.clone()), desc)
+                let mut error = OAuth2Error::new(ErrorCodes::from(err.clone()), desc);
+                error.details.error_uri = err.error_uri().cloned();
+                error
             }
             RequestTokenError::Request(err) => {
                 OAuth2Error::new(ErrorCodes::RequestError, err.to_string())
@@ -228,7 +373,9 @@ pub type OAuth2Result<T> = Result<T, OAuth2Error>;
 
 #[cfg(test)]
 mod tests {
-    use super::ErrorCodes;
+    use serde_json::{Value, json};
+
+    use super::{ErrorCategory, ErrorCodes, OAuth2Error, ProviderDetails};
 
     #[test]
     fn test_error_codes_to_json_snake_case() {
@@ -497,4 +644,78 @@ mod tests {
             ErrorCodes::OtherError
         );
     }
+
+    #[test]
+    fn test_error_json_stays_compatible() {
+        // Errors from earlier versions have neither a category nor details.
+        let error: OAuth2Error = serde_json::from_value(json!({
+            "error_code": "invalid_grant",
+            "error_code_desc": "AADSTS70008",
+        }))
+        .unwrap();
+        assert_eq!(error.error_code, ErrorCodes::InvalidGrant);
+        assert_eq!(error.category, ErrorCategory::UserActionRequired);
+        assert!(!error.retryable);
+        assert_eq!(*error.details, ProviderDetails::default());
+
+        let error = OAuth2Error::new(ErrorCodes::ClaimsVerificationError, "Expired".into());
+        assert_eq!(error.category, ErrorCategory::TokenValidation);
+        assert!(!error.retryable);
+
+        let error = OAuth2Error::new(ErrorCodes::ReqwestError, "timed out".into());
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            json!({
+                "error_code": "reqwest_error",
+                "error_code_desc": "timed out",
+                "category": "transient",
+                "retryable": true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_error_with_provider_response() {
+        let response = |status: u16, body: Value| {
+            http::Response::builder()
+                .status(status)
+                .body(serde_json::to_vec(&body).unwrap())
+                .unwrap()
+        };
+        let error = OAuth2Error::new(ErrorCodes::InvalidGrant, "AADSTS50173".into()).with_response(
+            &response(
+                400,
+                json!({
+                    "error": "invalid_grant",
+                    "error_description": "AADSTS50173",
+                    "error_codes": [50173],
+                    "trace_id": "0b1c",
+                    "correlation_id": "9f3e",
+                    "error_uri": "https://login.microsoftonline.com/error?code=50173",
+                    "refresh_token": "secret",
+                }),
+            ),
+        );
+        assert_eq!(error.category, ErrorCategory::UserActionRequired);
+        assert!(!error.retryable);
+        assert_eq!(error.details.http_status, Some(400));
+        assert_eq!(error.details.trace_id.as_deref(), Some("0b1c"));
+        assert_eq!(error.details.correlation_id.as_deref(), Some("9f3e"));
+        assert_eq!(error.details.error_codes, Some(vec![50173]));
+        let provider_response = error.details.provider_response.as_ref().unwrap();
+        assert_ne!(provider_response["refresh_token"], "secret");
+
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["error_code"], "invalid_grant");
+        assert_eq!(json["trace_id"], "0b1c");
+        let parsed: OAuth2Error = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.details, error.details);
+
+        // Whatever the code, an overloaded provider can be tried again.
+        let error = OAuth2Error::new(ErrorCodes::OtherError, String::new()).with_response(
+            &response(503, json!({ "error": "temporarily_unavailable" })),
+        );
+        assert_eq!(error.category, ErrorCategory::Transient);
+        assert!(error.retryable);
+    }
 }
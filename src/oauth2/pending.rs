@@ -0,0 +1,198 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use oauth2::StandardDeviceAuthorizationResponse;
+use serde::{Deserialize, Serialize};
+
+use crate::oauth2::{error::OAuth2Result, polling::PollingSchedule, provider::InputParameters};
+
+/// Extension of the file a pending login is kept in, next to its token file.
+const EXTENSION: &str = "pending";
+
+/// A device login still waiting for the user, kept on disk so a restarted
+/// service can resume polling.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingLogin {
+    /// The `login` parameters, without the tokens and token secret of the
+    /// call. The client secret is kept, as polling needs it.
+    pub provider: InputParameters,
+    pub session_id: String,
+    pub device_authorization: StandardDeviceAuthorizationResponse,
+    pub nonce: Option<String>,
+    pub token_secret_hash: Option<String>,
+    pub polling: PollingSchedule,
+    /// Unix time in seconds at which polling stops.
+    pub expires_at: u64,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Creates `path` for the service's user only, as a pending login holds the
+/// client secret and the device code.
+fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+fn pending_file(directory: &Path, token_file: &Path) -> PathBuf {
+    let mut name = token_file.as_os_str().to_owned();
+    name.push(".");
+    name.push(EXTENSION);
+    directory.join(name)
+}
+
+impl PendingLogin {
+    pub fn new(
+        provider: &InputParameters,
+        session_id: String,
+        device_authorization: StandardDeviceAuthorizationResponse,
+        nonce: Option<String>,
+        token_secret_hash: Option<String>,
+        polling: PollingSchedule,
+    ) -> Self {
+        let mut provider = provider.clone();
+        provider.id_token = None;
+        provider.access_token = None;
+        provider.token_secret = None;
+        Self {
+            provider,
+            session_id,
+            device_authorization,
+            nonce,
+            token_secret_hash,
+            expires_at: unix_now() + polling.max_duration,
+            polling,
+        }
+    }
+
+    /// Time left before polling stops.
+    pub fn remaining(&self) -> Duration {
+        Duration::from_secs(self.expires_at.saturating_sub(unix_now()))
+    }
+
+    /// Writes the login next to `token_file`. The file is written aside and
+    /// renamed, so a crash leaves either the old login or the new one.
+    pub fn save(&self, directory: &Path, token_file: &Path) -> OAuth2Result<()> {
+        fs::create_dir_all(directory)?;
+        let path = pending_file(directory, token_file);
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        // Left over by a crash, maybe with other permissions.
+        let _ = fs::remove_file(&temporary);
+
+        let written = create_private(&temporary).and_then(|mut file| {
+            file.write_all(serde_json::to_string(self)?.as_bytes())?;
+            file.sync_all()
+        });
+        if let Err(err) = written.and_then(|()| fs::rename(&temporary, &path)) {
+            let _ = fs::remove_file(&temporary);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Deletes the pending login of `token_file`, if it is still the one of
+    /// `session_id` and not already a newer login's.
+    pub fn remove(directory: &Path, token_file: &Path, session_id: &str) {
+        let path = pending_file(directory, token_file);
+        let Ok(text) = fs::read_to_string(&path) else {
+            return;
+        };
+        match serde_json::from_str::<PendingLogin>(&text) {
+            Ok(pending) if pending.session_id != session_id => {}
+            _ => {
+                if let Err(err) = fs::remove_file(&path)
+                    && err.kind() != ErrorKind::NotFound
+                {
+                    log::error!("{err}");
+                }
+            }
+        }
+    }
+
+    /// Every pending login in `directory`, with the token file it is for.
+    pub fn load_all(directory: &Path) -> Vec<(PathBuf, PendingLogin)> {
+        let Ok(entries) = fs::read_dir(directory) else {
+            return Vec::new();
+        };
+        entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == EXTENSION))
+            .filter_map(|path| {
+                let token_file = PathBuf::from(path.file_stem()?);
+                let text = fs::read_to_string(&path)
+                    .inspect_err(|err| log::error!("{}: {err}", path.display()))
+                    .ok()?;
+                match serde_json::from_str(&text) {
+                    Ok(pending) => Some((token_file, pending)),
+                    Err(err) => {
+                        log::error!("Dropping unreadable {}: {err}", path.display());
+                        let _ = fs::remove_file(&path);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::oauth2::polling::PollingPolicy;
+
+    use super::*;
+
+    #[test]
+    fn test_save_is_private_and_atomic() {
+        let directory = tempfile::tempdir().unwrap();
+        let token_file = Path::new("token-file");
+        let response: StandardDeviceAuthorizationResponse = serde_json::from_str(
+            r#"{"user_code":"U","device_code":"D","verification_uri":"https://v","expires_in":300}"#,
+        )
+        .unwrap();
+        let login = PendingLogin::new(
+            &InputParameters::default(),
+            "session-1".into(),
+            response.clone(),
+            None,
+            None,
+            PollingPolicy::default().schedule(&response),
+        );
+
+        let path = pending_file(directory.path(), token_file);
+        fs::write(&path, "left by an older version").unwrap();
+        login.save(directory.path(), token_file).unwrap();
+        login.save(directory.path(), token_file).unwrap();
+
+        let names: Vec<_> = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, [path.file_name().unwrap()]);
+        let loaded = PendingLogin::load_all(directory.path());
+        assert_eq!(loaded[0].1.session_id, "session-1");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
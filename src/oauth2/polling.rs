@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use oauth2::StandardDeviceAuthorizationResponse;
+use serde::{Deserialize, Serialize};
+
+/// How the token endpoint is polled during a device login.
+///
+/// Every field is optional in the request. The defaults keep the provider's
+/// interval, raised to one second, and add five seconds per `slow_down` as
+/// RFC 8628 section 3.5 asks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PollingPolicy {
+    /// Seconds after which polling gives up, at most the device code lifetime.
+    pub max_duration: Option<u64>,
+    /// Shortest interval in seconds, whatever the provider returns.
+    pub min_interval: u64,
+    /// Seconds added to the interval on every `slow_down`.
+    pub slow_down_increment: u64,
+    /// Random share of the interval, from 0 to 1, added to every wait.
+    pub jitter: f64,
+}
+
+impl Default for PollingPolicy {
+    fn default() -> Self {
+        Self {
+            max_duration: None,
+            min_interval: 1,
+            slow_down_increment: 5,
+            jitter: 0.0,
+        }
+    }
+}
+
+impl PollingPolicy {
+    /// The schedule a login starts with, given the provider's response.
+    pub fn schedule(&self, response: &StandardDeviceAuthorizationResponse) -> PollingSchedule {
+        let expires_in = response.expires_in().as_secs();
+        PollingSchedule {
+            interval: response.interval().as_secs().max(self.min_interval),
+            max_duration: self
+                .max_duration
+                .map_or(expires_in, |max| max.min(expires_in)),
+            slow_down_increment: self.slow_down_increment,
+            jitter: self.jitter.clamp(0.0, 1.0),
+            slow_downs: 0,
+        }
+    }
+}
+
+/// The effective polling of a login, as reported by `loginStatus` and the
+/// `login.pending` and `login.slow_down` events.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PollingSchedule {
+    /// Seconds between two polls, before jitter.
+    pub interval: u64,
+    /// Seconds polling lasts at most.
+    pub max_duration: u64,
+    pub slow_down_increment: u64,
+    pub jitter: f64,
+    /// `slow_down` responses received so far.
+    pub slow_downs: u32,
+}
+
+impl PollingSchedule {
+    pub fn slow_down(&mut self) {
+        self.slow_downs += 1;
+        self.interval += self.slow_down_increment;
+    }
+
+    /// How long to wait before the next poll.
+    pub fn next_wait(&self) -> Duration {
+        let interval = Duration::from_secs(self.interval);
+        interval + interval.mul_f64(self.jitter * rand::random::<f64>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(interval: u64, expires_in: u64) -> StandardDeviceAuthorizationResponse {
+        serde_json::from_value(serde_json::json!({
+            "device_code": "devicecode-123",
+            "user_code": "ABCD-EFGH",
+            "verification_uri": "https://example.com/device",
+            "expires_in": expires_in,
+            "interval": interval,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_schedule_applies_policy() {
+        let schedule = PollingPolicy::default().schedule(&response(0, 900));
+        assert_eq!(schedule.interval, 1);
+        assert_eq!(schedule.max_duration, 900);
+
+        let policy = PollingPolicy {
+            max_duration: Some(120),
+            min_interval: 10,
+            ..Default::default()
+        };
+        let schedule = policy.schedule(&response(3, 900));
+        assert_eq!(schedule.interval, 10);
+        assert_eq!(schedule.max_duration, 120);
+        // Never past the device code lifetime.
+        assert_eq!(policy.schedule(&response(3, 60)).max_duration, 60);
+    }
+
+    #[test]
+    fn test_slow_down_and_jitter() {
+        let policy = PollingPolicy {
+            slow_down_increment: 3,
+            jitter: 0.5,
+            ..Default::default()
+        };
+        let mut schedule = policy.schedule(&response(4, 900));
+        schedule.slow_down();
+        schedule.slow_down();
+        assert_eq!(schedule.interval, 10);
+        assert_eq!(schedule.slow_downs, 2);
+        for _ in 0..20 {
+            let wait = schedule.next_wait();
+            assert!(wait >= Duration::from_secs(10) && wait <= Duration::from_secs(15));
+        }
+    }
+}
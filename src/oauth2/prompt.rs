@@ -0,0 +1,161 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use oauth2::StandardDeviceAuthorizationResponse;
+use qrcode::{
+    Color, QrCode,
+    render::{svg, unicode::Dense1x2},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::oauth2::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+/// Pixels per module of the PNG rendering.
+const PNG_SCALE: usize = 8;
+/// Light modules around the code, as the QR specification requires.
+const QUIET_ZONE: usize = 4;
+
+/// What a UI shows the user to complete a device login.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoginPrompt {
+    /// Preformatted instructions, e.g. for a status line.
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qr_code: Option<LoginQrCode>,
+}
+
+/// A QR code of the verification URI in the forms displays commonly take.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoginQrCode {
+    /// The URI encoded, `verification_uri_complete` when the provider has one.
+    pub uri: String,
+    pub svg: String,
+    /// Grayscale PNG, base64 encoded.
+    pub png: String,
+    /// Block characters for a terminal, light on dark.
+    pub text: String,
+}
+
+impl LoginPrompt {
+    pub fn new(response: &StandardDeviceAuthorizationResponse) -> Self {
+        let user_code = response.user_code().secret();
+        let (uri, message) = match response.verification_uri_complete() {
+            Some(complete) => (
+                complete.secret().to_owned(),
+                format!(
+                    "Scan the QR code or open {} to sign in. If asked, enter the code {user_code}.",
+                    complete.secret()
+                ),
+            ),
+            None => (
+                response.verification_uri().as_str().to_owned(),
+                format!(
+                    "Scan the QR code or open {} and enter the code {user_code} to sign in.",
+                    response.verification_uri().as_str()
+                ),
+            ),
+        };
+        // The login has started already; a display without the code is
+        // still usable, so a failed rendering only drops it.
+        let qr_code = LoginQrCode::new(uri)
+            .inspect_err(|err| log::error!("{err}"))
+            .ok();
+        Self { message, qr_code }
+    }
+}
+
+impl LoginQrCode {
+    pub fn new(uri: String) -> OAuth2Result<Self> {
+        let code = QrCode::new(uri.as_bytes())
+            .map_err(|e| OAuth2Error::new(ErrorCodes::OtherError, format!("QR code: {e}")))?;
+        Ok(Self {
+            svg: code
+                .render::<svg::Color>()
+                .quiet_zone(true)
+                .min_dimensions(200, 200)
+                .build(),
+            png: STANDARD.encode(render_png(&code)?),
+            // Inverted so the code scans on the usual dark terminal background.
+            text: code
+                .render::<Dense1x2>()
+                .dark_color(Dense1x2::Light)
+                .light_color(Dense1x2::Dark)
+                .quiet_zone(true)
+                .build(),
+            uri,
+        })
+    }
+}
+
+fn render_png(code: &QrCode) -> OAuth2Result<Vec<u8>> {
+    let modules = code.width();
+    let colors = code.to_colors();
+    let size = (modules + 2 * QUIET_ZONE) * PNG_SCALE;
+    let mut pixels = vec![u8::MAX; size * size];
+    for (index, color) in colors.iter().enumerate() {
+        if *color != Color::Dark {
+            continue;
+        }
+        let x = (index % modules + QUIET_ZONE) * PNG_SCALE;
+        let y = (index / modules + QUIET_ZONE) * PNG_SCALE;
+        for row in y..y + PNG_SCALE {
+            pixels[row * size + x..row * size + x + PNG_SCALE].fill(0);
+        }
+    }
+
+    let png_error = |e: png::EncodingError| {
+        OAuth2Error::new(ErrorCodes::OtherError, format!("QR code PNG: {e}"))
+    };
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(&pixels).map_err(png_error)?;
+    writer.finish().map_err(png_error)?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(complete: bool) -> StandardDeviceAuthorizationResponse {
+        let mut value = serde_json::json!({
+            "device_code": "devicecode-123",
+            "user_code": "ABCD-EFGH",
+            "verification_uri": "https://example.com/device",
+            "expires_in": 900,
+        });
+        if complete {
+            value["verification_uri_complete"] =
+                "https://example.com/device?user_code=ABCD-EFGH".into();
+        }
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_prompt_encodes_complete_uri() {
+        let prompt = LoginPrompt::new(&response(true));
+        assert!(prompt.message.contains("ABCD-EFGH"));
+
+        let qr_code = prompt.qr_code.unwrap();
+        assert_eq!(
+            qr_code.uri,
+            "https://example.com/device?user_code=ABCD-EFGH"
+        );
+        assert!(qr_code.svg.contains("<svg"));
+        assert!(qr_code.text.contains('█') || qr_code.text.contains('▀'));
+        let png = STANDARD.decode(qr_code.png).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    }
+
+    #[test]
+    fn test_prompt_falls_back_to_verification_uri() {
+        let prompt = LoginPrompt::new(&response(false));
+        assert!(
+            prompt
+                .message
+                .contains("https://example.com/device and enter the code ABCD-EFGH")
+        );
+        assert_eq!(prompt.qr_code.unwrap().uri, "https://example.com/device");
+    }
+}
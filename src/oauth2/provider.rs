@@ -1,7 +1,13 @@
-use oauth2::{AuthUrl, ClientId, ClientSecret, DeviceAuthorizationUrl, Scope, TokenUrl, url::Url};
+use oauth2::{
+    AccessToken, AuthUrl, ClientId, ClientSecret, DeviceAuthorizationUrl, Scope, TokenUrl, url::Url,
+};
 use openidconnect::core::CoreIdToken;
 use serde::{Deserialize, Serialize};
 
+use crate::logger::control::ControlSecret;
+use crate::oauth2::{polling::PollingPolicy, session::TokenSecret};
+use crate::openid::{access_token::AccessTokenPolicy, pinned::PinnedKeys, policy::IdTokenPolicy};
+
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Default, Clone)]
 pub struct SmtpHostName(pub String);
 
@@ -11,7 +17,7 @@ pub struct SmtpPort(pub u16);
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ProfileUrl(pub Url);
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct InputParameters {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub process: Option<String>,
@@ -31,4 +37,51 @@ pub struct InputParameters {
     pub client_secret: Option<ClientSecret>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub id_token: Option<CoreIdToken>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token_policy: Option<IdTokenPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned_keys: Option<PinnedKeys>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_token: Option<AccessToken>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_token_policy: Option<AccessTokenPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_identity: Option<bool>,
+    /// Add a QR code of the verification URI and a message for the user to
+    /// the `login` response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_qr_code: Option<bool>,
+    /// Publish tokens in `token.ready` as before, instead of requiring
+    /// `requestToken` with the login's `token_secret`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens_in_events: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_secret: Option<TokenSecret>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub polling_policy: Option<PollingPolicy>,
+    /// Seconds `awaitLogin` waits at most.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// W3C trace context of the caller, which the call's spans join.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+    /// Send the trace context on to the provider in a `traceparent` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub propagate_trace: Option<bool>,
+    /// Level directives `setLogLevel` applies, e.g. `debug,ipc_broker=warn`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_levels: Option<String>,
+    /// Session whose login `setLogLevel` traces for `trace_seconds`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_session: Option<String>,
+    /// Process whose calls and logins `setLogLevel` traces for `trace_seconds`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_process: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_seconds: Option<u64>,
+    /// Secret allowing `setLogLevel`, see `AUTH_SERVICE_LOG_CONTROL_SECRET`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_secret: Option<ControlSecret>,
 }
@@ -0,0 +1,223 @@
+use std::fmt::{self, Debug, Formatter};
+
+use oauth2::{CsrfToken, StandardDeviceAuthorizationResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::oauth2::{
+    error::{ErrorCodes, OAuth2Error},
+    polling::PollingSchedule,
+    provider::InputParameters,
+};
+
+/// Object the service is called on, and publishes its events that belong to
+/// no login on.
+pub const EVENT_OBJECT: &str = "oauth2.device.code.flow";
+
+/// Environment variable that, set to `1` or `true`, also publishes the
+/// events of every login on [`EVENT_OBJECT`], for clients that predate
+/// [`LoginSession::object`].
+pub const SHARED_EVENTS_ENV: &str = "AUTH_SERVICE_SHARED_EVENTS";
+
+/// Whether `AUTH_SERVICE_SHARED_EVENTS` asks for session events on the
+/// shared object too.
+pub fn shared_events_from_env() -> bool {
+    std::env::var(SHARED_EVENTS_ENV)
+        .is_ok_and(|value| matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true"))
+}
+
+/// One device login, from `login` until the poller finishes.
+///
+/// Its fields are added to every event the login produces, and the events are
+/// published on [`LoginSession::object`], so a subscriber only sees its own
+/// session.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LoginSession {
+    pub session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// Whether events may carry tokens, see [`InputParameters::tokens_in_events`].
+    #[serde(skip)]
+    pub tokens_in_events: bool,
+}
+
+impl LoginSession {
+    pub fn new(param: &InputParameters) -> Self {
+        Self::resume(param, CsrfToken::new_random().secret().to_owned())
+    }
+
+    /// The session `session_id` of a login started with `param`.
+    pub fn resume(param: &InputParameters, session_id: String) -> Self {
+        Self {
+            session_id,
+            process: param.process.clone(),
+            provider: param.provider.clone(),
+            tokens_in_events: param.tokens_in_events.unwrap_or_default(),
+        }
+    }
+
+    /// Object the events of this session only are published on.
+    pub fn object(&self) -> String {
+        format!("{EVENT_OBJECT}.session.{}", self.session_id)
+    }
+
+    /// Adds the session fields to an event payload. Payloads that aren't
+    /// objects are kept under `result`.
+    pub fn tag(&self, value: Value) -> Value {
+        let mut object = match value {
+            Value::Object(object) => object,
+            Value::Null => Map::new(),
+            other => Map::from_iter([("result".to_string(), other)]),
+        };
+        if let Value::Object(session) = serde_json::to_value(self).unwrap_or_default() {
+            object.extend(session);
+        }
+        Value::Object(object)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginState {
+    Pending,
+    Completed,
+    Failed,
+    Expired,
+    Denied,
+    Cancelled,
+    /// Replaced by a newer login of the same process and provider.
+    Superseded,
+}
+
+impl LoginState {
+    /// The state a finished poller leaves its login in.
+    pub fn from_result(result: &Result<(), OAuth2Error>) -> Self {
+        match result {
+            Ok(()) => LoginState::Completed,
+            Err(error) => match error.error_code {
+                ErrorCodes::ExpiredToken => LoginState::Expired,
+                ErrorCodes::AccessDenied | ErrorCodes::AuthorizationDeclined => LoginState::Denied,
+                _ => LoginState::Failed,
+            },
+        }
+    }
+
+    /// The typed event announcing this state.
+    pub fn event(&self) -> &'static str {
+        match self {
+            LoginState::Pending => "login.pending",
+            LoginState::Completed => "login.completed",
+            LoginState::Failed => "login.failed",
+            LoginState::Expired => "login.expired",
+            LoginState::Denied => "login.denied",
+            LoginState::Cancelled => "login.cancelled",
+            LoginState::Superseded => "login.superseded",
+        }
+    }
+}
+
+/// What `loginStatus` and `awaitLogin` report about a login.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoginStatus {
+    #[serde(flatten)]
+    pub session: LoginSession,
+    pub state: LoginState,
+    /// Seconds until polling stops, while pending.
+    pub remaining_seconds: u64,
+    pub polling: PollingSchedule,
+    /// The device authorization response without the device code, enough
+    /// for another UI to show the user code again.
+    pub device_authorization: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<OAuth2Error>,
+}
+
+impl LoginStatus {
+    pub fn new(
+        session: LoginSession,
+        response: &StandardDeviceAuthorizationResponse,
+        polling: PollingSchedule,
+    ) -> Self {
+        let mut device_authorization = serde_json::to_value(response).unwrap_or_default();
+        if let Value::Object(object) = &mut device_authorization {
+            object.remove("device_code");
+        }
+        Self {
+            session,
+            state: LoginState::Pending,
+            remaining_seconds: polling.max_duration,
+            polling,
+            device_authorization,
+            error: None,
+        }
+    }
+}
+
+/// Secret handed to the process that started a login; the token it produces
+/// is only given out to callers presenting it.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct TokenSecret(String);
+
+impl TokenSecret {
+    pub fn new_random() -> Self {
+        Self(CsrfToken::new_random_len(32).secret().to_owned())
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.0
+    }
+
+    /// The form stored next to the token.
+    pub fn hash(&self) -> String {
+        Sha256::digest(self.0.as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl Debug for TokenSecret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("TokenSecret([redacted])")
+    }
+}
+
+/// Typed event for a token endpoint error that keeps the poller going.
+pub fn polling_event(error: &str) -> Option<&'static str> {
+    match error {
+        "authorization_pending" => Some("login.pending"),
+        "slow_down" => Some("login.slow_down"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_tag_merges_session_fields() {
+        let session = LoginSession {
+            session_id: "abc".into(),
+            process: Some("Process".into()),
+            provider: None,
+            tokens_in_events: false,
+        };
+
+        assert_eq!(
+            session.tag(json!({"error": "slow_down"})),
+            json!({"error": "slow_down", "session_id": "abc", "process": "Process"})
+        );
+        assert_eq!(
+            session.tag(json!(true)),
+            json!({"result": true, "session_id": "abc", "process": "Process"})
+        );
+        assert_eq!(session.object(), "oauth2.device.code.flow.session.abc");
+    }
+}
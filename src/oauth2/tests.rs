@@ -1 +1,28 @@
+mod auth_server;
+mod lifecycle;
 mod login;
+
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::interface::Interface;
+use crate::task_manager::{TaskManager, TaskMessage};
+
+/// Runs a test `body` while a task manager serves `rx`. A panic in the body
+/// fails the test right away instead of leaving the manager to idle out.
+pub async fn run_with_task_manager<I, F, T>(
+    interface: I,
+    rx: UnboundedReceiver<TaskMessage>,
+    body: F,
+) -> T
+where
+    I: Interface + Send + Sync + 'static,
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut body = tokio::spawn(body);
+    let mut task_manager = TaskManager::new(rx);
+    tokio::select! {
+        _ = task_manager.run(interface) => body.await.unwrap(),
+        result = &mut body => result.unwrap(),
+    }
+}
@@ -0,0 +1,686 @@
+use std::time::Duration;
+
+use tokio::sync::mpsc::unbounded_channel;
+
+use crate::http_client::{HttpClient, curl::Curl, reqwest::Reqwest};
+use crate::interface::{Interface, mock::Mock};
+use crate::oauth2::device_code_flow::{
+    await_login, cancel, login, login_status, request_token, resume_logins, stored_token,
+};
+use crate::oauth2::error::ErrorCodes;
+use crate::oauth2::pending::PendingLogin;
+use crate::oauth2::session::LoginState;
+use crate::oauth2::tests::run_with_task_manager;
+use crate::openid::{ApplicationNonce, cache::ProviderCache, verify_id_token};
+use crate::task_manager::TaskMessage;
+use crate::test_support::auth_server::{AuthServer, Decision};
+
+async fn device_flow_against_server(http_client: HttpClient) {
+    let server = AuthServer::start().await;
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new().with_http_client(http_client);
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let response = login(
+            server.provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+        assert!(
+            response
+                .device_authorization
+                .verification_uri_complete()
+                .is_some()
+        );
+        server.approve(response.device_authorization.user_code().secret());
+
+        let ready = inner
+            .wait_for_event("token.ready", Duration::from_secs(10))
+            .await
+            .expect("token.ready was not published");
+        assert!(ready.result["access_token"].is_null());
+        assert!(ready.result["refresh_token"].is_null());
+        assert!(ready.result["id_token"].is_null());
+
+        // Only the process holding the login's secret gets the token.
+        let result = request_token(
+            server.provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await;
+        assert_eq!(result.unwrap_err().error_code, ErrorCodes::Forbidden);
+
+        let mut provider = server.provider();
+        provider.token_secret = response.token_secret.clone();
+        let token = request_token(provider, inner.clone(), tx.clone(), &ProviderCache::new())
+            .await
+            .unwrap();
+        assert!(token.token_secret_hash.is_none());
+        assert!(token.nonce.is_none());
+        let mut provider = server.provider();
+        provider.id_token = token.id_token.clone();
+
+        let claims = verify_id_token(
+            provider,
+            ApplicationNonce::new(),
+            None,
+            &ProviderCache::new(),
+            inner.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(claims.subject().as_str(), "user-1");
+        assert_eq!(server.hits("/.well-known/openid-configuration"), 1);
+        assert_eq!(server.hits("/jwks"), 1);
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_device_flow_with_curl() {
+    device_flow_against_server(HttpClient::Curl(Curl::default())).await;
+}
+
+#[tokio::test]
+async fn test_device_flow_with_reqwest() {
+    device_flow_against_server(HttpClient::Reqwest(Reqwest::default())).await;
+}
+
+#[tokio::test]
+async fn test_device_flow_denied() {
+    let server = AuthServer::start().await;
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()));
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let response = login(
+            server.provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+        server.deny(response.device_authorization.user_code().secret());
+
+        let ready = inner
+            .wait_for_event("token.ready", Duration::from_secs(10))
+            .await
+            .expect("token.ready was not published");
+        assert_eq!(ready.result["error_code"], "access_denied");
+        let denied = inner
+            .wait_for_event("login.denied", Duration::from_secs(1))
+            .await
+            .expect("login.denied was not published");
+        assert_eq!(denied.result["session_id"], response.session_id.as_str());
+
+        let result = request_token(
+            server.provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await;
+        assert_eq!(result.unwrap_err().error_code, ErrorCodes::IoError);
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_refresh_rotates_tokens() {
+    let server = AuthServer::start().await;
+    server.set_auto_decision(Some(Decision::Approve));
+    server.set_token_lifetime(0);
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()));
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let mut provider = server.provider();
+        provider.tokens_in_events = Some(true);
+        let response = login(provider, inner.clone(), tx.clone(), &ProviderCache::new())
+            .await
+            .unwrap();
+        assert!(response.token_secret.is_none());
+        let ready = inner
+            .wait_for_event("token.ready", Duration::from_secs(10))
+            .await
+            .expect("token.ready was not published");
+
+        let first = request_token(
+            server.provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+        let second = request_token(
+            server.provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+
+        assert_ne!(
+            ready.result["access_token"],
+            first.access_token.secret().as_str()
+        );
+        assert_ne!(first.access_token.secret(), second.access_token.secret());
+        assert_eq!(server.hits("/token"), 3);
+
+        // The ID token from the login is not checked against the access token
+        // a refresh replaced it with, which its `at_hash` does not match.
+        let mut provider = server.provider();
+        provider.id_token = serde_json::from_value(ready.result["id_token"].clone()).unwrap();
+        assert!(second.access_token_of(provider.id_token.as_ref()).is_none());
+        assert_eq!(
+            second
+                .access_token_of(second.id_token.as_ref())
+                .map(|token| token.secret()),
+            Some(second.access_token.secret())
+        );
+        let cache = ProviderCache::new();
+        verify_id_token(
+            provider.clone(),
+            ApplicationNonce::new(),
+            second.access_token_of(provider.id_token.as_ref()),
+            &cache,
+            inner.clone(),
+        )
+        .await
+        .unwrap();
+        let result = verify_id_token(
+            provider,
+            ApplicationNonce::new(),
+            Some(&second.access_token),
+            &cache,
+            inner.clone(),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().error_code,
+            ErrorCodes::ClaimsVerificationError
+        );
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_generated_nonce_is_remembered() {
+    let server = AuthServer::start().await;
+    server.set_auto_decision(Some(Decision::Approve));
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()));
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let response = login(
+            server.provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+        inner
+            .wait_for_event("token.ready", Duration::from_secs(10))
+            .await
+            .expect("token.ready was not published");
+
+        let nonce = stored_token(&server.provider(), &inner)
+            .and_then(|token| token.nonce)
+            .expect("nonce was not stored");
+        let mut provider = server.provider();
+        provider.token_secret = response.token_secret;
+        let token = request_token(provider, inner.clone(), tx.clone(), &ProviderCache::new())
+            .await
+            .unwrap();
+        let mut provider = server.provider();
+        provider.id_token = token.id_token.clone();
+        let cache = ProviderCache::new();
+
+        let claims = verify_id_token(
+            provider.clone(),
+            ApplicationNonce::from(nonce.clone()),
+            None,
+            &cache,
+            inner.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(claims.nonce().unwrap().secret(), &nonce);
+
+        let result = verify_id_token(
+            provider,
+            ApplicationNonce::from("another-nonce".into()),
+            None,
+            &cache,
+            inner.clone(),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().error_code,
+            ErrorCodes::ClaimsVerificationError
+        );
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_nonce_ignored_by_provider() {
+    let server = AuthServer::start().await;
+    server.set_auto_decision(Some(Decision::Approve));
+    server.set_echo_nonce(false);
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()));
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let mut provider = server.provider();
+        provider.include_identity = Some(true);
+        login(provider, inner.clone(), tx.clone(), &ProviderCache::new())
+            .await
+            .unwrap();
+        let ready = inner
+            .wait_for_event("token.ready", Duration::from_secs(10))
+            .await
+            .expect("token.ready was not published");
+        // The generated nonce is not required of ID tokens that lack it.
+        assert_eq!(ready.result["identity"]["verified"], true);
+        let token = stored_token(&server.provider(), &inner).unwrap();
+        assert!(token.id_token.is_some());
+        assert!(token.nonce.is_none());
+
+        // A nonce the caller chose still is.
+        let mut provider = server.provider();
+        provider.nonce = Some("caller-nonce".into());
+        login(provider, inner.clone(), tx.clone(), &ProviderCache::new())
+            .await
+            .unwrap();
+        let status = await_login(server.provider(), tx.clone()).await.unwrap();
+        assert_eq!(status.state, LoginState::Completed);
+        let token = stored_token(&server.provider(), &inner).unwrap();
+        assert_eq!(token.nonce.as_deref(), Some("caller-nonce"));
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_verify_id_token_without_expected_nonce() {
+    let server = AuthServer::start().await;
+    let interface = Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()));
+
+    let mut provider = server.provider();
+    provider.id_token = Some(serde_json::from_value(server.id_token(None, None).into()).unwrap());
+
+    let result = verify_id_token(
+        provider,
+        ApplicationNonce::from("expected-nonce".into()),
+        None,
+        &ProviderCache::new(),
+        interface,
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().error_code,
+        ErrorCodes::ClaimsVerificationError
+    );
+}
+
+#[tokio::test]
+async fn test_verify_id_token_signed_by_unknown_key() {
+    let server = AuthServer::start().await;
+    let forged = server.id_token(None, None);
+    server.rotate_keys();
+    let interface = Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()));
+
+    let mut provider = server.provider();
+    provider.id_token = Some(serde_json::from_value(forged.into()).unwrap());
+
+    let result = verify_id_token(
+        provider,
+        ApplicationNonce::new(),
+        None,
+        &ProviderCache::new(),
+        interface,
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().error_code,
+        ErrorCodes::ClaimsVerificationError
+    );
+}
+
+#[tokio::test]
+async fn test_identity_included_on_request() {
+    let server = AuthServer::start().await;
+    server.set_auto_decision(Some(Decision::Approve));
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()));
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let mut provider = server.provider();
+        provider.include_identity = Some(true);
+        let cache = ProviderCache::new();
+
+        let response = login(provider.clone(), inner.clone(), tx.clone(), &cache)
+            .await
+            .unwrap();
+        provider.token_secret = response.token_secret;
+        let ready = inner
+            .wait_for_event("token.ready", Duration::from_secs(10))
+            .await
+            .expect("token.ready was not published");
+        let identity = &ready.result["identity"];
+        assert_eq!(identity["subject"], "user-1");
+        assert_eq!(identity["email"], "mock.user@example.com");
+        assert_eq!(identity["preferred_username"], "mock.user");
+        assert_eq!(identity["tenant"], "tenant-1");
+        assert_eq!(identity["verified"], true);
+
+        let token = request_token(provider.clone(), inner.clone(), tx.clone(), &cache)
+            .await
+            .unwrap();
+        let identity = token.identity.unwrap();
+        assert_eq!(identity.name.as_deref(), Some("Mock User"));
+        assert!(identity.verified);
+        // Verified once when the token arrived, then served from the token file.
+        assert_eq!(server.hits("/jwks"), 1);
+
+        provider.include_identity = None;
+        let token = request_token(provider, inner.clone(), tx.clone(), &cache)
+            .await
+            .unwrap();
+        assert!(token.identity.is_none());
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_cancelled_login_is_published() {
+    let server = AuthServer::start().await;
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()));
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let response = login(
+            server.provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+        inner
+            .wait_for_event("login.pending", Duration::from_secs(10))
+            .await
+            .expect("login.pending was not published");
+
+        cancel(server.provider(), tx.clone()).await.unwrap();
+        let cancelled = inner
+            .wait_for_event("login.cancelled", Duration::from_secs(10))
+            .await
+            .expect("login.cancelled was not published");
+        assert_eq!(cancelled.result["session_id"], response.session_id.as_str());
+        let ready = inner
+            .wait_for_event("token.ready", Duration::from_secs(1))
+            .await
+            .expect("token.ready was not published");
+        assert_eq!(ready.result["error_code"], "cancelled");
+        assert!(PendingLogin::load_all(&inner.token_directory()).is_empty());
+        let status = login_status(server.provider(), tx.clone()).await.unwrap();
+        assert_eq!(status.state, LoginState::Cancelled);
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_login_includes_qr_code_on_request() {
+    let server = AuthServer::start().await;
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()));
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let response = login(
+            server.provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+        assert!(response.prompt.is_none());
+
+        let mut provider = server.provider();
+        provider.include_qr_code = Some(true);
+        let response = login(provider, inner.clone(), tx.clone(), &ProviderCache::new())
+            .await
+            .unwrap();
+        let complete = response
+            .device_authorization
+            .verification_uri_complete()
+            .unwrap()
+            .secret()
+            .clone();
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value["qr_code"]["uri"], complete.as_str());
+        assert!(value["message"].as_str().unwrap().contains(&complete));
+        assert!(value["user_code"].is_string());
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_superseded_login_is_published() {
+    let server = AuthServer::start().await;
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()));
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let first = login(
+            server.provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+        let second = login(
+            server.provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+
+        let superseded = inner
+            .wait_for_event("login.superseded", Duration::from_secs(10))
+            .await
+            .expect("login.superseded was not published");
+        assert_eq!(superseded.result["session_id"], first.session_id.as_str());
+        let ready = inner
+            .wait_for_event("token.ready", Duration::from_secs(1))
+            .await
+            .expect("token.ready was not published");
+        assert_eq!(ready.result["error_code"], "superseded");
+        assert_eq!(ready.result["session_id"], first.session_id.as_str());
+
+        // The new login carries on.
+        let status = login_status(server.provider(), tx.clone()).await.unwrap();
+        assert_eq!(status.state, LoginState::Pending);
+        assert_eq!(status.session.session_id, second.session_id);
+        server.approve(second.device_authorization.user_code().secret());
+        let status = await_login(server.provider(), tx.clone()).await.unwrap();
+        assert_eq!(status.state, LoginState::Completed);
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_pending_login_resumes_after_restart() {
+    let server = AuthServer::start().await;
+    let interface = Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()));
+    let inner = interface.clone();
+
+    let (tx, rx) = unbounded_channel();
+    let response = run_with_task_manager(interface.clone(), rx, {
+        let provider = server.provider();
+        let inner = inner.clone();
+        async move {
+            let response = login(provider, inner.clone(), tx.clone(), &ProviderCache::new())
+                .await
+                .unwrap();
+            inner
+                .wait_for_event("login.pending", Duration::from_secs(10))
+                .await
+                .expect("login.pending was not published");
+            tx.send(TaskMessage::Quit).unwrap();
+            response
+        }
+    })
+    .await;
+    let pending = PendingLogin::load_all(&inner.token_directory());
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].1.session_id, response.session_id);
+
+    // A new service instance picks the login up where the old one stopped.
+    let (tx, rx) = unbounded_channel();
+    run_with_task_manager(interface, rx, async move {
+        resume_logins(inner.clone(), tx.clone(), &ProviderCache::new());
+        let status = login_status(server.provider(), tx.clone()).await.unwrap();
+        assert_eq!(status.state, LoginState::Pending);
+        assert_eq!(status.session.session_id, response.session_id);
+
+        server.approve(response.device_authorization.user_code().secret());
+        let ready = inner
+            .wait_for_event("token.ready", Duration::from_secs(10))
+            .await
+            .expect("token.ready was not published");
+        assert_eq!(ready.result["session_id"], response.session_id.as_str());
+        let status = await_login(server.provider(), tx.clone()).await.unwrap();
+        assert_eq!(status.state, LoginState::Completed);
+        assert!(PendingLogin::load_all(&inner.token_directory()).is_empty());
+
+        let mut provider = server.provider();
+        provider.token_secret = response.token_secret.clone();
+        request_token(provider, inner.clone(), tx.clone(), &ProviderCache::new())
+            .await
+            .unwrap();
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_login_status_and_await_login() {
+    let server = AuthServer::start().await;
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()));
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let result = login_status(server.provider(), tx.clone()).await;
+        assert_eq!(
+            result.unwrap_err().error_code,
+            ErrorCodes::InvalidParameters
+        );
+
+        let response = login(
+            server.provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+
+        let status = login_status(server.provider(), tx.clone()).await.unwrap();
+        assert_eq!(status.state, LoginState::Pending);
+        assert_eq!(status.session.session_id, response.session_id);
+        assert!(status.remaining_seconds > 0 && status.remaining_seconds <= 30);
+        assert_eq!(
+            status.device_authorization["user_code"],
+            response.device_authorization.user_code().secret().as_str()
+        );
+        assert!(status.device_authorization["device_code"].is_null());
+
+        // Times out while the user hasn't approved yet.
+        let mut provider = server.provider();
+        provider.timeout = Some(1);
+        let status = await_login(provider, tx.clone()).await.unwrap();
+        assert_eq!(status.state, LoginState::Pending);
+
+        server.approve(response.device_authorization.user_code().secret());
+        let status = await_login(server.provider(), tx.clone()).await.unwrap();
+        assert_eq!(status.state, LoginState::Completed);
+        assert_eq!(status.remaining_seconds, 0);
+
+        // Finished logins answer right away.
+        let status = await_login(server.provider(), tx.clone()).await.unwrap();
+        assert_eq!(status.state, LoginState::Completed);
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_await_denied_login() {
+    let server = AuthServer::start().await;
+    server.set_auto_decision(Some(Decision::Deny));
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()));
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        login(
+            server.provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+
+        let status = await_login(server.provider(), tx.clone()).await.unwrap();
+        assert_eq!(status.state, LoginState::Denied);
+        assert_eq!(status.error.unwrap().error_code, ErrorCodes::AccessDenied);
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
@@ -0,0 +1,424 @@
+use std::time::Duration;
+
+use http::StatusCode;
+use oauth2::StandardDeviceAuthorizationResponse;
+use tokio::sync::mpsc::unbounded_channel;
+
+use crate::interface::Interface;
+use crate::interface::mock::{Mock, Route};
+use crate::metrics;
+use crate::oauth2::device_code_flow::{
+    await_login, login, login_status, make_filename, request_token,
+};
+use crate::oauth2::error::ErrorCodes;
+use crate::oauth2::pending::PendingLogin;
+use crate::oauth2::polling::PollingPolicy;
+use crate::oauth2::session::{EVENT_OBJECT, LoginSession, LoginState, LoginStatus};
+use crate::oauth2::tests::run_with_task_manager;
+use crate::openid::cache::ProviderCache;
+use crate::service::health::ProviderErrors;
+use crate::task_manager::{TaskManager, TaskMessage};
+
+use super::login::build_mock_provider;
+
+const DEVICE_CODE_RESPONSE: &str = r#"{"user_code":"usercode-123","device_code":"devicecode-123","verification_uri":"https://verification_url","expires_in":20,"interval":1}"#;
+const PENDING_RESPONSE: &str =
+    r#"{"error":"authorization_pending","error_description":"Waiting for the user."}"#;
+const TOKEN_RESPONSE: &str = r#"{"token_type":"Bearer","access_token":"access-1","refresh_token":"refresh-1","expires_in":0,"scope":"offline_access"}"#;
+const REFRESHED_RESPONSE: &str = r#"{"token_type":"Bearer","access_token":"access-2","refresh_token":"refresh-2","expires_in":3600,"scope":"offline_access"}"#;
+
+fn build_mock_interface() -> Mock {
+    Mock::new()
+        .route(Route::post("/devicecode").respond_json(StatusCode::OK, DEVICE_CODE_RESPONSE))
+        .route(
+            Route::post("/token")
+                .body_contains("device_code=devicecode-123")
+                .respond_json(StatusCode::BAD_REQUEST, PENDING_RESPONSE)
+                .respond_json(StatusCode::BAD_REQUEST, PENDING_RESPONSE)
+                .respond_json(StatusCode::OK, TOKEN_RESPONSE),
+        )
+        .route(
+            Route::post("/token")
+                .body_contains("grant_type=refresh_token")
+                .body_contains("refresh_token=refresh-1")
+                .respond_json(StatusCode::OK, REFRESHED_RESPONSE),
+        )
+}
+
+#[tokio::test]
+async fn test_login_poll_and_request_token() {
+    let (tx, rx) = unbounded_channel();
+    let interface = build_mock_interface();
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let result = login(
+            build_mock_provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            result.device_authorization.device_code().secret(),
+            "devicecode-123"
+        );
+
+        let ready = inner
+            .wait_for_event("token.ready", Duration::from_secs(10))
+            .await
+            .expect("token.ready was not published");
+        assert!(ready.result["access_token"].is_null());
+        assert_eq!(ready.result["session_id"], result.session_id.as_str());
+        assert_eq!(ready.result["process"], "Process Name");
+        assert_eq!(ready.result["provider"], "Microsoft");
+
+        // Every event is published on the session object only.
+        let session_object = format!("{EVENT_OBJECT}.session.{}", result.session_id);
+        assert_eq!(ready.object, session_object);
+        let events = inner.events();
+        let names_on = |object: &str| -> Vec<String> {
+            events
+                .iter()
+                .filter(|event| event.object == object)
+                .map(|event| event.event.clone())
+                .collect()
+        };
+        assert!(names_on(EVENT_OBJECT).is_empty());
+        assert_eq!(
+            names_on(&session_object),
+            [
+                "login.pending",
+                "token.polling",
+                "login.pending",
+                "token.polling",
+                "token.polling",
+                "login.completed",
+                "token.ready",
+            ]
+        );
+
+        let polled = inner.events_named("token.polling");
+        let redacted = polled.last().unwrap().result["access_token"]
+            .as_str()
+            .unwrap();
+        assert!(redacted.starts_with("<redacted:"));
+
+        // The stored token has already expired, so it is refreshed.
+        let mut provider = build_mock_provider();
+        provider.token_secret = result.token_secret.clone();
+        let token = request_token(
+            provider.clone(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(token.access_token.secret(), "access-2");
+
+        let token_requests = inner.requests_to("/token");
+        assert_eq!(token_requests.len(), 4);
+        let refresh = String::from_utf8_lossy(token_requests[3].body()).to_string();
+        assert!(refresh.contains("grant_type=refresh_token"));
+
+        // A valid token is served from disk without contacting the endpoint,
+        // still only to the process that logged in.
+        let token = request_token(provider, inner.clone(), tx.clone(), &ProviderCache::new())
+            .await
+            .unwrap();
+        assert_eq!(token.access_token.secret(), "access-2");
+        assert_eq!(inner.requests_to("/token").len(), 4);
+
+        // Other tests share the counters, so only their presence is checked.
+        let metrics = metrics::render();
+        for series in [
+            "auth_logins_started_total{provider=\"Microsoft\"}",
+            "auth_logins_completed_total{provider=\"Microsoft\"}",
+            "auth_token_polls_total{result=\"authorization_pending\"}",
+            "auth_token_polls_total{result=\"ok\"}",
+            "auth_token_refreshes_total{result=\"ok\"}",
+        ] {
+            assert!(metrics.contains(series), "{series} missing");
+        }
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_polling_policy_and_slow_down() {
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new()
+        .route(Route::post("/devicecode").respond_json(
+            StatusCode::OK,
+            r#"{"user_code":"usercode-123","device_code":"devicecode-123","verification_uri":"https://verification_url","expires_in":20,"interval":0}"#,
+        ))
+        .route(
+            Route::post("/token")
+                .respond_json(
+                    StatusCode::BAD_REQUEST,
+                    r#"{"error":"slow_down","error_description":"Too fast."}"#,
+                )
+                .respond_json(StatusCode::BAD_REQUEST, PENDING_RESPONSE)
+                .respond_json(StatusCode::OK, TOKEN_RESPONSE),
+        );
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let mut provider = build_mock_provider();
+        provider.polling_policy = Some(PollingPolicy {
+            max_duration: Some(15),
+            min_interval: 1,
+            slow_down_increment: 1,
+            jitter: 0.0,
+        });
+        login(
+            provider.clone(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await
+        .unwrap();
+
+        let slow_down = inner
+            .wait_for_event("login.slow_down", Duration::from_secs(10))
+            .await
+            .expect("login.slow_down was not published");
+        assert_eq!(slow_down.result["polling"]["interval"], 2);
+        assert_eq!(slow_down.result["polling"]["slow_downs"], 1);
+        assert_eq!(slow_down.result["polling"]["max_duration"], 15);
+
+        inner
+            .wait_for_event("login.pending", Duration::from_secs(10))
+            .await
+            .expect("login.pending was not published");
+        let status = login_status(provider.clone(), tx.clone()).await.unwrap();
+        assert_eq!(status.polling.interval, 2);
+        assert!(status.remaining_seconds <= 15);
+
+        let status = await_login(provider, tx.clone()).await.unwrap();
+        assert_eq!(status.state, LoginState::Completed);
+        // slow_down, authorization_pending, then the token.
+        let token_requests = inner.requests_to("/token");
+        assert_eq!(token_requests.len(), 3);
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_unmatched_route_is_an_error() {
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new().route(Route::get("/devicecode"));
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let result = login(
+            build_mock_provider(),
+            inner.clone(),
+            tx.clone(),
+            &ProviderCache::new(),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(inner.requests_to("/devicecode").len(), 1);
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_panicking_poller_is_reported() {
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new();
+    let inner = interface.clone();
+
+    run_with_task_manager(interface, rx, async move {
+        let provider = build_mock_provider();
+        let session = LoginSession::new(&provider);
+        let response: StandardDeviceAuthorizationResponse =
+            serde_json::from_str(DEVICE_CODE_RESPONSE).unwrap();
+        let handle = tokio::spawn(async { panic!("poller failed") });
+        tx.send(TaskMessage::Add(
+            make_filename(&provider).unwrap(),
+            LoginStatus::new(
+                session.clone(),
+                &response,
+                PollingPolicy::default().schedule(&response),
+            ),
+            handle,
+        ))
+        .unwrap();
+
+        let failed = inner
+            .wait_for_event("login.failed", Duration::from_secs(10))
+            .await
+            .expect("login.failed was not published");
+        assert_eq!(failed.result["error_code"], "internal_error");
+        assert_eq!(failed.result["session_id"], session.session_id.as_str());
+        let ready = inner
+            .wait_for_event("token.ready", Duration::from_secs(1))
+            .await
+            .expect("token.ready was not published");
+        assert_eq!(ready.result["error_code"], "internal_error");
+
+        let status = await_login(provider, tx.clone()).await.unwrap();
+        assert_eq!(status.state, LoginState::Failed);
+
+        tx.send(TaskMessage::Quit).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_failed_login_is_the_provider_last_error() {
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new()
+        .route(Route::post("/devicecode").respond_json(StatusCode::OK, DEVICE_CODE_RESPONSE))
+        .route(Route::post("/token").respond_json(
+            StatusCode::BAD_REQUEST,
+            r#"{"error":"access_denied","error_description":"The user declined."}"#,
+        ));
+    let inner = interface.clone();
+    let errors = ProviderErrors::default();
+    let mut task_manager = TaskManager::new(rx).with_provider_errors(errors.clone());
+    let manager = tokio::spawn(async move { task_manager.run(interface).await });
+
+    login(
+        build_mock_provider(),
+        inner.clone(),
+        tx.clone(),
+        &ProviderCache::new(),
+    )
+    .await
+    .unwrap();
+    let status = await_login(build_mock_provider(), tx.clone())
+        .await
+        .unwrap();
+    assert_eq!(status.state, LoginState::Denied);
+
+    let last = &errors.snapshot()["Microsoft"];
+    assert_eq!(last.method, "login");
+    assert_eq!(last.error.error_code, ErrorCodes::AccessDenied);
+    assert_eq!(last.error.details.http_status, Some(400));
+
+    tx.send(TaskMessage::Quit).unwrap();
+    manager.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_shared_events_for_older_clients() {
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new();
+    let inner = interface.clone();
+    let mut task_manager = TaskManager::new(rx).with_shared_events(true);
+    let manager = tokio::spawn(async move { task_manager.run(interface).await });
+
+    let session = LoginSession::new(&build_mock_provider());
+    tx.send(TaskMessage::SendSessionEvent(
+        session.clone(),
+        "login.pending".into(),
+        serde_json::Value::Null,
+    ))
+    .unwrap();
+    tx.send(TaskMessage::Quit).unwrap();
+    manager.await.unwrap();
+
+    let objects: Vec<String> = inner
+        .events_named("login.pending")
+        .into_iter()
+        .map(|event| event.object)
+        .collect();
+    assert_eq!(objects, [EVENT_OBJECT.to_string(), session.object()]);
+}
+
+#[tokio::test]
+async fn test_shutdown_drains_events_and_keeps_pending_logins() {
+    let (tx, rx) = unbounded_channel();
+    let interface = Mock::new()
+        .route(Route::post("/devicecode").respond_json(StatusCode::OK, DEVICE_CODE_RESPONSE))
+        .route(Route::post("/token").respond_json(StatusCode::BAD_REQUEST, PENDING_RESPONSE));
+    let inner = interface.clone();
+    let mut task_manager = TaskManager::new(rx);
+    let manager = tokio::spawn(async move { task_manager.run(interface).await });
+
+    let response = login(
+        build_mock_provider(),
+        inner.clone(),
+        tx.clone(),
+        &ProviderCache::new(),
+    )
+    .await
+    .unwrap();
+    inner
+        .wait_for_event("login.pending", Duration::from_secs(10))
+        .await
+        .expect("login.pending was not published");
+
+    // Another login whose poller has its token and is still saving it.
+    let mut provider = build_mock_provider();
+    provider.process = Some("Other Process".into());
+    let key = make_filename(&provider).unwrap();
+    let session = LoginSession::new(&provider);
+    let device_authorization: StandardDeviceAuthorizationResponse =
+        serde_json::from_str(DEVICE_CODE_RESPONSE).unwrap();
+    let saving = {
+        let (tx, key, session) = (tx.clone(), key.clone(), session.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let ready = serde_json::json!({ "saved": true });
+            tx.send(TaskMessage::SendSessionEvent(
+                session.clone(),
+                "token.ready".into(),
+                ready,
+            ))
+            .unwrap();
+            tx.send(TaskMessage::PollingDone(key, session.session_id, Ok(())))
+                .unwrap();
+        })
+    };
+    tx.send(TaskMessage::Add(
+        key.clone(),
+        LoginStatus::new(
+            session.clone(),
+            &device_authorization,
+            PollingPolicy::default().schedule(&device_authorization),
+        ),
+        saving,
+    ))
+    .unwrap();
+    tx.send(TaskMessage::Saving(key, session.session_id.clone()))
+        .unwrap();
+    tx.send(TaskMessage::SendEvent(
+        "queued".into(),
+        serde_json::json!({}),
+    ))
+    .unwrap();
+    tx.send(TaskMessage::Shutdown(Duration::from_secs(5)))
+        .unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), manager)
+        .await
+        .expect("the task manager did not stop")
+        .unwrap();
+
+    assert_eq!(inner.events_named("queued").len(), 1);
+    let ready = inner.events_named("token.ready");
+    assert_eq!(ready[0].result["saved"], true);
+    assert_eq!(ready[0].result["session_id"], session.session_id.as_str());
+
+    // The polling login was stopped, but kept for the next start.
+    let polls = inner.requests_to("/token").len();
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(inner.requests_to("/token").len(), polls);
+    let pending = PendingLogin::load_all(&inner.token_directory());
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].1.session_id, response.session_id);
+}
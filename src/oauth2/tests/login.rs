@@ -4,13 +4,15 @@ use crate::interface::mock::Mock;
 use crate::logger;
 use crate::oauth2::device_code_flow::login;
 use crate::oauth2::provider::InputParameters;
-use crate::task_manager::{TaskManager, TaskMessage};
+use crate::oauth2::tests::run_with_task_manager;
+use crate::openid::cache::ProviderCache;
+use crate::task_manager::TaskMessage;
 
 use http::{HeaderMap, HeaderValue, Response, StatusCode};
 use oauth2::{AuthUrl, ClientId, DeviceAuthorizationUrl, Scope, TokenUrl, url::Url};
 use tokio::sync::mpsc::unbounded_channel;
 
-fn build_mock_provider() -> InputParameters {
+pub fn build_mock_provider() -> InputParameters {
     InputParameters {
         authorization_endpoint: Some(AuthUrl::from_url(
             Url::parse("https://login.microsoftonline.com/common/oauth2/v2.0/authorize").unwrap(),
@@ -27,10 +29,9 @@ fn build_mock_provider() -> InputParameters {
             Scope::new("https://outlook.office.com/User.Read".into()),
         ]),
         client_id: Some(ClientId::new("64c5d510-4b7e-4a18-8869-89778461c266".into())),
-        client_secret: None,
         process: Some(String::from("Process Name")),
         provider: Some(String::from("Microsoft")),
-        id_token: None,
+        ..Default::default()
     }
 }
 
@@ -40,7 +41,7 @@ async fn test_login() {
     let (tx, rx) = unbounded_channel();
     let interface = Mock::new();
     let mut inner = interface.clone();
-    tokio::spawn(async move {
+    run_with_task_manager(interface, rx, async move {
         let body = r#"{"user_code":"usercode-123","device_code":"devicecode-123","verification_uri":"https://verification_url","expires_in":20,"interval":1,"message":"Mock message"}"#.as_bytes().to_vec();
         let mut headers = HeaderMap::new();
 
@@ -57,22 +58,35 @@ async fn test_login() {
 
         inner = inner.set_mock_response(response);
         let provider = build_mock_provider();
-        let result = login(provider, inner, tx.clone()).await.unwrap();
+        let result = login(provider, inner, tx.clone(), &ProviderCache::new())
+            .await
+            .unwrap();
 
-        assert_eq!(result.device_code().secret(), "devicecode-123");
-        assert_eq!(result.expires_in(), Duration::from_secs(20));
-        assert_eq!(result.user_code().secret(), "usercode-123");
         assert_eq!(
-            result.verification_uri().as_str(),
+            result.device_authorization.device_code().secret(),
+            "devicecode-123"
+        );
+        assert_eq!(
+            result.device_authorization.expires_in(),
+            Duration::from_secs(20)
+        );
+        assert_eq!(
+            result.device_authorization.user_code().secret(),
+            "usercode-123"
+        );
+        assert_eq!(
+            result.device_authorization.verification_uri().as_str(),
             "https://verification_url"
         );
-        assert_eq!(result.interval(), Duration::from_secs(1));
+        assert_eq!(
+            result.device_authorization.interval(),
+            Duration::from_secs(1)
+        );
 
         log::trace!("Result: {:?}", result);
 
         tokio::time::sleep(Duration::from_millis(1)).await;
         tx.send(TaskMessage::Quit).unwrap();
-    });
-
-    TaskManager::new(rx).run(interface).await;
+    })
+    .await;
 }
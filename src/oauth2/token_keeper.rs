@@ -12,10 +12,14 @@ use oauth2::{
 };
 use openidconnect::core::CoreIdToken;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use subtle::ConstantTimeEq;
 
 use crate::oauth2::device_code_flow::CustomTokenResponse;
 // My crates
-use crate::oauth2::error::OAuth2Result;
+use crate::oauth2::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+use crate::oauth2::session::TokenSecret;
+use crate::openid::identity::{self, Identity};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TokenKeeper {
@@ -24,6 +28,15 @@ pub struct TokenKeeper {
     pub refresh_token: Option<RefreshToken>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub id_token: Option<CoreIdToken>,
+    /// Nonce sent with the device authorization that issued `id_token`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    /// Identity decoded from `id_token`, cached once computed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<Identity>,
+    /// Hash of the secret required to fetch this token, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_secret_hash: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     scopes: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -50,6 +63,9 @@ impl From<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>> for Toke
             access_token: token_response.access_token().to_owned(),
             refresh_token,
             id_token: None,
+            nonce: None,
+            identity: None,
+            token_secret_hash: None,
             scopes,
             expires_in: token_response.expires_in(),
             token_receive_time: SystemTime::now()
@@ -74,6 +90,9 @@ impl From<CustomTokenResponse> for TokenKeeper {
             access_token: token_response.access_token().to_owned(),
             refresh_token,
             id_token: token_response.extra_fields().id_token.to_owned(),
+            nonce: None,
+            identity: None,
+            token_secret_hash: None,
             scopes,
             expires_in: token_response.expires_in(),
             token_receive_time: SystemTime::now()
@@ -94,6 +113,67 @@ impl TokenKeeper {
             token_receive_time: Duration::new(0, 0),
             file_directory,
             id_token: None,
+            nonce: None,
+            identity: None,
+            token_secret_hash: None,
+        }
+    }
+
+    /// Keeps `nonce` to verify this token's ID token against if `required`,
+    /// as when the caller chose it, or else only if the provider echoed it in
+    /// the ID token: RFC 8628 has no `nonce` parameter, and providers
+    /// ignoring it would otherwise fail every verification.
+    pub fn set_nonce(&mut self, nonce: Option<String>, required: bool) {
+        let echoed = self.id_token.as_ref().and_then(identity::nonce);
+        self.nonce = nonce.filter(|nonce| required || echoed.as_ref() == Some(nonce));
+    }
+
+    /// The access token issued with `id_token`, whose `at_hash` binds them.
+    /// None for an ID token from another login, or from before a refresh
+    /// rotated the access token.
+    pub fn access_token_of(&self, id_token: Option<&CoreIdToken>) -> Option<&AccessToken> {
+        let issued = self.id_token.as_ref()?.to_string();
+        (id_token?.to_string() == issued).then_some(&self.access_token)
+    }
+
+    /// The token as announced in events: everything but the credentials.
+    pub fn without_secrets(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let Value::Object(object) = &mut value {
+            for field in [
+                "access_token",
+                "refresh_token",
+                "id_token",
+                "nonce",
+                "token_secret_hash",
+            ] {
+                object.remove(field);
+            }
+        }
+        value
+    }
+
+    /// The token as returned to the process that logged in: the hash of its
+    /// secret and the nonce stay with the service.
+    pub fn for_caller(mut self) -> Self {
+        self.nonce = None;
+        self.token_secret_hash = None;
+        self
+    }
+
+    /// Checks that `secret` may fetch this token.
+    pub fn authorize(&self, secret: Option<&TokenSecret>) -> OAuth2Result<()> {
+        match (&self.token_secret_hash, secret) {
+            (None, _) => Ok(()),
+            (Some(hash), Some(secret))
+                if bool::from(hash.as_bytes().ct_eq(secret.hash().as_bytes())) =>
+            {
+                Ok(())
+            }
+            (Some(_), _) => Err(OAuth2Error::new(
+                ErrorCodes::Forbidden,
+                "The token belongs to the process that logged in.".into(),
+            )),
         }
     }
 
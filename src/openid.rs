@@ -1,84 +1,173 @@
-use chrono::{DateTime, Utc};
+pub mod access_token;
+pub mod cache;
+pub mod identity;
+pub mod pinned;
+pub mod policy;
+#[cfg(test)]
+mod tests;
+
+use oauth2::AccessToken;
 use openidconnect::{
     NonceVerifier,
-    core::{CoreIdTokenClaims, CoreIdTokenVerifier, CoreProviderMetadata},
+    core::{CoreIdTokenClaims, CoreIdTokenVerifier},
 };
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use crate::{
-    http_client::OAuth2Client,
     interface::Interface,
+    metrics::{METRICS, result_label},
     oauth2::{
         error::{ErrorCodes, OAuth2Error, OAuth2Result},
         provider::InputParameters,
     },
+    openid::{
+        access_token::{AccessTokenClaims, UnverifiedAccessToken},
+        cache::{ProviderCache, jwt_kid},
+    },
 };
 
 pub async fn verify_id_token<I>(
     provider: InputParameters,
     app_nonce: ApplicationNonce,
+    access_token: Option<&AccessToken>,
+    cache: &ProviderCache,
     interface: I,
 ) -> OAuth2Result<CoreIdTokenClaims>
 where
     I: Interface + Clone + Send + Sync + 'static,
 {
-    log::info!("Verifying logged-in user . . .");
-    let verifier = CoreIdTokenVerifier::new_insecure_without_verification();
+    let result = check_id_token(provider, app_nonce, access_token, cache, interface).await;
+    METRICS
+        .verifications
+        .inc(&[("token", "id_token"), ("result", result_label(&result))]);
+    result
+}
 
+async fn check_id_token<I>(
+    provider: InputParameters,
+    app_nonce: ApplicationNonce,
+    access_token: Option<&AccessToken>,
+    cache: &ProviderCache,
+    interface: I,
+) -> OAuth2Result<CoreIdTokenClaims>
+where
+    I: Interface + Clone + Send + Sync + 'static,
+{
+    log::info!("Verifying logged-in user . . .");
     let id_token = provider.id_token.ok_or(OAuth2Error::new(
         ErrorCodes::ParseError,
         "No ID Token supplied.".into(),
     ))?;
+    let client_id = provider.client_id.ok_or(OAuth2Error::new(
+        ErrorCodes::ParseError,
+        "No Client ID supplied.".into(),
+    ))?;
+    let policy = provider.id_token_policy.unwrap_or_default();
+    let verifier =
+        CoreIdTokenVerifier::new_insecure_without_verification().set_time_fn(|| policy.now());
     let unverified_claims = id_token.claims(&verifier, ApplicationNonce::new())?;
+    policy.precheck(&id_token, unverified_claims, &client_id)?;
 
     let url = unverified_claims.issuer();
-    let aync_http_client = OAuth2Client::new(interface.clone());
-    let provider_metadata =
-        CoreProviderMetadata::discover_async(url.clone(), &aync_http_client).await?;
-
-    let json_web_key_set = provider_metadata.jwks();
-    let expiry = unverified_claims.expiration();
+    let json_web_key_set = match &provider.pinned_keys {
+        Some(pinned) => {
+            log::info!("Using pinned keys for {}", pinned.issuer.as_str());
+            pinned.check_issuer(url)?;
+            pinned.key_set()?
+        }
+        None => {
+            let kid = jwt_kid(&id_token.to_string());
+            let provider_metadata = cache.metadata(url, kid.as_deref(), interface).await?;
+            provider_metadata.jwks().clone()
+        }
+    };
     let verifier = if let Some(secret) = provider.client_secret {
         log::info!("Has client secret use => CoreIdTokenVerifier::new_confidential_client");
         CoreIdTokenVerifier::new_confidential_client(
-            provider.client_id.ok_or(OAuth2Error::new(
-                ErrorCodes::ParseError,
-                "No Client ID supplied.".into(),
-            ))?,
+            client_id.clone(),
             secret,
             url.clone(),
-            json_web_key_set.clone(),
+            json_web_key_set,
         )
-        .enable_signature_check()
-        .require_audience_match(true)
-        .require_issuer_match(true)
-        .set_time_fn(Utc::now)
-        .set_issue_time_verifier_fn(|time| check_expiry(time, expiry))
-        .set_auth_time_verifier_fn(log_authenticated_time)
     } else {
         log::info!("No client secret use => CoreIdTokenVerifier::new_public_client");
-        CoreIdTokenVerifier::new_public_client(
-            provider.client_id.ok_or(OAuth2Error::new(
-                ErrorCodes::ParseError,
-                "No Client ID supplied.".into(),
-            ))?,
-            url.clone(),
-            json_web_key_set.clone(),
-        )
-        .enable_signature_check()
-        .require_audience_match(true)
-        .require_issuer_match(true)
-        .set_time_fn(Utc::now)
-        .set_issue_time_verifier_fn(|time| check_expiry(time, expiry))
-        .set_auth_time_verifier_fn(log_authenticated_time)
+        CoreIdTokenVerifier::new_public_client(client_id.clone(), url.clone(), json_web_key_set)
     };
+    let verifier = policy.apply(
+        verifier
+            .enable_signature_check()
+            .require_audience_match(true)
+            .require_issuer_match(true),
+    );
 
     let verified_claims = id_token.claims(&verifier, app_nonce)?.clone();
+    policy.check_claims(&verified_claims, &client_id)?;
+    if let Some(access_token) = access_token {
+        policy.check_at_hash(&id_token, &verified_claims, &verifier, access_token)?;
+    }
     log::info!("Verifying logged-in user successfull!");
     Ok(verified_claims)
 }
 
+/// Validates a JWT access token (RFC 9068) presented to a resource server.
+pub async fn verify_access_token<I>(
+    provider: InputParameters,
+    cache: &ProviderCache,
+    interface: I,
+) -> OAuth2Result<AccessTokenClaims>
+where
+    I: Interface + Clone + Send + Sync + 'static,
+{
+    let result = check_access_token(provider, cache, interface).await;
+    METRICS
+        .verifications
+        .inc(&[("token", "access_token"), ("result", result_label(&result))]);
+    result
+}
+
+async fn check_access_token<I>(
+    provider: InputParameters,
+    cache: &ProviderCache,
+    interface: I,
+) -> OAuth2Result<AccessTokenClaims>
+where
+    I: Interface + Clone + Send + Sync + 'static,
+{
+    log::info!("Verifying access token . . .");
+    let access_token = provider.access_token.ok_or(OAuth2Error::new(
+        ErrorCodes::ParseError,
+        "No Access Token supplied.".into(),
+    ))?;
+    let policy = provider.access_token_policy.unwrap_or_default();
+    let issuer = policy
+        .issuer
+        .clone()
+        .or_else(|| Some(provider.pinned_keys.as_ref()?.issuer.clone()))
+        .ok_or(OAuth2Error::new(
+            ErrorCodes::InvalidParameters,
+            "No access token issuer supplied.".into(),
+        ))?;
+
+    let token = UnverifiedAccessToken::parse(access_token.secret())?;
+    let json_web_key_set = match &provider.pinned_keys {
+        Some(pinned) => {
+            pinned.check_issuer(&issuer)?;
+            pinned.key_set()?
+        }
+        None => {
+            let provider_metadata = cache
+                .metadata(&issuer, token.kid.as_deref(), interface)
+                .await?;
+            provider_metadata.jwks().clone()
+        }
+    };
+
+    let claims = token.verify(&json_web_key_set, &issuer, &policy)?;
+    log::info!("Verifying access token successfull!");
+    Ok(claims)
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ApplicationNonce(String);
 
@@ -86,7 +175,6 @@ impl ApplicationNonce {
     pub fn new() -> Self {
         Self::default()
     }
-    #[allow(dead_code)]
     pub fn from(nonce: String) -> Self {
         Self(nonce)
     }
@@ -106,33 +194,12 @@ impl NonceVerifier for ApplicationNonce {
             } else {
                 log::info!("There is no application side nonce used.");
             }
+        } else if !self.0.is_empty() {
+            log::info!("Nonce expected but missing!");
+            return Err("nonce expected but missing from the ID token".to_string());
         } else {
             log::info!("The server didn't give some Nonce.");
         }
         Ok(())
     }
 }
-
-fn check_expiry(issued_time: DateTime<Utc>, expiry_time: DateTime<Utc>) -> Result<(), String> {
-    log::info!("ID Token Issue Time: {issued_time:?}");
-    log::info!("ID Token Expiry Time: {expiry_time:?}");
-
-    let allowed_expiry_range = expiry_time - issued_time;
-    log::info!("Configured Lifetime (Expiry - Issue): {allowed_expiry_range:?}");
-
-    let actual_diff = Utc::now() - issued_time;
-    log::info!("Elapsed Since Issue: {actual_diff:?}");
-
-    if actual_diff > allowed_expiry_range {
-        Err(String::from("ID Token is expired (issue time too old)."))
-    } else {
-        Ok(())
-    }
-}
-
-fn log_authenticated_time(time: Option<DateTime<Utc>>) -> Result<(), String> {
-    if let Some(time) = time {
-        log::info!("User authenticated at: {time:?}");
-    }
-    Ok(())
-}
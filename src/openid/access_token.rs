@@ -0,0 +1,252 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{TimeDelta, Utc};
+use openidconnect::{
+    IssuerUrl, JsonWebKey,
+    core::{CoreJsonWebKeySet, CoreJwsSigningAlgorithm},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::oauth2::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+use crate::openid::policy::alg_name;
+
+/// What a resource server expects of the JWT access tokens it receives.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AccessTokenPolicy {
+    /// Expected `iss`; defaults to the issuer of the pinned keys.
+    pub issuer: Option<IssuerUrl>,
+    /// Expected `aud`, i.e. the resource server's identifier.
+    pub audience: Option<String>,
+    /// JWS algorithms accepted for the token signature.
+    pub allowed_algorithms: Vec<CoreJwsSigningAlgorithm>,
+    /// Leeway in seconds applied to `exp` and `nbf`.
+    pub clock_skew: u64,
+    /// Scopes the token must all grant.
+    pub required_scopes: Vec<String>,
+    /// Roles the token must all carry.
+    pub required_roles: Vec<String>,
+}
+
+impl Default for AccessTokenPolicy {
+    fn default() -> Self {
+        Self {
+            issuer: None,
+            audience: None,
+            allowed_algorithms: vec![CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256],
+            clock_skew: 60,
+            required_scopes: Vec::new(),
+            required_roles: Vec::new(),
+        }
+    }
+}
+
+/// The claims of a verified RFC 9068 access token.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccessTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: Audiences,
+    pub exp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub roles: Vec<String>,
+    #[serde(flatten)]
+    pub additional_claims: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Audiences {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audiences {
+    pub fn contains(&self, audience: &str) -> bool {
+        match self {
+            Audiences::Single(aud) => aud == audience,
+            Audiences::Multiple(auds) => auds.iter().any(|aud| aud == audience),
+        }
+    }
+}
+
+/// Reads a claim that may be a single string or an array of them, as
+/// `roles` may be (RFC 9068 section 2.2.3.1).
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+fn invalid(reason: String) -> OAuth2Error {
+    OAuth2Error::new(ErrorCodes::ClaimsVerificationError, reason)
+}
+
+fn decode_part<T: serde::de::DeserializeOwned>(part: &str, name: &str) -> OAuth2Result<T> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|e| invalid(format!("Malformed access token {name}: {e}")))?;
+    serde_json::from_slice(&bytes)
+        .map_err(|e| invalid(format!("Malformed access token {name}: {e}")))
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: Value,
+    typ: Option<String>,
+    kid: Option<String>,
+}
+
+/// An access token split into its parts, not verified yet.
+pub struct UnverifiedAccessToken<'a> {
+    signing_input: &'a str,
+    signature: Vec<u8>,
+    alg: CoreJwsSigningAlgorithm,
+    pub kid: Option<String>,
+    pub claims: AccessTokenClaims,
+}
+
+impl<'a> UnverifiedAccessToken<'a> {
+    pub fn parse(token: &'a str) -> OAuth2Result<Self> {
+        let (signing_input, signature) = token
+            .rsplit_once('.')
+            .ok_or_else(|| invalid("Access token is not a JWT.".into()))?;
+        let (header, claims) = signing_input
+            .split_once('.')
+            .ok_or_else(|| invalid("Access token is not a JWT.".into()))?;
+        let header: Header = decode_part(header, "header")?;
+
+        // RFC 9068 section 4: the type must be at+jwt, with or without the media type prefix.
+        let typ = header.typ.unwrap_or_default().to_ascii_lowercase();
+        if typ != "at+jwt" && typ != "application/at+jwt" {
+            return Err(invalid(format!(
+                "Invalid access token type {typ:?}, expected at+jwt."
+            )));
+        }
+        let alg = serde_json::from_value(header.alg)
+            .map_err(|e| invalid(format!("Unsupported access token algorithm: {e}")))?;
+
+        Ok(Self {
+            signing_input,
+            signature: URL_SAFE_NO_PAD
+                .decode(signature)
+                .map_err(|e| invalid(format!("Malformed access token signature: {e}")))?,
+            alg,
+            kid: header.kid,
+            claims: decode_part(claims, "claims")?,
+        })
+    }
+
+    /// Checks the signature against `jwks`, then the claims against `policy`.
+    pub fn verify(
+        self,
+        jwks: &CoreJsonWebKeySet,
+        issuer: &IssuerUrl,
+        policy: &AccessTokenPolicy,
+    ) -> OAuth2Result<AccessTokenClaims> {
+        if !policy.allowed_algorithms.contains(&self.alg) {
+            return Err(invalid(format!(
+                "access_token_policy.allowed_algorithms: {} is not allowed.",
+                alg_name(&self.alg)
+            )));
+        }
+        let verified = jwks
+            .keys()
+            .iter()
+            .filter(|key| match (&self.kid, key.key_id()) {
+                (Some(kid), Some(key_id)) => kid == key_id.as_str(),
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .any(|key| {
+                key.verify_signature(&self.alg, self.signing_input.as_bytes(), &self.signature)
+                    .is_ok()
+            });
+        if !verified {
+            return Err(invalid(
+                "Access token signature verification failed.".into(),
+            ));
+        }
+
+        let claims = self.claims;
+        if claims.iss.trim_end_matches('/') != issuer.as_str().trim_end_matches('/') {
+            return Err(invalid(format!(
+                "Invalid issuer: expected {}, found {}.",
+                issuer.as_str(),
+                claims.iss
+            )));
+        }
+        let audience = policy.audience.as_deref().ok_or_else(|| {
+            OAuth2Error::new(
+                ErrorCodes::InvalidParameters,
+                "No access token audience supplied.".into(),
+            )
+        })?;
+        if !claims.aud.contains(audience) {
+            return Err(invalid(format!(
+                "Invalid audiences: {audience} is not an audience of the access token."
+            )));
+        }
+
+        let now = Utc::now();
+        let skew = TimeDelta::seconds(policy.clock_skew as i64);
+        if claims.exp <= (now - skew).timestamp() {
+            return Err(invalid(format!(
+                "Expired: access token expired at {}.",
+                claims.exp
+            )));
+        }
+        if let Some(nbf) = claims.nbf
+            && nbf > (now + skew).timestamp()
+        {
+            return Err(invalid(format!("Access token is not valid before {nbf}.")));
+        }
+
+        let scopes: Vec<&str> = claims
+            .scope
+            .as_deref()
+            .unwrap_or_default()
+            .split(' ')
+            .collect();
+        if let Some(missing) = policy
+            .required_scopes
+            .iter()
+            .find(|scope| !scopes.contains(&scope.as_str()))
+        {
+            return Err(invalid(format!(
+                "access_token_policy.required_scopes: {missing} not granted."
+            )));
+        }
+        if let Some(missing) = policy
+            .required_roles
+            .iter()
+            .find(|role| !claims.roles.contains(role))
+        {
+            return Err(invalid(format!(
+                "access_token_policy.required_roles: {missing} missing."
+            )));
+        }
+        Ok(claims)
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use http::{
+    HeaderMap, Method, Request,
+    header::{ACCEPT, CACHE_CONTROL},
+};
+use oauth2::url::Url;
+use openidconnect::{
+    IssuerUrl, JsonWebKey,
+    core::{CoreJsonWebKeySet, CoreProviderMetadata},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::{
+    interface::Interface,
+    metrics::METRICS,
+    oauth2::error::{ErrorCodes, OAuth2Error, OAuth2Result},
+};
+
+/// Lifetime of a document served without a usable `Cache-Control` header.
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+/// Upper bound for `max-age`, so a misconfigured provider can't pin keys forever.
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Minimum time between two JWKS fetches triggered by an unknown `kid`.
+const MIN_JWKS_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub jwks_refetches: u64,
+    pub rate_limited: u64,
+    pub stale_served: u64,
+    pub errors: u64,
+}
+
+struct CacheEntry {
+    metadata: CoreProviderMetadata,
+    metadata_expires: Instant,
+    jwks_expires: Instant,
+    /// Last JWKS fetch caused by an unknown `kid`.
+    jwks_refetched: Option<Instant>,
+}
+
+impl CacheEntry {
+    fn has_key(&self, kid: &str) -> bool {
+        self.metadata
+            .jwks()
+            .keys()
+            .iter()
+            .any(|key| key.key_id().is_some_and(|id| id.as_str() == kid))
+    }
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    stats: CacheStats,
+}
+
+enum Action {
+    Discover,
+    FetchJwks,
+    Rotate,
+}
+
+/// Per-issuer cache of discovery documents and their JWKS.
+///
+/// Documents are kept as long as the provider's `Cache-Control` allows. A
+/// token signed with a `kid` missing from the cached JWKS triggers a single
+/// JWKS refetch (key rotation); further refetches are limited to one per
+/// [`MIN_JWKS_REFETCH_INTERVAL`]. When the provider can't be reached an
+/// expired entry is served rather than failing.
+#[derive(Clone, Default)]
+pub struct ProviderCache {
+    state: Arc<Mutex<CacheState>>,
+}
+
+impl ProviderCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            entries: state.entries.len(),
+            ..state.stats.clone()
+        }
+    }
+
+    /// Returns the provider metadata of `issuer` with its JWKS populated,
+    /// making sure the JWKS contains `kid` if at all possible.
+    pub async fn metadata<I>(
+        &self,
+        issuer: &IssuerUrl,
+        kid: Option<&str>,
+        interface: I,
+    ) -> OAuth2Result<CoreProviderMetadata>
+    where
+        I: Interface + Clone + Send + Sync + 'static,
+    {
+        let key = issuer.as_str().trim_end_matches('/').to_string();
+        let now = Instant::now();
+
+        let action = {
+            let mut state = self.state.lock().unwrap();
+            let CacheState { entries, stats } = &mut *state;
+            match entries.get_mut(&key) {
+                None => Action::Discover,
+                Some(entry) if entry.metadata_expires <= now => Action::Discover,
+                Some(entry) if entry.jwks_expires <= now => Action::FetchJwks,
+                Some(entry) if kid.is_some_and(|kid| !entry.has_key(kid)) => {
+                    if entry
+                        .jwks_refetched
+                        .is_some_and(|last| now.duration_since(last) < MIN_JWKS_REFETCH_INTERVAL)
+                    {
+                        log::warn!("Unknown key {kid:?} for {key}, JWKS refetch rate limited.");
+                        stats.rate_limited += 1;
+                        return Ok(entry.metadata.clone());
+                    }
+                    log::info!("Unknown key {kid:?} for {key}, refetching JWKS.");
+                    entry.jwks_refetched = Some(now);
+                    Action::Rotate
+                }
+                Some(entry) => {
+                    stats.hits += 1;
+                    return Ok(entry.metadata.clone());
+                }
+            }
+        };
+
+        let result = match action {
+            Action::Discover => {
+                self.state.lock().unwrap().stats.misses += 1;
+                self.discover(issuer, &key, &interface).await
+            }
+            Action::FetchJwks | Action::Rotate => {
+                let metadata = {
+                    let mut state = self.state.lock().unwrap();
+                    match action {
+                        Action::Rotate => state.stats.jwks_refetches += 1,
+                        _ => state.stats.misses += 1,
+                    }
+                    state.entries[&key].metadata.clone()
+                };
+                self.refresh_jwks(metadata, &key, &interface).await
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let CacheState { entries, stats } = &mut *state;
+        match result {
+            Ok(entry) => {
+                let metadata = entry.metadata.clone();
+                entries.insert(key, entry);
+                Ok(metadata)
+            }
+            Err(e) => {
+                stats.errors += 1;
+                match entries.get(&key) {
+                    Some(stale) => {
+                        log::warn!("Using cached provider metadata for {key}: {e}");
+                        stats.stale_served += 1;
+                        Ok(stale.metadata.clone())
+                    }
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    async fn discover<I>(
+        &self,
+        issuer: &IssuerUrl,
+        key: &str,
+        interface: &I,
+    ) -> OAuth2Result<CacheEntry>
+    where
+        I: Interface + Clone + Send + Sync + 'static,
+    {
+        let discovery_url = Url::parse(&format!("{key}/.well-known/openid-configuration"))?;
+        let started = Instant::now();
+        let (metadata, ttl): (CoreProviderMetadata, _) =
+            fetch_json(&discovery_url, interface).await?;
+
+        if metadata.issuer() != issuer {
+            return Err(OAuth2Error::new(
+                ErrorCodes::DiscoveryError,
+                format!(
+                    "Issuer mismatch: expected {}, discovery document has {}.",
+                    issuer.as_str(),
+                    metadata.issuer().as_str()
+                ),
+            ));
+        }
+
+        let mut entry = self.refresh_jwks(metadata, key, interface).await?;
+        METRICS.discovery_duration.observe(&[], started.elapsed());
+        entry.metadata_expires = Instant::now() + ttl;
+        Ok(entry)
+    }
+
+    async fn refresh_jwks<I>(
+        &self,
+        metadata: CoreProviderMetadata,
+        key: &str,
+        interface: &I,
+    ) -> OAuth2Result<CacheEntry>
+    where
+        I: Interface + Clone + Send + Sync + 'static,
+    {
+        let (jwks, ttl): (CoreJsonWebKeySet, _) =
+            fetch_json(metadata.jwks_uri().url(), interface).await?;
+        log::info!("Fetched {} signing keys for {key}.", jwks.keys().len());
+
+        let now = Instant::now();
+        let (metadata_expires, jwks_refetched) = self
+            .state
+            .lock()
+            .unwrap()
+            .entries
+            .get(key)
+            .map_or((now + DEFAULT_TTL, None), |entry| {
+                (entry.metadata_expires, entry.jwks_refetched)
+            });
+        Ok(CacheEntry {
+            metadata: metadata.set_jwks(jwks),
+            metadata_expires,
+            jwks_expires: now + ttl,
+            jwks_refetched,
+        })
+    }
+}
+
+async fn fetch_json<I, T>(url: &Url, interface: &I) -> OAuth2Result<(T, Duration)>
+where
+    I: Interface + Clone + Send + Sync + 'static,
+    T: DeserializeOwned,
+{
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri(url.as_str())
+        .header(ACCEPT, "application/json")
+        .body(Vec::new())?;
+    let response = interface.http_request(request).await?;
+
+    if !response.status().is_success() {
+        return Err(OAuth2Error::new(
+            ErrorCodes::DiscoveryError,
+            format!("{url} returned HTTP {}.", response.status()),
+        ));
+    }
+    let document = serde_json::from_slice(response.body()).map_err(|e| {
+        OAuth2Error::new(
+            ErrorCodes::DiscoveryError,
+            format!("Invalid document at {url}: {e}"),
+        )
+    })?;
+    Ok((document, cache_lifetime(response.headers())))
+}
+
+fn cache_lifetime(headers: &HeaderMap) -> Duration {
+    let Some(cache_control) = headers.get(CACHE_CONTROL).and_then(|v| v.to_str().ok()) else {
+        return DEFAULT_TTL;
+    };
+
+    let mut ttl = DEFAULT_TTL;
+    for directive in cache_control.split(',').map(str::trim) {
+        if directive.eq_ignore_ascii_case("no-store") || directive.eq_ignore_ascii_case("no-cache")
+        {
+            return Duration::ZERO;
+        }
+        if let Some(max_age) = directive.strip_prefix("max-age=")
+            && let Ok(seconds) = max_age.trim_matches('"').parse()
+        {
+            ttl = Duration::from_secs(seconds).min(MAX_TTL);
+        }
+    }
+    ttl
+}
+
+/// Reads the `kid` from the (unverified) header of a compact JWT.
+pub fn jwt_kid(jwt: &str) -> Option<String> {
+    let header = jwt.split('.').next()?;
+    let header: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+    header.get("kid")?.as_str().map(str::to_string)
+}
@@ -0,0 +1,100 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use openidconnect::core::CoreIdToken;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    interface::Interface,
+    oauth2::{
+        error::{ErrorCodes, OAuth2Error, OAuth2Result},
+        provider::InputParameters,
+        token_keeper::TokenKeeper,
+    },
+    openid::{ApplicationNonce, cache::ProviderCache, verify_id_token},
+};
+
+/// Claims providers use for the tenant of the user, in order of preference.
+const TENANT_CLAIMS: [&str; 3] = ["tid", "tenant_id", "tenant"];
+
+/// Who is logged in, taken from the ID token.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub subject: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// False when the ID token could only be decoded, not verified.
+    pub verified: bool,
+}
+
+/// The payload of `id_token`, unverified.
+fn claims(id_token: &CoreIdToken) -> Option<Value> {
+    let jwt = id_token.to_string();
+    let payload = jwt.split('.').nth(1)?;
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+}
+
+/// The `nonce` claim of `id_token`, unverified.
+pub fn nonce(id_token: &CoreIdToken) -> Option<String> {
+    claims(id_token)?.get("nonce")?.as_str().map(str::to_string)
+}
+
+impl Identity {
+    /// Reads the identity claims from the payload of `id_token`.
+    pub fn decode(id_token: &CoreIdToken, verified: bool) -> OAuth2Result<Self> {
+        let claims = claims(id_token).ok_or(OAuth2Error::new(
+            ErrorCodes::ParseError,
+            "Malformed ID Token payload.".into(),
+        ))?;
+        let claim = |name: &str| claims.get(name).and_then(Value::as_str).map(str::to_string);
+
+        Ok(Self {
+            subject: claim("sub").ok_or(OAuth2Error::new(
+                ErrorCodes::ParseError,
+                "ID Token has no subject.".into(),
+            ))?,
+            name: claim("name"),
+            email: claim("email"),
+            preferred_username: claim("preferred_username"),
+            tenant: TENANT_CLAIMS.iter().find_map(|name| claim(name)),
+            verified,
+        })
+    }
+}
+
+/// The identity of `token`'s ID token, verified with the provider's settings
+/// when possible and only decoded otherwise.
+pub async fn identify<I>(
+    provider: &InputParameters,
+    token: &TokenKeeper,
+    cache: &ProviderCache,
+    interface: I,
+) -> Option<Identity>
+where
+    I: Interface + Clone + Send + Sync + 'static,
+{
+    let id_token = token.id_token.clone()?;
+    let mut provider = provider.clone();
+    provider.id_token = Some(id_token.clone());
+    let nonce = token
+        .nonce
+        .clone()
+        .map_or_else(ApplicationNonce::new, ApplicationNonce::from);
+
+    let verified =
+        match verify_id_token(provider, nonce, Some(&token.access_token), cache, interface).await {
+            Ok(_) => true,
+            Err(err) => {
+                log::warn!("Identity is not verified: {err}");
+                false
+            }
+        };
+    Identity::decode(&id_token, verified)
+        .inspect_err(|err| log::error!("{err}"))
+        .ok()
+}
@@ -0,0 +1,47 @@
+use std::{fs, path::PathBuf};
+
+use openidconnect::{IssuerUrl, core::CoreJsonWebKeySet};
+use serde::{Deserialize, Serialize};
+
+use crate::oauth2::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+/// Keys trusted for an issuer without discovery, for hosts that can't reach
+/// the provider. The JWKS is given inline or as a path to a JWKS document.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PinnedKeys {
+    pub issuer: IssuerUrl,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwks: Option<CoreJsonWebKeySet>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwks_file: Option<PathBuf>,
+}
+
+impl PinnedKeys {
+    pub fn key_set(&self) -> OAuth2Result<CoreJsonWebKeySet> {
+        match (&self.jwks, &self.jwks_file) {
+            (Some(jwks), _) => Ok(jwks.clone()),
+            (None, Some(path)) => {
+                log::info!("Reading pinned keys from {path:?}");
+                Ok(serde_json::from_slice(&fs::read(path)?)?)
+            }
+            (None, None) => Err(OAuth2Error::new(
+                ErrorCodes::InvalidParameters,
+                "Pinned keys need either jwks or jwks_file.".into(),
+            )),
+        }
+    }
+
+    pub fn check_issuer(&self, issuer: &IssuerUrl) -> OAuth2Result<()> {
+        if issuer.as_str().trim_end_matches('/') != self.issuer.as_str().trim_end_matches('/') {
+            return Err(OAuth2Error::new(
+                ErrorCodes::ClaimsVerificationError,
+                format!(
+                    "Invalid issuer: expected pinned issuer {}, found {}.",
+                    self.issuer.as_str(),
+                    issuer.as_str()
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use oauth2::AccessToken;
+use openidconnect::{
+    AccessTokenHash, Audience, AuthenticationContextClass, ClientId,
+    core::{CoreIdToken, CoreIdTokenClaims, CoreIdTokenVerifier, CoreJwsSigningAlgorithm},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::oauth2::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+/// Per-provider rules applied on top of the standard ID token validation.
+///
+/// Every field is optional in the request; missing fields fall back to the
+/// defaults below (RS256 only, one minute clock skew, `azp` and `at_hash`
+/// checked when present).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct IdTokenPolicy {
+    /// JWS algorithms accepted for the ID token signature.
+    pub allowed_algorithms: Vec<CoreJwsSigningAlgorithm>,
+    /// Leeway in seconds applied to `exp`, `iat` and `auth_time`.
+    pub clock_skew: u64,
+    /// Maximum age in seconds of the end-user authentication (`auth_time`).
+    pub max_age: Option<u64>,
+    /// `acr` values of which the token must assert one.
+    pub acr_values: Vec<String>,
+    /// `amr` values the token must all assert.
+    pub required_amr: Vec<String>,
+    /// Audiences trusted besides the client ID.
+    pub trusted_audiences: Vec<String>,
+    /// Require `azp` to be the client ID, and present with several audiences.
+    pub check_azp: bool,
+    /// Compare `at_hash` with the stored access token issued with the ID token.
+    pub check_at_hash: bool,
+}
+
+impl Default for IdTokenPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_algorithms: vec![CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256],
+            clock_skew: 60,
+            max_age: None,
+            acr_values: Vec::new(),
+            required_amr: Vec::new(),
+            trusted_audiences: Vec::new(),
+            check_azp: true,
+            check_at_hash: true,
+        }
+    }
+}
+
+fn violation(rule: &str, reason: String) -> OAuth2Error {
+    OAuth2Error::new(
+        ErrorCodes::ClaimsVerificationError,
+        format!("id_token_policy.{rule}: {reason}"),
+    )
+}
+
+impl IdTokenPolicy {
+    fn skew(&self) -> TimeDelta {
+        TimeDelta::seconds(self.clock_skew as i64)
+    }
+
+    /// The current time, moved back by the clock skew for `exp` checks.
+    pub fn now(&self) -> DateTime<Utc> {
+        Utc::now() - self.skew()
+    }
+
+    /// Rules checked before the signature, so a rejected algorithm or
+    /// audience is reported by rule rather than as a generic failure.
+    pub fn precheck(
+        &self,
+        id_token: &CoreIdToken,
+        unverified_claims: &CoreIdTokenClaims,
+        client_id: &ClientId,
+    ) -> OAuth2Result<()> {
+        let alg = id_token
+            .signing_alg()
+            .map_err(|e| violation("allowed_algorithms", e.to_string()))?;
+        if !self.allowed_algorithms.contains(alg) {
+            return Err(violation(
+                "allowed_algorithms",
+                format!("{} is not allowed.", alg_name(alg)),
+            ));
+        }
+        if let Some(audience) = unverified_claims
+            .audiences()
+            .iter()
+            .find(|aud| aud.as_str() != client_id.as_str() && !self.trusts_audience(aud))
+        {
+            return Err(violation(
+                "trusted_audiences",
+                format!("{} is not trusted.", audience.as_str()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Configures the rules `verifier` can check itself.
+    pub fn apply<'a>(&'a self, verifier: CoreIdTokenVerifier<'a>) -> CoreIdTokenVerifier<'a> {
+        verifier
+            .set_allowed_algs(self.allowed_algorithms.clone())
+            .set_time_fn(|| self.now())
+            .set_issue_time_verifier_fn(|time| self.check_issue_time(time))
+            .set_auth_time_verifier_fn(|time| self.check_auth_time(time))
+            .set_auth_context_verifier_fn(|acr| self.check_acr(acr))
+            .set_other_audience_verifier_fn(|aud| self.trusts_audience(aud))
+    }
+
+    /// Rules checked on the verified claims.
+    pub fn check_claims(
+        &self,
+        claims: &CoreIdTokenClaims,
+        client_id: &ClientId,
+    ) -> OAuth2Result<()> {
+        let amr = claims.auth_method_refs().cloned().unwrap_or_default();
+        for required in &self.required_amr {
+            if !amr.iter().any(|value| value.as_str() == required) {
+                return Err(violation(
+                    "required_amr",
+                    format!("{required} missing from amr."),
+                ));
+            }
+        }
+
+        if self.check_azp {
+            match claims.authorized_party() {
+                Some(azp) if azp != client_id => {
+                    return Err(violation(
+                        "check_azp",
+                        format!("azp {} is not the client ID.", azp.as_str()),
+                    ));
+                }
+                None if claims.audiences().len() > 1 => {
+                    return Err(violation(
+                        "check_azp",
+                        "azp missing with multiple audiences.".into(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares `at_hash`, when the token has one, with `access_token`.
+    pub fn check_at_hash(
+        &self,
+        id_token: &CoreIdToken,
+        claims: &CoreIdTokenClaims,
+        verifier: &CoreIdTokenVerifier,
+        access_token: &AccessToken,
+    ) -> OAuth2Result<()> {
+        let Some(expected) = claims.access_token_hash().filter(|_| self.check_at_hash) else {
+            return Ok(());
+        };
+        let alg = id_token
+            .signing_alg()
+            .map_err(|e| violation("check_at_hash", e.to_string()))?;
+        let key = id_token
+            .signing_key(verifier)
+            .map_err(|e| violation("check_at_hash", e.to_string()))?;
+        let actual = AccessTokenHash::from_token(access_token, alg, key)
+            .map_err(|e| violation("check_at_hash", e.to_string()))?;
+        if &actual != expected {
+            return Err(violation(
+                "check_at_hash",
+                "at_hash does not match the stored access token.".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_issue_time(&self, issued_time: DateTime<Utc>) -> Result<(), String> {
+        log::info!("ID Token Issue Time: {issued_time:?}");
+        if issued_time > Utc::now() + self.skew() {
+            return Err(format!(
+                "id_token_policy.clock_skew: issued in the future ({issued_time})."
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_auth_time(&self, time: Option<DateTime<Utc>>) -> Result<(), String> {
+        if let Some(time) = time {
+            log::info!("User authenticated at: {time:?}");
+        }
+        let Some(max_age) = self.max_age else {
+            return Ok(());
+        };
+        let time = time.ok_or("id_token_policy.max_age: auth_time missing.")?;
+        let elapsed = Utc::now() - time;
+        if elapsed > TimeDelta::seconds(max_age as i64) + self.skew() {
+            return Err(format!(
+                "id_token_policy.max_age: authenticated {}s ago, more than {max_age}s.",
+                elapsed.num_seconds()
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_acr(&self, acr: Option<&AuthenticationContextClass>) -> Result<(), String> {
+        if self.acr_values.is_empty() {
+            return Ok(());
+        }
+        match acr {
+            Some(acr) if self.acr_values.iter().any(|value| value == acr.as_str()) => Ok(()),
+            Some(acr) => Err(format!(
+                "id_token_policy.acr_values: {} is not accepted.",
+                acr.as_str()
+            )),
+            None => Err("id_token_policy.acr_values: acr missing.".into()),
+        }
+    }
+
+    fn trusts_audience(&self, audience: &Audience) -> bool {
+        self.trusted_audiences
+            .iter()
+            .any(|value| value == audience.as_str())
+    }
+}
+
+/// The JOSE name of `alg`, e.g. `RS256`.
+pub fn alg_name(alg: &CoreJwsSigningAlgorithm) -> String {
+    serde_json::to_value(alg)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_else(|| format!("{alg:?}"))
+}
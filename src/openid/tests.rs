@@ -0,0 +1,550 @@
+use chrono::Utc;
+use http::{HeaderValue, StatusCode};
+use oauth2::AccessToken;
+use openidconnect::{IssuerUrl, core::CoreIdTokenClaims};
+use serde_json::{Value, json};
+
+use crate::http_client::{HttpClient, reqwest::Reqwest};
+use crate::interface::mock::{Mock, Route, json_response};
+use crate::oauth2::error::{ErrorCodes, OAuth2Result};
+use crate::openid::access_token::AccessTokenClaims;
+use crate::openid::cache::{CacheStats, ProviderCache};
+use crate::openid::pinned::PinnedKeys;
+use crate::openid::{ApplicationNonce, verify_access_token, verify_id_token};
+use crate::test_support::auth_server::{AuthServer, RESOURCE_AUDIENCE};
+use crate::test_support::keys::SigningAlgorithm;
+
+fn network_interface() -> Mock {
+    Mock::new().with_http_client(HttpClient::Reqwest(Reqwest::default()))
+}
+
+#[tokio::test]
+async fn test_discovery_and_jwks_are_cached() {
+    let server = AuthServer::start().await;
+    let cache = ProviderCache::new();
+
+    for _ in 0..3 {
+        let mut provider = server.provider();
+        provider.id_token =
+            Some(serde_json::from_value(server.id_token(None, None).into()).unwrap());
+        verify_id_token(
+            provider,
+            ApplicationNonce::new(),
+            None,
+            &cache,
+            network_interface(),
+        )
+        .await
+        .unwrap();
+    }
+
+    assert_eq!(server.hits("/.well-known/openid-configuration"), 1);
+    assert_eq!(server.hits("/jwks"), 1);
+    assert_eq!(
+        cache.stats(),
+        CacheStats {
+            entries: 1,
+            hits: 2,
+            misses: 1,
+            ..Default::default()
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_jwks_refetched_once_on_key_rotation() {
+    let server = AuthServer::start().await;
+    server.set_signing_algorithm(SigningAlgorithm::Rs256);
+    let cache = ProviderCache::new();
+
+    let mut provider = server.provider();
+    provider.id_token = Some(serde_json::from_value(server.id_token(None, None).into()).unwrap());
+    verify_id_token(
+        provider,
+        ApplicationNonce::new(),
+        None,
+        &cache,
+        network_interface(),
+    )
+    .await
+    .unwrap();
+
+    server.rotate_keys();
+    let mut provider = server.provider();
+    provider.id_token = Some(serde_json::from_value(server.id_token(None, None).into()).unwrap());
+    verify_id_token(
+        provider,
+        ApplicationNonce::new(),
+        None,
+        &cache,
+        network_interface(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(server.hits("/jwks"), 2);
+
+    // Another unknown key right after a refetch must not hit the provider again.
+    let forged = server.sign(
+        SigningAlgorithm::Rs256,
+        json!({"kid": "unknown-kid"}),
+        &json!({
+            "iss": server.issuer(),
+            "aud": "mock-client",
+            "sub": "user-1",
+            "iat": Utc::now().timestamp(),
+            "exp": Utc::now().timestamp() + 60,
+        }),
+    );
+    let mut provider = server.provider();
+    provider.id_token = Some(serde_json::from_value(forged.into()).unwrap());
+    let result = verify_id_token(
+        provider,
+        ApplicationNonce::new(),
+        None,
+        &cache,
+        network_interface(),
+    )
+    .await;
+
+    assert_eq!(
+        result.unwrap_err().error_code,
+        ErrorCodes::ClaimsVerificationError
+    );
+    assert_eq!(server.hits("/jwks"), 2);
+    assert_eq!(cache.stats().jwks_refetches, 1);
+    assert_eq!(cache.stats().rate_limited, 1);
+}
+
+#[tokio::test]
+async fn test_stale_metadata_served_when_provider_unreachable() {
+    let issuer = "https://issuer.example.com";
+    let discovery = json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{issuer}/authorize"),
+        "jwks_uri": format!("{issuer}/jwks"),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["RS256"],
+    })
+    .to_string();
+    let mut no_cache = json_response(StatusCode::OK, &discovery);
+    no_cache
+        .headers_mut()
+        .insert("cache-control", HeaderValue::from_static("no-cache"));
+
+    let interface = Mock::new()
+        .route(
+            Route::get("/.well-known/openid-configuration")
+                .respond(no_cache)
+                .respond_json(StatusCode::SERVICE_UNAVAILABLE, "{}"),
+        )
+        .route(Route::get("/jwks").respond_json(StatusCode::OK, r#"{"keys":[]}"#));
+    let cache = ProviderCache::new();
+    let issuer = IssuerUrl::new(issuer.into()).unwrap();
+
+    cache
+        .metadata(&issuer, None, interface.clone())
+        .await
+        .unwrap();
+    let metadata = cache
+        .metadata(&issuer, None, interface.clone())
+        .await
+        .unwrap();
+
+    assert_eq!(metadata.issuer(), &issuer);
+    assert_eq!(interface.requests_to("/.well-known").len(), 2);
+    assert_eq!(cache.stats().stale_served, 1);
+    assert_eq!(cache.stats().errors, 1);
+}
+
+#[tokio::test]
+async fn test_discovery_failure_without_cache_is_an_error() {
+    let interface = Mock::new().route(
+        Route::get("/.well-known/openid-configuration").respond_json(StatusCode::NOT_FOUND, "{}"),
+    );
+    let cache = ProviderCache::new();
+    let issuer = IssuerUrl::new("https://issuer.example.com".into()).unwrap();
+
+    let result = cache.metadata(&issuer, None, interface).await;
+
+    assert_eq!(result.unwrap_err().error_code, ErrorCodes::DiscoveryError);
+    assert_eq!(cache.stats().errors, 1);
+}
+
+async fn verify_with_policy(
+    server: &AuthServer,
+    id_token: String,
+    policy: Value,
+    access_token: Option<&str>,
+) -> OAuth2Result<CoreIdTokenClaims> {
+    let mut provider = server.provider();
+    provider.id_token = Some(serde_json::from_value(id_token.into()).unwrap());
+    provider.id_token_policy = Some(serde_json::from_value(policy).unwrap());
+    verify_id_token(
+        provider,
+        ApplicationNonce::new(),
+        access_token
+            .map(|token| AccessToken::new(token.into()))
+            .as_ref(),
+        &ProviderCache::new(),
+        network_interface(),
+    )
+    .await
+}
+
+fn assert_violates(result: OAuth2Result<CoreIdTokenClaims>, rule: &str) {
+    let error = result.unwrap_err();
+    assert_eq!(error.error_code, ErrorCodes::ClaimsVerificationError);
+    assert!(
+        error
+            .error_code_desc
+            .contains(&format!("id_token_policy.{rule}")),
+        "{}",
+        error.error_code_desc
+    );
+}
+
+#[tokio::test]
+async fn test_policy_allowed_algorithms() {
+    let server = AuthServer::start().await;
+    server.set_signing_algorithm(SigningAlgorithm::Es256);
+    let id_token = server.id_token(None, None);
+
+    let result = verify_with_policy(&server, id_token.clone(), json!({}), None).await;
+    assert_violates(result, "allowed_algorithms");
+
+    let policy = json!({"allowed_algorithms": ["RS256", "ES256"]});
+    verify_with_policy(&server, id_token, policy, None)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_policy_clock_skew() {
+    let server = AuthServer::start().await;
+    let now = Utc::now().timestamp();
+    server.set_id_token_claims(json!({"iat": now + 120, "exp": now + 600}));
+    let id_token = server.id_token(None, None);
+
+    let result = verify_with_policy(&server, id_token.clone(), json!({}), None).await;
+    assert_violates(result, "clock_skew");
+
+    verify_with_policy(&server, id_token, json!({"clock_skew": 300}), None)
+        .await
+        .unwrap();
+
+    // Expired within the leeway.
+    server.set_id_token_claims(json!({"iat": now - 600, "exp": now - 30}));
+    verify_with_policy(&server, server.id_token(None, None), json!({}), None)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_policy_max_age() {
+    let server = AuthServer::start().await;
+    server.set_id_token_claims(json!({"auth_time": Utc::now().timestamp() - 600}));
+    let id_token = server.id_token(None, None);
+
+    let result = verify_with_policy(&server, id_token.clone(), json!({"max_age": 300}), None).await;
+    assert_violates(result, "max_age");
+
+    verify_with_policy(&server, id_token, json!({"max_age": 900}), None)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_policy_acr_and_amr() {
+    let server = AuthServer::start().await;
+    server.set_id_token_claims(json!({"acr": "urn:mock:loa:1", "amr": ["pwd"]}));
+    let id_token = server.id_token(None, None);
+
+    let policy = json!({"acr_values": ["urn:mock:loa:2"]});
+    let result = verify_with_policy(&server, id_token.clone(), policy, None).await;
+    assert_violates(result, "acr_values");
+
+    let policy = json!({"acr_values": ["urn:mock:loa:1"], "required_amr": ["mfa"]});
+    let result = verify_with_policy(&server, id_token, policy.clone(), None).await;
+    assert_violates(result, "required_amr");
+
+    server.set_id_token_claims(json!({"acr": "urn:mock:loa:1", "amr": ["pwd", "mfa"]}));
+    verify_with_policy(&server, server.id_token(None, None), policy, None)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_policy_trusted_audiences_and_azp() {
+    let server = AuthServer::start().await;
+    server.set_id_token_claims(json!({"aud": ["mock-client", "api://other"]}));
+    let id_token = server.id_token(None, None);
+
+    let result = verify_with_policy(&server, id_token.clone(), json!({}), None).await;
+    assert_violates(result, "trusted_audiences");
+
+    let policy = json!({"trusted_audiences": ["api://other"]});
+    let result = verify_with_policy(&server, id_token, policy.clone(), None).await;
+    assert_violates(result, "check_azp");
+
+    server
+        .set_id_token_claims(json!({"aud": ["mock-client", "api://other"], "azp": "mock-client"}));
+    verify_with_policy(&server, server.id_token(None, None), policy, None)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_policy_at_hash() {
+    let server = AuthServer::start().await;
+    let id_token = server.id_token(None, Some("access-1"));
+
+    let result = verify_with_policy(&server, id_token.clone(), json!({}), Some("access-2")).await;
+    assert_violates(result, "check_at_hash");
+
+    verify_with_policy(&server, id_token.clone(), json!({}), Some("access-1"))
+        .await
+        .unwrap();
+    verify_with_policy(
+        &server,
+        id_token,
+        json!({"check_at_hash": false}),
+        Some("access-2"),
+    )
+    .await
+    .unwrap();
+}
+
+fn offline_interface() -> Mock {
+    Mock::new().route(Route::get("/offline"))
+}
+
+#[tokio::test]
+async fn test_pinned_jwks_needs_no_network() {
+    let server = AuthServer::start().await;
+    let interface = offline_interface();
+
+    let mut provider = server.provider();
+    provider.id_token = Some(serde_json::from_value(server.id_token(None, None).into()).unwrap());
+    provider.pinned_keys = Some(PinnedKeys {
+        issuer: IssuerUrl::new(server.issuer()).unwrap(),
+        jwks: Some(serde_json::from_value(server.jwks()).unwrap()),
+        jwks_file: None,
+    });
+
+    let claims = verify_id_token(
+        provider,
+        ApplicationNonce::new(),
+        None,
+        &ProviderCache::new(),
+        interface.clone(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(claims.subject().as_str(), "user-1");
+    assert!(interface.requests().is_empty());
+    assert_eq!(server.hits("/jwks"), 0);
+}
+
+#[tokio::test]
+async fn test_pinned_jwks_file() {
+    let server = AuthServer::start().await;
+    let jwks_file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(jwks_file.path(), server.jwks().to_string()).unwrap();
+    let pinned = PinnedKeys {
+        issuer: IssuerUrl::new(server.issuer()).unwrap(),
+        jwks: None,
+        jwks_file: Some(jwks_file.path().to_path_buf()),
+    };
+
+    let mut provider = server.provider();
+    provider.id_token = Some(serde_json::from_value(server.id_token(None, None).into()).unwrap());
+    provider.pinned_keys = Some(pinned.clone());
+    verify_id_token(
+        provider,
+        ApplicationNonce::new(),
+        None,
+        &ProviderCache::new(),
+        offline_interface(),
+    )
+    .await
+    .unwrap();
+
+    // Keys rotated at the provider are unknown until the file is updated.
+    server.rotate_keys();
+    let mut provider = server.provider();
+    provider.id_token = Some(serde_json::from_value(server.id_token(None, None).into()).unwrap());
+    provider.pinned_keys = Some(pinned);
+    let result = verify_id_token(
+        provider,
+        ApplicationNonce::new(),
+        None,
+        &ProviderCache::new(),
+        offline_interface(),
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().error_code,
+        ErrorCodes::ClaimsVerificationError
+    );
+}
+
+#[tokio::test]
+async fn test_pinned_issuer_mismatch() {
+    let server = AuthServer::start().await;
+
+    let mut provider = server.provider();
+    provider.id_token = Some(serde_json::from_value(server.id_token(None, None).into()).unwrap());
+    provider.pinned_keys = Some(PinnedKeys {
+        issuer: IssuerUrl::new("https://issuer.example.com".into()).unwrap(),
+        jwks: Some(serde_json::from_value(server.jwks()).unwrap()),
+        jwks_file: None,
+    });
+
+    let result = verify_id_token(
+        provider,
+        ApplicationNonce::new(),
+        None,
+        &ProviderCache::new(),
+        offline_interface(),
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().error_code,
+        ErrorCodes::ClaimsVerificationError
+    );
+}
+
+async fn verify_access_with_policy(
+    server: &AuthServer,
+    access_token: String,
+    policy: Value,
+) -> OAuth2Result<AccessTokenClaims> {
+    let mut provider = server.provider();
+    provider.access_token = Some(AccessToken::new(access_token));
+    let mut policy = policy;
+    policy["issuer"] = server.issuer().into();
+    policy["audience"] = RESOURCE_AUDIENCE.into();
+    provider.access_token_policy = Some(serde_json::from_value(policy).unwrap());
+    verify_access_token(provider, &ProviderCache::new(), network_interface()).await
+}
+
+fn assert_rejected(result: OAuth2Result<AccessTokenClaims>, reason: &str) {
+    let error = result.unwrap_err();
+    assert_eq!(error.error_code, ErrorCodes::ClaimsVerificationError);
+    assert!(
+        error.error_code_desc.contains(reason),
+        "{}",
+        error.error_code_desc
+    );
+}
+
+#[tokio::test]
+async fn test_verify_access_token() {
+    let server = AuthServer::start().await;
+    let access_token = server.access_token("openid api.read");
+
+    let policy = json!({"required_scopes": ["api.read"]});
+    let claims = verify_access_with_policy(&server, access_token.clone(), policy)
+        .await
+        .unwrap();
+    assert_eq!(claims.sub, "user-1");
+    assert_eq!(claims.client_id.as_deref(), Some("mock-client"));
+
+    let policy = json!({"required_scopes": ["api.write"]});
+    let result = verify_access_with_policy(&server, access_token.clone(), policy).await;
+    assert_rejected(result, "required_scopes");
+
+    let mut provider = server.provider();
+    provider.access_token = Some(AccessToken::new(access_token));
+    provider.access_token_policy = Some(
+        serde_json::from_value(json!({"issuer": server.issuer(), "audience": "api://other"}))
+            .unwrap(),
+    );
+    let result = verify_access_token(provider, &ProviderCache::new(), network_interface()).await;
+    assert_rejected(result, "Invalid audiences");
+}
+
+#[tokio::test]
+async fn test_verify_access_token_claims() {
+    let server = AuthServer::start().await;
+    let now = Utc::now().timestamp();
+    let claims = json!({
+        "iss": server.issuer(),
+        "sub": "user-1",
+        "aud": [RESOURCE_AUDIENCE],
+        "client_id": "mock-client",
+        "roles": ["reader"],
+        "iat": now,
+        "exp": now + 600,
+    });
+    let at_jwt = json!({"typ": "at+jwt"});
+
+    let token = server.sign(SigningAlgorithm::Rs256, at_jwt.clone(), &claims);
+    verify_access_with_policy(
+        &server,
+        token.clone(),
+        json!({"required_roles": ["reader"]}),
+    )
+    .await
+    .unwrap();
+    let result =
+        verify_access_with_policy(&server, token, json!({"required_roles": ["admin"]})).await;
+    assert_rejected(result, "required_roles");
+
+    // `roles` may be a single string.
+    let mut single_role = claims.clone();
+    single_role["roles"] = "reader".into();
+    let token = server.sign(SigningAlgorithm::Rs256, at_jwt.clone(), &single_role);
+    let verified = verify_access_with_policy(&server, token, json!({"required_roles": ["reader"]}))
+        .await
+        .unwrap();
+    assert_eq!(verified.roles, ["reader"]);
+
+    // An ID token is not an access token.
+    let token = server.sign(SigningAlgorithm::Rs256, json!({}), &claims);
+    let result = verify_access_with_policy(&server, token, json!({})).await;
+    assert_rejected(result, "at+jwt");
+
+    let mut expired = claims.clone();
+    expired["exp"] = (now - 120).into();
+    let token = server.sign(SigningAlgorithm::Rs256, at_jwt.clone(), &expired);
+    let result = verify_access_with_policy(&server, token, json!({})).await;
+    assert_rejected(result, "Expired");
+
+    let mut not_yet = claims.clone();
+    not_yet["nbf"] = (now + 600).into();
+    let token = server.sign(SigningAlgorithm::Rs256, at_jwt.clone(), &not_yet);
+    let result = verify_access_with_policy(&server, token, json!({})).await;
+    assert_rejected(result, "not valid before");
+
+    let mut foreign = claims.clone();
+    foreign["iss"] = "https://issuer.example.com".into();
+    let token = server.sign(SigningAlgorithm::Rs256, at_jwt.clone(), &foreign);
+    let result = verify_access_with_policy(&server, token, json!({})).await;
+    assert_rejected(result, "Invalid issuer");
+
+    let token = server.sign(SigningAlgorithm::Es256, at_jwt, &claims);
+    let result = verify_access_with_policy(&server, token.clone(), json!({})).await;
+    assert_rejected(result, "allowed_algorithms");
+    verify_access_with_policy(&server, token, json!({"allowed_algorithms": ["ES256"]}))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_verify_access_token_tampered() {
+    let server = AuthServer::start().await;
+    let access_token = server.access_token("api.read");
+    let (signing_input, _) = access_token.rsplit_once('.').unwrap();
+    let (_, signature) = server
+        .access_token("api.write")
+        .rsplit_once('.')
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .unwrap();
+
+    let result =
+        verify_access_with_policy(&server, format!("{signing_input}.{signature}"), json!({})).await;
+    assert_rejected(result, "signature verification failed");
+}
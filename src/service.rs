@@ -0,0 +1,149 @@
+pub mod broker;
+pub mod health;
+pub mod systemd;
+pub mod worker;
+
+use std::{str::FromStr, time::Duration};
+
+/// Environment variable holding the [`IdlePolicy`].
+pub const IDLE_EXIT_ENV: &str = "AUTH_SERVICE_IDLE_EXIT";
+
+/// Seconds without IPC activity after which the service exits by default.
+const DEFAULT_IDLE_TIMEOUT: u64 = 60;
+
+/// How long a termination signal leaves tokens being saved to finish.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// When the service exits on its own for lack of activity.
+///
+/// Read from `AUTH_SERVICE_IDLE_EXIT`:
+/// - `never`: keeps running until stopped.
+/// - `<seconds>` (default 60): exits after that long without IPC activity,
+///   once no login is being polled, so no approval waits for a restart.
+///   `no-logins:<seconds>` is accepted as well.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdlePolicy {
+    Never,
+    After(Duration),
+}
+
+impl Default for IdlePolicy {
+    fn default() -> Self {
+        IdlePolicy::After(Duration::from_secs(DEFAULT_IDLE_TIMEOUT))
+    }
+}
+
+impl FromStr for IdlePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let seconds = |value: &str| {
+            value
+                .trim()
+                .parse()
+                .map(Duration::from_secs)
+                .map_err(|_| format!("Invalid idle exit policy {value:?}."))
+        };
+        match value.trim() {
+            "never" => Ok(IdlePolicy::Never),
+            value => {
+                seconds(value.strip_prefix("no-logins:").unwrap_or(value)).map(IdlePolicy::After)
+            }
+        }
+    }
+}
+
+impl IdlePolicy {
+    pub fn from_env() -> Self {
+        match std::env::var(IDLE_EXIT_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|err| {
+                log::warn!("{err} Using the default.");
+                IdlePolicy::default()
+            }),
+            Err(_) => IdlePolicy::default(),
+        }
+    }
+
+    /// Inactivity after which exiting is considered.
+    pub fn timeout(&self) -> Option<Duration> {
+        match self {
+            IdlePolicy::Never => None,
+            IdlePolicy::After(timeout) => Some(*timeout),
+        }
+    }
+
+    /// Whether the service may exit once idle, with `polling` logins going on.
+    pub fn allows_exit(&self, polling: usize) -> bool {
+        match self {
+            IdlePolicy::Never => false,
+            IdlePolicy::After(_) => polling == 0,
+        }
+    }
+}
+
+/// Resolves on SIGTERM, as sent by systemd, or on Ctrl-C.
+pub async fn termination_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => log::info!("SIGTERM received."),
+                    _ = tokio::signal::ctrl_c() => log::info!("SIGINT received."),
+                }
+                return;
+            }
+            Err(err) => log::error!("SIGTERM handler not installed: {err}"),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+    log::info!("Ctrl-C received.");
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+    use crate::interface::mock::Mock;
+    use crate::task_manager::TaskManager;
+
+    #[test]
+    fn test_idle_policy_parsing() {
+        assert_eq!("never".parse(), Ok(IdlePolicy::Never));
+        assert_eq!(
+            "300".parse(),
+            Ok(IdlePolicy::After(Duration::from_secs(300)))
+        );
+        assert_eq!(
+            "no-logins:30".parse(),
+            Ok(IdlePolicy::After(Duration::from_secs(30)))
+        );
+        assert!("soon".parse::<IdlePolicy>().is_err());
+        assert_eq!(
+            IdlePolicy::default(),
+            IdlePolicy::After(Duration::from_secs(60))
+        );
+
+        assert!(!IdlePolicy::Never.allows_exit(0));
+        // A pending login keeps the service running.
+        assert!(!IdlePolicy::After(Duration::ZERO).allows_exit(1));
+        assert!(IdlePolicy::After(Duration::ZERO).allows_exit(0));
+    }
+
+    #[tokio::test]
+    async fn test_task_manager_follows_idle_policy() {
+        let (_tx, rx) = unbounded_channel();
+        let mut task_manager =
+            TaskManager::new(rx).with_idle_policy(IdlePolicy::After(Duration::from_millis(100)));
+        tokio::time::timeout(Duration::from_secs(5), task_manager.run(Mock::new()))
+            .await
+            .expect("the task manager did not exit when idle");
+
+        let (_tx, rx) = unbounded_channel();
+        let mut task_manager = TaskManager::new(rx).with_idle_policy(IdlePolicy::Never);
+        let run = tokio::time::timeout(Duration::from_millis(500), task_manager.run(Mock::new()));
+        assert!(run.await.is_err());
+    }
+}
@@ -0,0 +1,496 @@
+// The connection to the IPC broker, kept up across broker restarts: events
+// are queued while the broker is away, those it may have missed are queued
+// again, and the worker registers again once it is back.
+
+use std::{collections::VecDeque, io, sync::Arc, time::Duration};
+
+use ipc_broker::{client::IPCClient, worker::SharedObject};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{Mutex, Notify, watch};
+
+use super::worker;
+use crate::oauth2::{pending::unix_now, session::EVENT_OBJECT};
+
+/// How often the connection is checked. Publishing is fire-and-forget and
+/// succeeds on a dead connection, so the events published since the last
+/// check are kept until the next one, and queued again if it fails.
+const PROBE_INTERVAL: Duration = Duration::from_secs(2);
+/// A broker answering slower than this is taken as up, only busy.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// Events kept while disconnected; the oldest are dropped first.
+const MAX_QUEUED_EVENTS: usize = 1000;
+
+/// Exponentially growing delay between reconnection attempts.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(500), Duration::from_secs(30))
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    /// Delay before the next attempt, doubled on every call up to the maximum.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct QueuedEvent {
+    object: String,
+    event: String,
+    result: Value,
+}
+
+/// Events waiting for the broker, oldest first.
+struct EventQueue {
+    events: VecDeque<QueuedEvent>,
+    capacity: usize,
+    dropped: u64,
+}
+
+impl EventQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::new(),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, event: QueuedEvent) {
+        self.events.push_back(event);
+        self.trim();
+    }
+
+    /// Queues `events`, published before any queued event, ahead of them.
+    fn requeue(&mut self, events: Vec<QueuedEvent>) {
+        for event in events.into_iter().rev() {
+            self.events.push_front(event);
+        }
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.events.len() > self.capacity {
+            if let Some(dropped) = self.events.pop_front() {
+                log::warn!("Event queue full, dropping {}.", dropped.event);
+            }
+            self.dropped += 1;
+        }
+    }
+}
+
+/// Events published since the broker last answered a probe, which it may
+/// not have received. Published again after a reconnection, so a
+/// subscriber may see one twice.
+#[derive(Default)]
+struct Unconfirmed {
+    /// Events with their sequence numbers, oldest first.
+    events: VecDeque<(u64, QueuedEvent)>,
+    next: u64,
+}
+
+impl Unconfirmed {
+    fn push(&mut self, event: QueuedEvent) {
+        if self.events.len() >= MAX_QUEUED_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back((self.next, event));
+        self.next += 1;
+    }
+
+    /// Marks the events published so far, which a probe sent now follows.
+    fn mark(&self) -> u64 {
+        self.next
+    }
+
+    /// Forgets the events before `mark`, the broker having answered since.
+    fn confirm(&mut self, mark: u64) {
+        while self.events.front().is_some_and(|(seq, _)| *seq < mark) {
+            self.events.pop_front();
+        }
+    }
+
+    fn take(&mut self) -> Vec<QueuedEvent> {
+        self.events.drain(..).map(|(_, event)| event).collect()
+    }
+}
+
+/// The state of the broker connection, as reported by the `health` method.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct BrokerHealth {
+    pub connected: bool,
+    /// Unix time in seconds of the last connection or disconnection.
+    pub since: u64,
+    /// Times the connection came back after being lost.
+    pub reconnects: u64,
+    /// Attempts failed since the connection was lost.
+    pub failed_attempts: u32,
+    /// Events waiting to be published.
+    pub queued_events: usize,
+    /// Events lost because too many were waiting.
+    pub dropped_events: u64,
+    pub last_error: Option<String>,
+}
+
+struct State {
+    client: Option<IPCClient>,
+    /// Counts connections, so a failure seen on an old one is not taken for
+    /// a failure of the current one.
+    generation: u64,
+    queue: EventQueue,
+    unconfirmed: Unconfirmed,
+    health: BrokerHealth,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    connected: watch::Sender<bool>,
+    lost: Notify,
+}
+
+/// A broker connection shared by the event publisher and the worker.
+#[derive(Clone)]
+pub struct BrokerConnection {
+    inner: Arc<Inner>,
+}
+
+/// Whether the broker still answers on `client`.
+async fn probe(client: &IPCClient) -> io::Result<()> {
+    match tokio::time::timeout(PROBE_TIMEOUT, client.wait_for_object(EVENT_OBJECT)).await {
+        Ok(result) => result,
+        // Answering, only without the object registered yet.
+        Err(_) => Ok(()),
+    }
+}
+
+impl BrokerConnection {
+    /// Starts connecting in the background. Whenever the broker goes away,
+    /// reconnection is retried with a [`Backoff`].
+    pub fn start() -> Self {
+        let (connected, _) = watch::channel(false);
+        let connection = Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    client: None,
+                    generation: 0,
+                    queue: EventQueue::new(MAX_QUEUED_EVENTS),
+                    unconfirmed: Unconfirmed::default(),
+                    health: BrokerHealth::default(),
+                }),
+                connected,
+                lost: Notify::new(),
+            }),
+        };
+        tokio::spawn(connection.clone().supervise());
+        connection
+    }
+
+    /// Follows whether the broker is connected.
+    pub fn watch(&self) -> watch::Receiver<bool> {
+        self.inner.connected.subscribe()
+    }
+
+    pub async fn health(&self) -> BrokerHealth {
+        let state = self.inner.state.lock().await;
+        BrokerHealth {
+            queued_events: state.queue.events.len(),
+            dropped_events: state.queue.dropped,
+            ..state.health.clone()
+        }
+    }
+
+    /// Publishes an event, or queues it until the broker is back.
+    pub async fn publish(&self, object: &str, event: &str, result: &Value) -> io::Result<()> {
+        let queued = QueuedEvent {
+            object: object.to_string(),
+            event: event.to_string(),
+            result: result.clone(),
+        };
+        loop {
+            let (client, generation) = {
+                let mut state = self.inner.state.lock().await;
+                match &state.client {
+                    Some(client) => (client.clone(), state.generation),
+                    None => {
+                        log::debug!("Broker disconnected, queueing {event}.");
+                        state.queue.push(queued);
+                        return Ok(());
+                    }
+                }
+            };
+            // Not holding the state, so a slow broker delays no other event.
+            let published = client.publish(object, event, result).await;
+            let mut state = self.inner.state.lock().await;
+            match published {
+                // Sent on a connection lost meanwhile: sent again on the next.
+                Ok(()) if state.generation != generation => continue,
+                Ok(()) => {
+                    state.unconfirmed.push(queued);
+                    return Ok(());
+                }
+                Err(err) => self.lost(&mut state, generation, err),
+            }
+        }
+    }
+
+    /// Waits until `object` is registered on the broker.
+    pub async fn wait_for_object(&self, object: &str) {
+        let mut connected = self.watch();
+        loop {
+            if !until(&mut connected, true).await {
+                return;
+            }
+            let (client, generation) = {
+                let state = self.inner.state.lock().await;
+                match &state.client {
+                    Some(client) => (client.clone(), state.generation),
+                    None => continue,
+                }
+            };
+            match client.wait_for_object(object).await {
+                Ok(()) => return,
+                Err(err) => {
+                    let mut state = self.inner.state.lock().await;
+                    self.lost(&mut state, generation, err);
+                }
+            }
+        }
+    }
+
+    fn lost(&self, state: &mut State, generation: u64, err: io::Error) {
+        if state.generation != generation || state.client.is_none() {
+            return;
+        }
+        log::warn!("Lost the connection to the IPC broker: {err}");
+        let unconfirmed = state.unconfirmed.take();
+        if !unconfirmed.is_empty() {
+            log::info!(
+                "Queueing {} events the broker may have missed.",
+                unconfirmed.len()
+            );
+        }
+        state.queue.requeue(unconfirmed);
+        state.client = None;
+        state.health.connected = false;
+        state.health.since = unix_now();
+        state.health.last_error = Some(err.to_string());
+        self.inner.connected.send_replace(false);
+        self.inner.lost.notify_one();
+    }
+
+    async fn connected(&self, client: IPCClient) {
+        let mut state = self.inner.state.lock().await;
+        let queued: Vec<_> = state.queue.events.drain(..).collect();
+        for QueuedEvent {
+            object,
+            event,
+            result,
+        } in &queued
+        {
+            if let Err(err) = client.publish(object, event, result).await {
+                log::error!("Failed to publish queued {event}: {err}");
+            }
+        }
+        log::info!(
+            "Connected to the IPC broker, {} queued events published.",
+            queued.len()
+        );
+
+        if state.generation > 0 {
+            state.health.reconnects += 1;
+        }
+        state.generation += 1;
+        state.client = Some(client);
+        state.health.connected = true;
+        state.health.since = unix_now();
+        state.health.failed_attempts = 0;
+        self.inner.connected.send_replace(true);
+    }
+
+    async fn supervise(self) {
+        let mut backoff = Backoff::default();
+        loop {
+            let current = {
+                let state = self.inner.state.lock().await;
+                state
+                    .client
+                    .clone()
+                    .map(|client| (client, state.generation))
+            };
+            match current {
+                Some((client, generation)) => {
+                    tokio::select! {
+                        _ = self.inner.lost.notified() => {}
+                        _ = tokio::time::sleep(PROBE_INTERVAL) => {
+                            let mark = self.inner.state.lock().await.unconfirmed.mark();
+                            let probed = probe(&client).await;
+                            let mut state = self.inner.state.lock().await;
+                            match probed {
+                                Ok(()) if state.generation == generation => {
+                                    state.unconfirmed.confirm(mark);
+                                }
+                                Ok(()) => {}
+                                Err(err) => self.lost(&mut state, generation, err),
+                            }
+                        }
+                    }
+                }
+                None => match IPCClient::connect().await {
+                    Ok(client) => {
+                        backoff.reset();
+                        self.connected(client).await;
+                    }
+                    Err(err) => {
+                        let delay = backoff.next_delay();
+                        log::warn!("IPC broker unreachable: {err}. Retrying in {delay:?}.");
+                        {
+                            let mut state = self.inner.state.lock().await;
+                            state.health.failed_attempts += 1;
+                            state.health.last_error = Some(err.to_string());
+                        }
+                        tokio::time::sleep(delay).await;
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Waits for the connection to be `state`; false once it can't change any more.
+async fn until(connected: &mut watch::Receiver<bool>, state: bool) -> bool {
+    connected
+        .wait_for(|connected| *connected == state)
+        .await
+        .is_ok()
+}
+
+/// Serves `object` on the broker until `stop` changes, registering it again
+/// whenever the connection comes back.
+pub async fn serve<T>(
+    connection: BrokerConnection,
+    name: &str,
+    object: T,
+    mut stop: watch::Receiver<bool>,
+) -> io::Result<()>
+where
+    T: SharedObject + 'static,
+{
+    let object = Arc::new(object);
+    let mut connected = connection.watch();
+    let mut backoff = Backoff::default();
+    loop {
+        tokio::select! {
+            open = until(&mut connected, true) => {
+                if !open {
+                    return Ok(());
+                }
+            }
+            _ = stop.changed() => return Ok(()),
+        }
+
+        let result = match worker::connect().await {
+            Ok(stream) => {
+                backoff.reset();
+                worker::run(stream, name, object.clone(), &mut stop).await
+            }
+            Err(err) => Err(err),
+        };
+        // `Ok` once stopped; the connection failed otherwise.
+        let Err(err) = result else {
+            return Ok(());
+        };
+        let delay = backoff.next_delay();
+        log::warn!("Serving {name} stopped: {err}. Registering it again in {delay:?}.");
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = stop.changed() => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(500));
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 500, 500]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_event_queue_drops_oldest() {
+        let event = |name: &str| QueuedEvent {
+            object: EVENT_OBJECT.to_string(),
+            event: name.to_string(),
+            result: Value::Null,
+        };
+        let mut queue = EventQueue::new(2);
+        queue.push(event("login.pending"));
+        queue.push(event("login.completed"));
+        queue.push(event("token.ready"));
+
+        assert_eq!(queue.dropped, 1);
+        assert_eq!(
+            queue.events,
+            [event("login.completed"), event("token.ready")]
+        );
+    }
+
+    #[test]
+    fn test_unconfirmed_events_are_queued_again_in_order() {
+        let event = |name: &str| QueuedEvent {
+            object: EVENT_OBJECT.to_string(),
+            event: name.to_string(),
+            result: Value::Null,
+        };
+        let mut unconfirmed = Unconfirmed::default();
+        unconfirmed.push(event("login.pending"));
+        let mark = unconfirmed.mark();
+        // Published while the probe is on its way.
+        unconfirmed.push(event("login.completed"));
+        unconfirmed.confirm(mark);
+        unconfirmed.push(event("token.ready"));
+
+        // The connection is lost; an event raised since waits behind them.
+        let mut queue = EventQueue::new(10);
+        queue.push(event("login.failed"));
+        queue.requeue(unconfirmed.take());
+        assert_eq!(
+            queue.events,
+            [
+                event("login.completed"),
+                event("token.ready"),
+                event("login.failed")
+            ]
+        );
+        assert!(unconfirmed.take().is_empty());
+    }
+}
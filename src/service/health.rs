@@ -0,0 +1,320 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use chrono::{DateTime, Utc};
+use http::{Method, Request, header::DATE};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc::UnboundedSender, oneshot};
+
+use crate::{
+    interface::Interface,
+    oauth2::{
+        device_code_flow::stored_sessions,
+        error::{ErrorCodes, OAuth2Error, OAuth2Result},
+        pending::unix_now,
+        provider::InputParameters,
+    },
+    openid::cache::{CacheStats, ProviderCache},
+    service::broker::BrokerHealth,
+    task_manager::TaskMessage,
+};
+
+/// Seconds of clock difference with a provider above which `diagnose` warns:
+/// the default leeway of ID token and access token validation.
+const MAX_CLOCK_SKEW: i64 = 60;
+
+/// The last error a provider returned to a call.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProviderError {
+    pub method: String,
+    /// Unix time in seconds.
+    pub at: u64,
+    pub error: OAuth2Error,
+}
+
+/// The last error of each provider, by provider name.
+#[derive(Clone, Default)]
+pub struct ProviderErrors(Arc<Mutex<BTreeMap<String, ProviderError>>>);
+
+impl ProviderErrors {
+    pub fn record(&self, provider: &str, method: &str, error: &OAuth2Error) {
+        self.0.lock().unwrap().insert(
+            provider.to_string(),
+            ProviderError {
+                method: method.to_string(),
+                at: unix_now(),
+                error: error.clone(),
+            },
+        );
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, ProviderError> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// What the `status` and `health` methods report.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceStatus {
+    pub version: String,
+    pub uptime_seconds: u64,
+    pub http_backend: String,
+    pub broker: BrokerHealth,
+    /// Logins being polled.
+    pub polling_tasks: usize,
+    /// Tokens stored, one per process and provider.
+    pub stored_sessions: usize,
+    pub cache: CacheStats,
+    pub provider_errors: BTreeMap<String, ProviderError>,
+}
+
+pub async fn status<I: Interface>(
+    interface: &I,
+    tx: &UnboundedSender<TaskMessage>,
+    started: Instant,
+    cache: &ProviderCache,
+    errors: &ProviderErrors,
+) -> OAuth2Result<ServiceStatus> {
+    let (oneshot_tx, oneshot_rx) = oneshot::channel();
+    tx.send(TaskMessage::PollingTasks(oneshot_tx))?;
+    let polling_tasks = oneshot_rx.await.map_err(|_| {
+        OAuth2Error::new(
+            ErrorCodes::ChannelError,
+            "The task manager is not running.".into(),
+        )
+    })?;
+
+    Ok(ServiceStatus {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_seconds: started.elapsed().as_secs(),
+        http_backend: interface.http_backend(),
+        broker: interface.broker_health().await,
+        polling_tasks,
+        stored_sessions: stored_sessions(&interface.token_directory()),
+        cache: cache.stats(),
+        provider_errors: errors.snapshot(),
+    })
+}
+
+/// How one of the provider's endpoints answered.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EndpointCheck {
+    /// The parameter the endpoint was given in, e.g. `token_endpoint`.
+    pub endpoint: String,
+    pub url: String,
+    /// Whether any HTTP response came back, whatever its status.
+    pub reachable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    pub latency_ms: u64,
+    /// The endpoint's clock minus the local one, from its `Date` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock_skew_seconds: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<OAuth2Error>,
+}
+
+/// What the `diagnose` method reports.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Diagnosis {
+    pub endpoints: Vec<EndpointCheck>,
+    /// The largest clock difference found, if any endpoint sent a `Date`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock_skew_seconds: Option<i64>,
+    /// Whether the local clock is off by enough to fail token validation.
+    pub clock_skewed: bool,
+}
+
+/// `date`, an HTTP `Date` header, minus `local`, in seconds.
+fn clock_skew(date: &str, local: DateTime<Utc>) -> Option<i64> {
+    let date = DateTime::parse_from_rfc2822(date).ok()?;
+    Some((date.with_timezone(&Utc) - local).num_seconds())
+}
+
+async fn check_endpoint<I: Interface>(interface: &I, endpoint: &str, url: &str) -> EndpointCheck {
+    let mut check = EndpointCheck {
+        endpoint: endpoint.to_string(),
+        url: url.to_string(),
+        reachable: false,
+        status: None,
+        latency_ms: 0,
+        clock_skew_seconds: None,
+        error: None,
+    };
+    let request = match Request::builder()
+        .method(Method::GET)
+        .uri(url)
+        .body(Vec::new())
+    {
+        Ok(request) => request,
+        Err(err) => {
+            check.error = Some(err.into());
+            return check;
+        }
+    };
+
+    let sent = Utc::now();
+    let started = Instant::now();
+    let result = interface.http_request(request).await;
+    let elapsed = started.elapsed();
+    check.latency_ms = elapsed.as_millis() as u64;
+    match result {
+        Ok(response) => {
+            check.reachable = true;
+            check.status = Some(response.status().as_u16());
+            // The provider stamped its response about halfway through.
+            let local = sent + elapsed / 2;
+            check.clock_skew_seconds = response
+                .headers()
+                .get(DATE)
+                .and_then(|date| date.to_str().ok())
+                .and_then(|date| clock_skew(date, local));
+        }
+        Err(err) => check.error = Some(err),
+    }
+    check
+}
+
+/// Checks that the provider's endpoints answer, and compares their clock
+/// with the local one.
+pub async fn diagnose<I: Interface>(
+    param: &InputParameters,
+    interface: &I,
+) -> OAuth2Result<Diagnosis> {
+    log::trace!("diagnose({:?})", param.provider);
+
+    let endpoints = [
+        (
+            "authorization_endpoint",
+            param
+                .authorization_endpoint
+                .as_ref()
+                .map(|url| url.as_str()),
+        ),
+        (
+            "device_auth_endpoint",
+            param.device_auth_endpoint.as_ref().map(|url| url.as_str()),
+        ),
+        (
+            "token_endpoint",
+            param.token_endpoint.as_ref().map(|url| url.as_str()),
+        ),
+    ];
+    let mut checks = Vec::new();
+    for (endpoint, url) in endpoints {
+        if let Some(url) = url {
+            checks.push(check_endpoint(interface, endpoint, url).await);
+        }
+    }
+    if checks.is_empty() {
+        return Err(OAuth2Error::new(
+            ErrorCodes::InvalidParameters,
+            "No endpoint supplied.".into(),
+        ));
+    }
+
+    let clock_skew_seconds = checks
+        .iter()
+        .filter_map(|check| check.clock_skew_seconds)
+        .max_by_key(|skew| skew.abs());
+    Ok(Diagnosis {
+        endpoints: checks,
+        clock_skew_seconds,
+        clock_skewed: clock_skew_seconds.is_some_and(|skew| skew.abs() > MAX_CLOCK_SKEW),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderValue, StatusCode};
+    use serde_json::json;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+    use crate::interface::mock::{Mock, Route, json_response};
+    use crate::task_manager::TaskManager;
+
+    #[test]
+    fn test_clock_skew_from_date_header() {
+        let local = DateTime::parse_from_rfc3339("2026-10-18T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            clock_skew("Sun, 18 Oct 2026 12:05:00 GMT", local),
+            Some(300)
+        );
+        assert_eq!(
+            clock_skew("Sun, 18 Oct 2026 11:59:30 GMT", local),
+            Some(-30)
+        );
+        assert_eq!(clock_skew("yesterday", local), None);
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_reports_reachability_and_skew() {
+        let date = (Utc::now() + chrono::TimeDelta::hours(1)).to_rfc2822();
+        let mut response = json_response(StatusCode::METHOD_NOT_ALLOWED, "{}");
+        response
+            .headers_mut()
+            .insert(DATE, HeaderValue::from_str(&date).unwrap());
+        let interface = Mock::new().route(Route::get("/token").respond(response));
+        let param: InputParameters = serde_json::from_value(json!({
+            "provider": "Example",
+            "token_endpoint": "https://example.com/token",
+            "device_auth_endpoint": "https://example.com/devicecode",
+        }))
+        .unwrap();
+
+        let diagnosis = diagnose(&param, &interface).await.unwrap();
+        let device = &diagnosis.endpoints[0];
+        assert_eq!(device.endpoint, "device_auth_endpoint");
+        assert!(!device.reachable);
+        assert!(device.error.is_some());
+        let token = &diagnosis.endpoints[1];
+        assert!(token.reachable);
+        assert_eq!(token.status, Some(405));
+        let skew = diagnosis.clock_skew_seconds.unwrap();
+        assert!((3590..=3610).contains(&skew));
+        assert!(diagnosis.clock_skewed);
+
+        let param: InputParameters = serde_json::from_value(json!({})).unwrap();
+        assert!(diagnose(&param, &interface).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_status_reports_service_state() {
+        let (tx, rx) = unbounded_channel();
+        let interface = Mock::new();
+        let errors = ProviderErrors::default();
+        errors.record(
+            "Example",
+            "requestToken",
+            &OAuth2Error::new(ErrorCodes::InvalidGrant, "Token revoked.".into()),
+        );
+
+        let manager = interface.clone();
+        tokio::spawn(async move { TaskManager::new(rx).run(manager).await });
+
+        let status = status(
+            &interface,
+            &tx,
+            Instant::now(),
+            &ProviderCache::new(),
+            &errors,
+        )
+        .await
+        .unwrap();
+        assert_eq!(status.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(status.http_backend, "Mock");
+        assert!(status.broker.connected);
+        assert_eq!(status.polling_tasks, 0);
+        assert_eq!(status.stored_sessions, 0);
+        assert_eq!(
+            status.provider_errors["Example"].error.error_code,
+            ErrorCodes::InvalidGrant
+        );
+    }
+}
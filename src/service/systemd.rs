@@ -0,0 +1,45 @@
+// Service manager notifications. Everything here is a no-op unless the
+// service runs under systemd with `Type=notify`.
+
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use sd_notify::NotifyState;
+
+#[cfg(target_os = "linux")]
+fn notify(state: &[NotifyState]) {
+    if let Err(err) = sd_notify::notify(false, state) {
+        log::warn!("sd_notify failed: {err}");
+    }
+}
+
+/// Tells systemd the service is up, i.e. its object is on the broker.
+pub fn ready() {
+    #[cfg(target_os = "linux")]
+    notify(&[NotifyState::Ready, NotifyState::Status("Serving")]);
+}
+
+/// Tells systemd the service is shutting down.
+pub fn stopping(reason: &str) {
+    #[cfg(target_os = "linux")]
+    notify(&[NotifyState::Stopping, NotifyState::Status(reason)]);
+    #[cfg(not(target_os = "linux"))]
+    let _ = reason;
+}
+
+pub fn watchdog() {
+    #[cfg(target_os = "linux")]
+    notify(&[NotifyState::Watchdog]);
+}
+
+/// How often to ping the watchdog: half its timeout, as systemd advises,
+/// or `None` when no watchdog is set up.
+pub fn watchdog_interval() -> Option<Duration> {
+    #[cfg(target_os = "linux")]
+    {
+        let mut usec = 0;
+        sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec / 2))
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
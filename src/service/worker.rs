@@ -0,0 +1,251 @@
+// Serves a shared object on the IPC broker. Unlike ipc-broker's own worker,
+// which awaits every call before reading the next, calls run side by side,
+// so a long one such as `awaitLogin` holds up no other.
+
+use std::{io, sync::Arc, time::Duration};
+
+use ipc_broker::{
+    broker::{read_packet, write_packet},
+    rpc::{RpcRequest, RpcResponse},
+    worker::SharedObject,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, watch},
+    task::JoinSet,
+};
+
+/// How long calls in progress may finish once the worker is stopped.
+const CALL_GRACE: Duration = Duration::from_secs(5);
+
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+/// Opens a connection to the broker the way `IPCClient` does: over TCP to
+/// `BROKER_ADDR` if set, otherwise over the local socket or pipe.
+pub async fn connect() -> io::Result<Box<dyn Stream>> {
+    if let Ok(address) = std::env::var("BROKER_ADDR") {
+        return Ok(Box::new(tokio::net::TcpStream::connect(address).await?));
+    }
+    #[cfg(unix)]
+    {
+        let stream = tokio::net::UnixStream::connect(ipc_broker::rpc::UNIX_PATH).await?;
+        Ok(Box::new(stream))
+    }
+    #[cfg(windows)]
+    {
+        let pipe = tokio::net::windows::named_pipe::ClientOptions::new()
+            .open(ipc_broker::rpc::PIPE_PATH)?;
+        Ok(Box::new(pipe))
+    }
+}
+
+fn encode<T: serde::Serialize>(message: &T) -> io::Result<Vec<u8>> {
+    serde_json::to_vec(message).map_err(io::Error::other)
+}
+
+/// Registers `object` as `name` on `stream` and serves its calls, until
+/// `stop` changes (`Ok`) or the connection fails (`Err`).
+pub async fn run<S, T>(
+    stream: S,
+    name: &str,
+    object: Arc<T>,
+    stop: &mut watch::Receiver<bool>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    T: SharedObject + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let register = RpcRequest::RegisterObject {
+        object_name: name.to_string(),
+    };
+    write_packet(&mut writer, &encode(&register)?).await?;
+
+    // Read apart, as a packet read halfway can't be resumed.
+    let (packets_tx, mut packets) = mpsc::unbounded_channel();
+    let reading = tokio::spawn(async move {
+        loop {
+            let packet = read_packet(&mut reader).await;
+            let failed = packet.is_err();
+            if packets_tx.send(packet).is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    let mut calls = JoinSet::new();
+    let result = loop {
+        tokio::select! {
+            _ = stop.changed() => break Ok(()),
+            Some(response) = calls.join_next() => match response {
+                Ok(response) => {
+                    if let Err(err) = write_packet(&mut writer, &encode(&response)?).await {
+                        break Err(err);
+                    }
+                }
+                Err(err) => log::error!("Call failed: {err}"),
+            },
+            packet = packets.recv() => {
+                let packet = match packet {
+                    Some(Ok(packet)) => packet,
+                    Some(Err(err)) => break Err(err),
+                    None => break Err(io::ErrorKind::UnexpectedEof.into()),
+                };
+                match serde_json::from_slice::<RpcRequest>(&packet) {
+                    Ok(RpcRequest::Call {
+                        call_id,
+                        object_name,
+                        method,
+                        args,
+                    }) if object_name == name => {
+                        let object = object.clone();
+                        calls.spawn(async move {
+                            let value = object.call(&method, &args).await;
+                            RpcResponse::Result {
+                                call_id,
+                                object_name,
+                                value,
+                            }
+                        });
+                    }
+                    Ok(RpcRequest::Call { object_name, .. }) => {
+                        log::error!("Unknown object: {object_name}");
+                    }
+                    Ok(request) => log::debug!("Unsupported request: {request:?}"),
+                    Err(_) => log::debug!("Ignoring a packet that is not a request."),
+                }
+            }
+        }
+    };
+    reading.abort();
+
+    if result.is_ok() {
+        // Takes no more calls, but answers those in progress.
+        let answered = tokio::time::timeout(CALL_GRACE, async {
+            while let Some(response) = calls.join_next().await {
+                if let Ok(response) = response {
+                    write_packet(&mut writer, &encode(&response)?).await?;
+                }
+            }
+            io::Result::Ok(())
+        })
+        .await;
+        if answered.is_err() {
+            log::warn!(
+                "{} call(s) still running after {CALL_GRACE:?}, dropped.",
+                calls.len()
+            );
+        }
+        let _ = writer.shutdown().await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use ipc_broker::rpc::CallId;
+    use serde_json::{Value, json};
+    use tokio::{io::DuplexStream, sync::mpsc::unbounded_channel};
+
+    use super::*;
+    use crate::{
+        interface::mock::{Mock, Route},
+        oauth2::session::EVENT_OBJECT,
+        shared_object::DeviceCodeFlowObject,
+        task_manager::{TaskManager, TaskMessage},
+    };
+
+    const DEVICE_CODE_RESPONSE: &str = r#"{"user_code":"usercode-123","device_code":"devicecode-123","verification_uri":"https://verification_url","expires_in":300,"interval":1}"#;
+    const PENDING_RESPONSE: &str = r#"{"error":"authorization_pending"}"#;
+
+    async fn send(broker: &mut DuplexStream, id: &str, method: &str, args: Value) {
+        let call = RpcRequest::Call {
+            call_id: serde_json::from_value(id.into()).unwrap(),
+            object_name: EVENT_OBJECT.into(),
+            method: method.into(),
+            args,
+        };
+        write_packet(broker, &encode(&call).unwrap()).await.unwrap();
+    }
+
+    /// The next reply, or `None` if none comes within a second.
+    async fn reply(broker: &mut DuplexStream) -> Option<(CallId, Value)> {
+        let packet = tokio::time::timeout(Duration::from_secs(1), read_packet(broker))
+            .await
+            .ok()?
+            .unwrap();
+        match serde_json::from_slice(&packet).unwrap() {
+            RpcResponse::Result { call_id, value, .. } => Some((call_id, value)),
+            response => panic!("unexpected {response:?}"),
+        }
+    }
+
+    fn id(id: &str) -> CallId {
+        serde_json::from_value(id.into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_await_login_holds_up_no_other_call() {
+        let interface = Mock::new()
+            .route(Route::post("/devicecode").respond_json(StatusCode::OK, DEVICE_CODE_RESPONSE))
+            .route(Route::post("/token").respond_json(StatusCode::BAD_REQUEST, PENDING_RESPONSE));
+        let (tx, rx) = unbounded_channel();
+        let mut task_manager = TaskManager::new(rx);
+        let manager = tokio::spawn({
+            let interface = interface.clone();
+            async move { task_manager.run(interface).await }
+        });
+
+        let object = Arc::new(DeviceCodeFlowObject::new(interface, tx.clone()));
+        let (mut broker, stream) = tokio::io::duplex(64 * 1024);
+        let (stop_tx, mut stop) = watch::channel(false);
+        let worker =
+            tokio::spawn(async move { run(stream, EVENT_OBJECT, object, &mut stop).await });
+        let registered = read_packet(&mut broker).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<RpcRequest>(&registered).unwrap(),
+            RpcRequest::RegisterObject {
+                object_name: EVENT_OBJECT.into()
+            }
+        );
+
+        let provider = json!({
+            "process": "Process Name",
+            "provider": "Example",
+            "device_auth_endpoint": "https://login.example.com/devicecode",
+            "token_endpoint": "https://login.example.com/token",
+            "client_id": "client",
+            "scopes": ["offline_access"],
+            "timeout": 300,
+        });
+        send(&mut broker, "login", "login", provider.clone()).await;
+        let (call_id, value) = reply(&mut broker).await.unwrap();
+        assert_eq!(call_id, id("login"));
+        assert!(value.get("error_code").is_none(), "{value}");
+
+        // Waits for the login, while the other calls are answered.
+        send(&mut broker, "await", "awaitLogin", provider.clone()).await;
+        send(&mut broker, "status", "loginStatus", provider.clone()).await;
+        let (call_id, value) = reply(&mut broker).await.unwrap();
+        assert_eq!(call_id, id("status"));
+        assert_eq!(value["state"], "pending");
+        assert!(reply(&mut broker).await.is_none());
+
+        send(&mut broker, "cancel", "cancel", provider).await;
+        let mut replies = [
+            reply(&mut broker).await.unwrap(),
+            reply(&mut broker).await.unwrap(),
+        ];
+        replies.sort_by_key(|(call_id, _)| serde_json::to_string(call_id).unwrap());
+        assert_eq!(replies[0].0, id("await"));
+        assert_eq!(replies[0].1["state"], "cancelled");
+        assert_eq!(replies[1].0, id("cancel"));
+
+        stop_tx.send(true).unwrap();
+        worker.await.unwrap().unwrap();
+        tx.send(TaskMessage::Quit).unwrap();
+        manager.await.unwrap();
+    }
+}
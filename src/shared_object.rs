@@ -1,23 +1,39 @@
+use std::{sync::Arc, time::Instant};
+
 use async_trait::async_trait;
 
 use ipc_broker::worker::SharedObject;
 use json_result::r#struct::JsonResult;
+use serde::Serialize;
 use serde_json::Value;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{Mutex, mpsc::UnboundedSender};
 
+use crate::http_client::redact::redact_value;
 use crate::interface::Interface;
+use crate::logger::{self, LogFields, control};
+use crate::metrics;
 use crate::oauth2::device_code_flow::{self};
-use crate::oauth2::error::{ErrorCodes, OAuth2Error};
+use crate::oauth2::error::{ErrorCodes, OAuth2Error, OAuth2Result};
 use crate::oauth2::provider::InputParameters;
-use crate::openid::{self, ApplicationNonce};
+use crate::oauth2::session::EVENT_OBJECT;
+use crate::openid::{self, ApplicationNonce, cache::ProviderCache};
+use crate::service::health::{self, ProviderErrors};
 use crate::task_manager::TaskMessage;
+use crate::telemetry::Span;
 
+#[derive(Clone)]
 pub struct DeviceCodeFlowObject<I>
 where
     I: Interface + Send + Sync + 'static,
 {
     interface: I,
     tx: UnboundedSender<TaskMessage>,
+    cache: ProviderCache,
+    errors: ProviderErrors,
+    started: Instant,
+    /// Runs calls one at a time, but for `awaitLogin`, which only waits on
+    /// the task manager.
+    calls: Arc<Mutex<()>>,
 }
 
 impl<I> DeviceCodeFlowObject<I>
@@ -25,55 +41,128 @@ where
     I: Interface + Send + Sync + 'static,
 {
     pub fn new(interface: I, tx: UnboundedSender<TaskMessage>) -> Self {
-        Self { interface, tx }
+        Self {
+            interface,
+            tx,
+            cache: ProviderCache::new(),
+            errors: ProviderErrors::default(),
+            started: Instant::now(),
+            calls: Arc::default(),
+        }
+    }
+
+    /// The reply of `method`, remembering a failure as the provider's last
+    /// error.
+    fn reply<T: Serialize>(
+        &self,
+        method: &str,
+        provider: Option<&str>,
+        result: OAuth2Result<T>,
+    ) -> Value {
+        if let (Err(err), Some(provider)) = (&result, provider) {
+            self.errors.record(provider, method, err);
+        }
+        JsonResult::from(result).into()
     }
 }
 
-#[async_trait]
-impl<I> SharedObject for DeviceCodeFlowObject<I>
+impl<I> DeviceCodeFlowObject<I>
 where
     I: Interface + Send + Sync + 'static + Clone,
 {
-    async fn call(&self, method: &str, args: &Value) -> Value {
-        log::trace!("Method: {} Param: {:?}", method, args);
-        // Reset inactivity timer
-        if let Err(err) = self.tx.send(TaskMessage::ResetInactivityTimer) {
-            log::error!("{err}");
-        }
-        let param: InputParameters = match serde_json::from_value(args.clone()) {
-            Ok(p) => p,
-            Err(e) => {
-                let e = OAuth2Error::from(e);
-                return JsonResult::<(), OAuth2Error>(Err(e)).into();
-            }
-        };
+    /// The last error of each provider, shared with the task manager for
+    /// the logins failing in the background.
+    pub fn provider_errors(&self) -> ProviderErrors {
+        self.errors.clone()
+    }
+
+    /// Resumes the logins a previous run of the service left pending.
+    pub fn resume_logins(&self) {
+        device_code_flow::resume_logins(self.interface.clone(), self.tx.clone(), &self.cache);
+    }
 
+    async fn dispatch(&self, method: &str, param: InputParameters) -> Value {
+        let provider = param.provider.clone();
+        let provider = provider.as_deref();
         match method {
             "login" => {
-                let result =
-                    device_code_flow::login(param, self.interface.clone(), self.tx.clone()).await;
-                JsonResult::from(result).into()
+                let result = device_code_flow::login(
+                    param,
+                    self.interface.clone(),
+                    self.tx.clone(),
+                    &self.cache,
+                )
+                .await;
+                self.reply(method, provider, result)
             }
             "cancel" => {
                 let result = device_code_flow::cancel(param, self.tx.clone()).await;
-                JsonResult::from(result).into()
+                self.reply(method, provider, result)
+            }
+            "loginStatus" => {
+                let result = device_code_flow::login_status(param, self.tx.clone()).await;
+                self.reply(method, provider, result)
+            }
+            "awaitLogin" => {
+                let result = device_code_flow::await_login(param, self.tx.clone()).await;
+                self.reply(method, provider, result)
             }
             "requestToken" => {
-                let result =
-                    device_code_flow::request_token(param, self.interface.clone(), self.tx.clone())
-                        .await;
-                JsonResult::from(result).into()
+                let result = device_code_flow::request_token(
+                    param,
+                    self.interface.clone(),
+                    self.tx.clone(),
+                    &self.cache,
+                )
+                .await;
+                self.reply(method, provider, result)
             }
             "logout" => {
                 let result = device_code_flow::logout(param, self.interface.clone()).await;
-                JsonResult::from(result).into()
+                self.reply(method, provider, result)
             }
             "verifyIDToken" => {
+                let stored = device_code_flow::stored_token(&param, &self.interface);
+                let nonce = param
+                    .nonce
+                    .clone()
+                    .or_else(|| stored.as_ref()?.nonce.clone());
+                let access_token = stored
+                    .as_ref()
+                    .and_then(|token| token.access_token_of(param.id_token.as_ref()));
+                let result = openid::verify_id_token(
+                    param,
+                    nonce.map_or_else(ApplicationNonce::new, ApplicationNonce::from),
+                    access_token,
+                    &self.cache,
+                    self.interface.clone(),
+                )
+                .await;
+                self.reply(method, provider, result)
+            }
+            "verifyAccessToken" => {
                 let result =
-                    openid::verify_id_token(param, ApplicationNonce::new(), self.interface.clone())
-                        .await;
+                    openid::verify_access_token(param, &self.cache, self.interface.clone()).await;
+                self.reply(method, provider, result)
+            }
+            "cacheStats" => JsonResult::<_, OAuth2Error>(Ok(self.cache.stats())).into(),
+            "status" | "health" => {
+                let result = health::status(
+                    &self.interface,
+                    &self.tx,
+                    self.started,
+                    &self.cache,
+                    &self.errors,
+                )
+                .await;
                 JsonResult::from(result).into()
             }
+            "metrics" => JsonResult::<_, OAuth2Error>(Ok(metrics::render())).into(),
+            "setLogLevel" => JsonResult::from(control::set_log_level(&param)).into(),
+            "diagnose" => {
+                let result = health::diagnose(&param, &self.interface).await;
+                self.reply(method, provider, result)
+            }
             _ => {
                 let e = OAuth2Error::new(
                     ErrorCodes::OtherError,
@@ -84,3 +173,59 @@ where
         }
     }
 }
+
+#[async_trait]
+impl<I> SharedObject for DeviceCodeFlowObject<I>
+where
+    I: Interface + Send + Sync + 'static + Clone,
+{
+    async fn call(&self, method: &str, args: &Value) -> Value {
+        log::trace!("Method: {} Param: {}", method, redact_value(args));
+        // Reset inactivity timer
+        if let Err(err) = self.tx.send(TaskMessage::ResetInactivityTimer) {
+            log::error!("{err}");
+        }
+        let param: InputParameters = match serde_json::from_value(args.clone()) {
+            Ok(p) => p,
+            Err(e) => {
+                let e = OAuth2Error::from(e);
+                return JsonResult::<(), OAuth2Error>(Err(e)).into();
+            }
+        };
+
+        let mut span = Span::server(
+            method,
+            param.traceparent.as_deref(),
+            param.propagate_trace.unwrap_or_default(),
+        );
+        span.set_attribute("rpc.system", "ipc-broker");
+        span.set_attribute("rpc.service", EVENT_OBJECT);
+        span.set_attribute("rpc.method", method);
+        if let Some(provider) = &param.provider {
+            span.set_attribute("auth.provider", provider.as_str());
+        }
+        if let Some(process) = &param.process {
+            span.set_attribute("auth.process", process.as_str());
+        }
+
+        let fields = LogFields {
+            session: None,
+            process: param.process.clone(),
+            provider: param.provider.clone(),
+            method: Some(method.to_string()),
+        };
+        logger::scope(fields, async {
+            let _serialized = match method {
+                "awaitLogin" => None,
+                _ => Some(self.calls.lock().await),
+            };
+            let value = span.scope(self.dispatch(method, param)).await;
+            if let Some(error_code) = value.get("error_code").and_then(Value::as_str) {
+                span.fail(error_code);
+                log::info!(error_code; "{method} failed.");
+            }
+            value
+        })
+        .await
+    }
+}
@@ -1,104 +1,437 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use json_result::r#struct::JsonResult;
 use serde_json::Value;
 use tokio::{
     sync::{mpsc::UnboundedReceiver, oneshot},
-    task::JoinHandle,
+    task::{AbortHandle, JoinError, JoinHandle, JoinSet},
     time::Instant,
 };
 
 use crate::interface::Interface;
-
-const TIMEOUT: u64 = 60;
+use crate::metrics::METRICS;
+use crate::oauth2::error::{ErrorCodes, OAuth2Error};
+use crate::oauth2::pending::PendingLogin;
+use crate::oauth2::polling::PollingSchedule;
+use crate::oauth2::session::{EVENT_OBJECT, LoginSession, LoginState, LoginStatus};
+use crate::service::{IdlePolicy, health::ProviderErrors, systemd};
 
 pub enum TaskMessage {
+    /// Cancels the login of a process and provider.
     Abort(PathBuf),
-    Add(PathBuf, JoinHandle<()>),
+    /// Aborts the login of a process and provider before a new one starts.
+    Supersede(PathBuf),
+    Add(PathBuf, LoginStatus, JoinHandle<()>),
     Check(PathBuf, oneshot::Sender<bool>),
-    PollingDone(PathBuf),
+    /// Outcome of the poller of the given session.
+    PollingDone(PathBuf, String, Result<(), OAuth2Error>),
+    Status(PathBuf, oneshot::Sender<Option<LoginStatus>>),
+    /// Number of logins being polled.
+    PollingTasks(oneshot::Sender<usize>),
+    Await(PathBuf, oneshot::Sender<Option<LoginStatus>>),
     SendEvent(String, Value),
+    SendSessionEvent(LoginSession, String, Value),
+    /// New polling schedule of a session, after a `slow_down`.
+    Reschedule(LoginSession, PollingSchedule),
+    /// The poller of the given session got its token and is saving it.
+    Saving(PathBuf, String),
+    /// Stops once the pollers saving a token are done, or the grace period
+    /// is over; every other poller stops right away.
+    Shutdown(Duration),
     ResetInactivityTimer,
     Quit,
 }
 
+/// The latest login of a process and provider, kept after it finished so
+/// its outcome can still be queried.
+struct LoginRecord {
+    status: LoginStatus,
+    expires_at: Instant,
+    waiters: Vec<oneshot::Sender<Option<LoginStatus>>>,
+}
+
+impl LoginRecord {
+    fn new(status: LoginStatus) -> Self {
+        Self {
+            expires_at: Instant::now() + Duration::from_secs(status.remaining_seconds),
+            status,
+            waiters: Vec::new(),
+        }
+    }
+
+    fn snapshot(&self) -> LoginStatus {
+        let mut status = self.status.clone();
+        status.remaining_seconds = match status.state {
+            LoginState::Pending => self
+                .expires_at
+                .saturating_duration_since(Instant::now())
+                .as_secs(),
+            _ => 0,
+        };
+        status
+    }
+
+    fn finish(&mut self, state: LoginState, error: Option<OAuth2Error>) {
+        self.status.state = state;
+        self.status.error = error;
+        for waiter in std::mem::take(&mut self.waiters) {
+            let _ = waiter.send(Some(self.snapshot()));
+        }
+    }
+}
+
+/// A running poller, as the task manager knows it.
+struct PollingTask {
+    session_id: String,
+    handle: AbortHandle,
+    /// Past polling, so worth waiting for on shutdown.
+    saving: bool,
+}
+
+/// Exit of a supervised poller: its login and how the task ended.
+type TaskExit = (PathBuf, String, Result<(), JoinError>);
+
 pub struct TaskManager {
     rx: UnboundedReceiver<TaskMessage>,
+    idle_policy: IdlePolicy,
+    errors: ProviderErrors,
+    shared_events: bool,
 }
 
 impl TaskManager {
     pub fn new(rx: UnboundedReceiver<TaskMessage>) -> Self {
-        Self { rx }
+        Self {
+            rx,
+            idle_policy: IdlePolicy::default(),
+            errors: ProviderErrors::default(),
+            shared_events: false,
+        }
     }
+
+    /// Also publishes session events on the shared object, see
+    /// [`SHARED_EVENTS_ENV`](crate::oauth2::session::SHARED_EVENTS_ENV).
+    pub fn with_shared_events(mut self, shared_events: bool) -> Self {
+        self.shared_events = shared_events;
+        self
+    }
+
+    /// Where failed logins are remembered as their provider's last error.
+    pub fn with_provider_errors(mut self, errors: ProviderErrors) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    pub fn with_idle_policy(mut self, idle_policy: IdlePolicy) -> Self {
+        self.idle_policy = idle_policy;
+        self
+    }
+
     pub async fn run<I: Interface + Send + Sync + 'static>(&mut self, interface: I) {
-        let timeout = std::time::Duration::from_secs(TIMEOUT);
+        let idle_timeout = self.idle_policy.timeout();
+        let shared = self.shared_events;
         let mut last_activity = Instant::now();
-        let mut task_list = HashMap::<PathBuf, JoinHandle<()>>::new();
+        // Pinged from this loop, so systemd restarts the service if it stalls.
+        let watchdog_interval = systemd::watchdog_interval();
+        let mut watchdog =
+            tokio::time::interval(watchdog_interval.unwrap_or(Duration::from_secs(3600)));
+        let mut task_list = HashMap::<PathBuf, PollingTask>::new();
+        let mut logins = HashMap::<PathBuf, LoginRecord>::new();
+        // Every poller is awaited here, so a task that panics or is aborted
+        // is noticed even though it never sends `PollingDone`.
+        let mut supervisor = JoinSet::<TaskExit>::new();
+        let token_dir = interface.token_directory();
+        let mut stopping = None;
         loop {
+            if stopping.is_some() && task_list.is_empty() {
+                break;
+            }
             tokio::select! {
                     Some(msg) = self.rx.recv() => {
                     match msg {
-                        TaskMessage::Add(key, value) => {
+                        TaskMessage::Add(key, status, value) => {
                             last_activity = Instant::now();
-                            task_list.insert(key, value);
+                            let session_id = status.session.session_id.clone();
+                            if stopping.is_some() {
+                                // Already on disk, resumed on the next start.
+                                value.abort();
+                                continue;
+                            }
+                            task_list.insert(key.clone(), PollingTask {
+                                session_id: session_id.clone(),
+                                handle: value.abort_handle(),
+                                saving: false,
+                            });
+                            logins.insert(key.clone(), LoginRecord::new(status));
+                            supervisor.spawn(async move { (key, session_id, value.await) });
                             log::trace!("Polling tasks: {}", task_list.len());
                         }
                         TaskMessage::Abort(key) => {
                             last_activity = Instant::now();
-                            if let Some(task) = task_list.remove(&key) {
-                                task.abort();
-                            }
+                            abort_login(&interface, shared, &mut task_list, &mut logins, &key, LoginState::Cancelled).await;
+                            log::trace!("Polling tasks: {}", task_list.len());
+                        }
+                        TaskMessage::Supersede(key) => {
+                            last_activity = Instant::now();
+                            abort_login(&interface, shared, &mut task_list, &mut logins, &key, LoginState::Superseded).await;
                             log::trace!("Polling tasks: {}", task_list.len());
                         }
                         TaskMessage::Check(key, oneshot_tx) => {
                             last_activity = Instant::now();
                             log::trace!("Polling tasks: {}", task_list.len());
-                            let existing = if let Some(task) = task_list.get(&key) {
-                                log::trace!("{:?} exists, aborting task ...", task);
-                                true
-                            } else {
-                                false
-                            };
+                            let existing = task_list.contains_key(&key);
+                            if existing {
+                                log::trace!("{key:?} is already being polled.");
+                            }
                             oneshot_tx.send(existing).unwrap_or_else(|e|{
                                 log::error!("{:}", e);
                             });
                         }
-                        TaskMessage::PollingDone(key) => {
+                        TaskMessage::PollingDone(key, session_id, result) => {
                             log::info!("Polling done!");
                             last_activity = Instant::now();
-                            task_list.remove(&key);
+                            // A superseded poller may finish after its successor started.
+                            if task_list.get(&key).is_some_and(|task| task.session_id == session_id) {
+                                task_list.remove(&key);
+                            }
+                            PendingLogin::remove(&token_dir, &key, &session_id);
+                            if let Some(login) = logins.get_mut(&key)
+                                && login.status.session.session_id == session_id
+                                && login.status.state == LoginState::Pending
+                            {
+                                if let (Err(err), Some(provider)) = (&result, &login.status.session.provider) {
+                                    self.errors.record(provider, "login", err);
+                                }
+                                login.finish(LoginState::from_result(&result), result.err());
+                            }
                             log::trace!("Polling tasks: {}", task_list.len());
                         }
+                        TaskMessage::Status(key, oneshot_tx) => {
+                            last_activity = Instant::now();
+                            let _ = oneshot_tx.send(logins.get(&key).map(LoginRecord::snapshot));
+                        }
+                        TaskMessage::PollingTasks(oneshot_tx) => {
+                            let _ = oneshot_tx.send(task_list.len());
+                        }
+                        TaskMessage::Await(key, oneshot_tx) => {
+                            last_activity = Instant::now();
+                            match logins.get_mut(&key) {
+                                Some(login) if login.status.state == LoginState::Pending => {
+                                    login.waiters.push(oneshot_tx);
+                                }
+                                login => {
+                                    let _ = oneshot_tx.send(login.map(|login| login.snapshot()));
+                                }
+                            }
+                        }
                         TaskMessage::SendEvent(event, result) => {
                             last_activity = Instant::now();
                             log::info!("Event: {event}");
-                            interface.send_event("oauth2.device.code.flow", &event, &result).await.unwrap_or_else(|e|{
+                            interface.send_event(EVENT_OBJECT, &event, &result).await.unwrap_or_else(|e|{
                                 log::error!("{:}", e);
                             });
                         }
+                        TaskMessage::SendSessionEvent(session, event, result) => {
+                            last_activity = Instant::now();
+                            log::info!("Event: {event} ({})", session.session_id);
+                            send_session_event(&interface, shared, &session, &event, result).await;
+                        }
+                        TaskMessage::Reschedule(session, polling) => {
+                            last_activity = Instant::now();
+                            if let Some(login) = logins
+                                .values_mut()
+                                .find(|login| login.status.session.session_id == session.session_id)
+                            {
+                                login.status.polling = polling;
+                            }
+                        }
                         TaskMessage::ResetInactivityTimer => {
                             last_activity = Instant::now();
                             log::trace!("Activity detected, resetting inactivity timer.");
                         }
+                        TaskMessage::Saving(key, session_id) => {
+                            if let Some(task) = task_list.get_mut(&key)
+                                && task.session_id == session_id
+                            {
+                                task.saving = true;
+                            }
+                        }
+                        TaskMessage::Shutdown(grace) => {
+                            log::info!("Shutting down, waiting up to {grace:?} for tokens being saved.");
+                            stopping = Some(Instant::now() + grace);
+                            // Pending logins stay on disk and resume on the next start.
+                            task_list.retain(|_, task| {
+                                if !task.saving {
+                                    task.handle.abort();
+                                }
+                                task.saving
+                            });
+                        }
                         TaskMessage::Quit => {
                             break;
                         }
                     }
                 }
 
-                _ = tokio::time::sleep_until(last_activity + timeout) => {
-                    log::warn!("No activity for {TIMEOUT} seconds, shutting down . . .");
+                _ = tokio::time::sleep_until(stopping.unwrap_or_else(Instant::now)), if stopping.is_some() => {
+                    log::warn!("Shutdown grace period over, {} token(s) not saved.", task_list.len());
+                    break;
+                }
+
+                Some(Ok((key, session_id, exit))) = supervisor.join_next() => {
+                    last_activity = Instant::now();
+                    if task_list.get(&key).is_some_and(|task| task.session_id == session_id) {
+                        task_list.remove(&key);
+                    }
+                    if let Err(err) = exit
+                        && err.is_panic()
+                    {
+                        log::error!("Polling task of session {session_id} panicked: {err}");
+                        PendingLogin::remove(&token_dir, &key, &session_id);
+                        if let Some(login) = logins.get_mut(&key)
+                            && login.status.session.session_id == session_id
+                            && login.status.state == LoginState::Pending
+                        {
+                            let error = OAuth2Error::new(
+                                ErrorCodes::InternalError,
+                                "The polling task stopped unexpectedly.".into(),
+                            );
+                            if let Some(provider) = &login.status.session.provider {
+                                self.errors.record(provider, "login", &error);
+                            }
+                            finish_login(&interface, shared, login, LoginState::Failed, error).await;
+                        }
+                    }
+                    log::trace!("Polling tasks: {}", task_list.len());
+                }
+
+                _ = watchdog.tick(), if watchdog_interval.is_some() => {
+                    systemd::watchdog();
+                }
+
+                _ = tokio::time::sleep_until(last_activity + idle_timeout.unwrap_or_default()), if idle_timeout.is_some() && stopping.is_none() => {
+                    log::warn!("No activity for {:?}, shutting down . . .", idle_timeout.unwrap_or_default());
                     log::warn!("Checking task list if there are still on going polling tasks . . .");
 
-                    if !task_list.is_empty() {
+                    if !self.idle_policy.allows_exit(task_list.len()) {
                         log::warn!("Task list is not empty, cancel shutdown and reset inactivity timer.");
                         last_activity = Instant::now();
                     } else {
-                        log::warn!("Task list is empty, exiting now!");
+                        log::warn!("Exiting now.");
                         break;
                     }
                 }
             }
         }
+        // Pending logins stay on disk and resume on the next start.
+        for task in task_list.values() {
+            task.handle.abort();
+        }
+        self.drain(&interface, &token_dir).await;
         log::info!("Task manager exited.");
     }
 }
+
+impl TaskManager {
+    /// Handles what is still queued once the manager stops: events are
+    /// published so no subscriber misses them, requests are dropped.
+    async fn drain<I: Interface>(&mut self, interface: &I, token_dir: &Path) {
+        self.rx.close();
+        while let Ok(msg) = self.rx.try_recv() {
+            match msg {
+                TaskMessage::SendEvent(event, result) => {
+                    log::info!("Event: {event}");
+                    interface
+                        .send_event(EVENT_OBJECT, &event, &result)
+                        .await
+                        .unwrap_or_else(|e| {
+                            log::error!("{:}", e);
+                        });
+                }
+                TaskMessage::SendSessionEvent(session, event, result) => {
+                    log::info!("Event: {event} ({})", session.session_id);
+                    send_session_event(interface, self.shared_events, &session, &event, result)
+                        .await;
+                }
+                TaskMessage::PollingDone(key, session_id, _) => {
+                    PendingLogin::remove(token_dir, &key, &session_id);
+                }
+                TaskMessage::Add(_, _, handle) => handle.abort(),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Aborts the poller of `key`, leaving its login in `state`.
+async fn abort_login<I: Interface>(
+    interface: &I,
+    shared: bool,
+    task_list: &mut HashMap<PathBuf, PollingTask>,
+    logins: &mut HashMap<PathBuf, LoginRecord>,
+    key: &PathBuf,
+    state: LoginState,
+) {
+    let Some(task) = task_list.remove(key) else {
+        return;
+    };
+    task.handle.abort();
+    PendingLogin::remove(&interface.token_directory(), key, &task.session_id);
+    if let Some(login) = logins.get_mut(key)
+        && login.status.state == LoginState::Pending
+    {
+        let error = match state {
+            LoginState::Superseded => OAuth2Error::new(
+                ErrorCodes::Superseded,
+                "A new login was started for this process and provider.".into(),
+            ),
+            _ => OAuth2Error::new(ErrorCodes::Cancelled, "The login was cancelled.".into()),
+        };
+        finish_login(interface, shared, login, state, error).await;
+    }
+}
+
+/// Ends a login the poller couldn't report on itself, publishing its typed
+/// event and a `token.ready` error so no subscriber is left waiting.
+async fn finish_login<I: Interface>(
+    interface: &I,
+    shared: bool,
+    login: &mut LoginRecord,
+    state: LoginState,
+    error: OAuth2Error,
+) {
+    login.finish(state, Some(error.clone()));
+    let session = &login.status.session;
+    METRICS.logins_failed.inc(&[
+        ("provider", session.provider.as_deref().unwrap_or_default()),
+        ("error_code", error.error_code.as_ref()),
+    ]);
+    let payload = serde_json::to_value(&error).unwrap_or_default();
+    send_session_event(interface, shared, session, state.event(), payload).await;
+    let ready: Value = JsonResult::<(), OAuth2Error>(Err(error)).into();
+    send_session_event(interface, shared, session, "token.ready", ready).await;
+}
+
+/// Publishes a session event on the session's object, and on the shared one
+/// if `shared`.
+async fn send_session_event<I: Interface>(
+    interface: &I,
+    shared: bool,
+    session: &LoginSession,
+    event: &str,
+    result: Value,
+) {
+    let result = session.tag(result);
+    let shared = shared.then(|| EVENT_OBJECT.to_string());
+    for object in shared.into_iter().chain([session.object()]) {
+        interface
+            .send_event(&object, event, &result)
+            .await
+            .unwrap_or_else(|e| {
+                log::error!("{:}", e);
+            });
+    }
+}
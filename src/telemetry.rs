@@ -0,0 +1,195 @@
+pub mod otlp;
+
+use std::{collections::HashMap, future::Future};
+
+use oauth2::HttpRequest;
+use opentelemetry::{
+    Context, KeyValue,
+    context::FutureExt,
+    global,
+    propagation::TextMapPropagator,
+    trace::{SpanKind, Status, TraceContextExt, Tracer},
+};
+use opentelemetry_http::HeaderInjector;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use serde_json::Value;
+
+use crate::oauth2::error::OAuth2Result;
+
+/// Header and call argument carrying the W3C trace context.
+pub const TRACEPARENT: &str = "traceparent";
+
+/// Whether providers receive the context of the spans under a call.
+#[derive(Clone, Copy)]
+struct Propagate(bool);
+
+fn attribute(key: &'static str, value: Value) -> KeyValue {
+    match value {
+        Value::Bool(value) => KeyValue::new(key, value),
+        Value::Number(number) => match number.as_i64() {
+            Some(value) => KeyValue::new(key, value),
+            None => KeyValue::new(key, number.as_f64().unwrap_or_default()),
+        },
+        Value::String(value) => KeyValue::new(key, value),
+        value => KeyValue::new(key, value.to_string()),
+    }
+}
+
+/// A unit of work, ended when dropped.
+pub struct Span {
+    context: Context,
+}
+
+impl Span {
+    fn new(name: impl Into<String>, kind: SpanKind, parent: Context) -> Self {
+        let tracer = global::tracer(env!("CARGO_PKG_NAME"));
+        let span = tracer
+            .span_builder(name.into())
+            .with_kind(kind)
+            .start_with_context(&tracer, &parent);
+        Self {
+            context: parent.with_span(span),
+        }
+    }
+
+    /// Starts a span under the current one, or a new trace.
+    pub fn start(name: impl Into<String>) -> Self {
+        Self::new(name, SpanKind::Internal, Context::current())
+    }
+
+    /// Starts a span for a request to a provider.
+    pub fn client(name: impl Into<String>) -> Self {
+        Self::new(name, SpanKind::Client, Context::current())
+    }
+
+    /// Starts the span of an incoming call, under the caller's `traceparent`
+    /// if it sent one. `propagate` sends the context on to providers.
+    pub fn server(name: impl Into<String>, traceparent: Option<&str>, propagate: bool) -> Self {
+        let mut parent = Context::new();
+        if let Some(traceparent) = traceparent {
+            let carrier = HashMap::from([(TRACEPARENT.to_string(), traceparent.to_string())]);
+            parent = TraceContextPropagator::new().extract(&carrier);
+            if !parent.has_active_span() {
+                log::warn!("Ignoring invalid traceparent {traceparent:?}.");
+            }
+        }
+        Self::new(
+            name,
+            SpanKind::Server,
+            parent.with_value(Propagate(propagate)),
+        )
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        self.context
+            .span()
+            .set_attribute(attribute(key, value.into()));
+    }
+
+    pub fn fail(&mut self, message: impl Into<String>) {
+        self.context
+            .span()
+            .set_status(Status::error(message.into()));
+    }
+
+    /// Marks the span failed with the error code of `result`, if any.
+    pub fn record<T>(&mut self, result: &OAuth2Result<T>) {
+        if let Err(err) = result {
+            self.fail(err.error_code.as_ref());
+        }
+    }
+
+    /// Runs `future` with this span as the current one.
+    pub async fn scope<F: Future>(&self, future: F) -> F::Output {
+        future.with_context(self.context.clone()).await
+    }
+
+    /// Adds this span's `traceparent` to a request for a provider, when the
+    /// call asked for it.
+    pub fn inject(&self, request: &mut HttpRequest) {
+        if self
+            .context
+            .get::<Propagate>()
+            .is_some_and(|propagate| propagate.0)
+        {
+            TraceContextPropagator::new()
+                .inject_context(&self.context, &mut HeaderInjector(request.headers_mut()));
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        self.context.span().end();
+    }
+}
+
+/// Keeps the current span for `future`, e.g. a task spawned from a call.
+pub fn propagate<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let current = Context::current();
+    async move {
+        if current.has_active_span() {
+            future.with_context(current).await
+        } else {
+            future.await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::SpanContext;
+
+    use super::*;
+    use crate::test_support::collector;
+
+    const TRACEPARENT_VALUE: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn span_context(span: &Span) -> SpanContext {
+        span.context.span().span_context().clone()
+    }
+
+    #[tokio::test]
+    async fn test_spans_nest_and_propagate() {
+        collector::start();
+        let call = Span::server("login", Some(TRACEPARENT_VALUE), true);
+        assert_eq!(
+            format!("{:x}", span_context(&call).trace_id()),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+
+        let (request, child) = call
+            .scope(propagate(async {
+                let span = Span::client("POST");
+                let mut request = HttpRequest::new(Vec::new());
+                span.inject(&mut request);
+                (request, span_context(&span))
+            }))
+            .await;
+        assert_eq!(child.trace_id(), span_context(&call).trace_id());
+        assert_ne!(child.span_id(), span_context(&call).span_id());
+        assert_eq!(
+            request.headers()[TRACEPARENT].to_str().unwrap(),
+            format!("00-{:x}-{:x}-01", child.trace_id(), child.span_id())
+        );
+
+        // Without `propagate`, providers see nothing.
+        let call = Span::server("login", None, false);
+        let request = call
+            .scope(async {
+                let mut request = HttpRequest::new(Vec::new());
+                Span::client("POST").inject(&mut request);
+                request
+            })
+            .await;
+        assert!(request.headers().get(TRACEPARENT).is_none());
+        assert_ne!(
+            span_context(&Span::start("outside")).trace_id(),
+            span_context(&call).trace_id()
+        );
+
+        // An invalid `traceparent` starts a new trace.
+        let call = Span::server("login", Some("00-xyz-01"), true);
+        assert!(span_context(&call).is_valid());
+    }
+}
@@ -0,0 +1,198 @@
+// Records spans with the OpenTelemetry SDK and, when a collector is set,
+// exports them in batches over OTLP/HTTP with the JSON encoding.
+
+use std::sync::OnceLock;
+
+use opentelemetry::{KeyValue, global};
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use reqwest::Url;
+
+/// Environment variable holding the collector's traces URL, e.g.
+/// `http://127.0.0.1:4318/v1/traces`. Unset, no span is exported.
+pub const OTLP_ENDPOINT_ENV: &str = "AUTH_SERVICE_OTLP_ENDPOINT";
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// The collector URL from `AUTH_SERVICE_OTLP_ENDPOINT`.
+pub fn endpoint_from_env() -> Option<Url> {
+    let value = std::env::var(OTLP_ENDPOINT_ENV).ok()?;
+    match Url::parse(&value) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Some(url),
+        Ok(url) => {
+            log::warn!("Traces not exported: unsupported scheme in {url}.");
+            None
+        }
+        Err(err) => {
+            log::warn!("Traces not exported: invalid URL {value:?}: {err}");
+            None
+        }
+    }
+}
+
+fn provider(endpoint: Option<Url>) -> SdkTracerProvider {
+    let resource = Resource::builder()
+        .with_service_name(env!("CARGO_PKG_NAME"))
+        .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+        .build();
+    let builder = SdkTracerProvider::builder().with_resource(resource);
+    let Some(endpoint) = endpoint else {
+        return builder.build();
+    };
+    // The exporter has its own blocking client and thread, so exports are
+    // neither traced nor counted as calls to providers.
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_protocol(Protocol::HttpJson)
+        .with_endpoint(endpoint.as_str())
+        .build();
+    match exporter {
+        Ok(exporter) => {
+            log::info!("Exporting traces to {endpoint}");
+            builder.with_batch_exporter(exporter).build()
+        }
+        Err(err) => {
+            log::warn!("Traces not exported: {err}");
+            builder.build()
+        }
+    }
+}
+
+/// Starts recording spans, and exporting them to `endpoint` if set. Spans
+/// get their identifiers either way, so callers' traces carry on to
+/// providers.
+pub fn start(endpoint: Option<Url>) {
+    let mut started = false;
+    let provider = PROVIDER.get_or_init(|| {
+        started = true;
+        provider(endpoint)
+    });
+    if started {
+        global::set_tracer_provider(provider.clone());
+    }
+}
+
+/// Sends the spans not exported yet, e.g. before exiting.
+pub async fn flush() {
+    let Some(provider) = PROVIDER.get().cloned() else {
+        return;
+    };
+    // Waits on the exporter's thread, which may be posting a batch.
+    let result = tokio::task::spawn_blocking(move || provider.force_flush()).await;
+    if let Ok(Err(err)) = result {
+        log::debug!("Failed to export spans: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Request;
+    use opentelemetry_proto::tonic::{
+        collector::trace::v1::ExportTraceServiceRequest,
+        common::v1::{KeyValue, any_value::Value},
+        trace::v1::{span::SpanKind, status::StatusCode},
+    };
+
+    use super::*;
+    use crate::{
+        http_client::{HttpClient, reqwest::Reqwest},
+        telemetry::{Span, TRACEPARENT},
+        test_support::collector::{self, hex},
+    };
+
+    fn attribute<'a>(attributes: &'a [KeyValue], key: &str) -> Option<&'a Value> {
+        attributes
+            .iter()
+            .find(|attribute| attribute.key == key)
+            .and_then(|attribute| attribute.value.as_ref()?.value.as_ref())
+    }
+
+    #[tokio::test]
+    async fn test_otlp_json_round_trip() {
+        let collector = collector::start();
+        let trace_id = "0af7651916cd43dd8448eb211c80319c";
+        let mut span = Span::server(
+            "requestToken",
+            Some(&format!("00-{trace_id}-00f067aa0ba902b7-01")),
+            false,
+        );
+        span.set_attribute("rpc.method", "requestToken");
+        span.set_attribute("http.response.status_code", 400);
+        span.set_attribute("auth.cached", true);
+        span.fail("invalid_grant");
+        drop(span);
+        flush().await;
+
+        // Each body decodes into the OTLP messages and encodes back unchanged.
+        for body in collector.exports() {
+            let request: ExportTraceServiceRequest = serde_json::from_slice(&body).unwrap();
+            let encoded: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(serde_json::to_value(&request).unwrap(), encoded);
+            let resource = request.resource_spans[0].resource.as_ref().unwrap();
+            assert_eq!(
+                attribute(&resource.attributes, "service.name"),
+                Some(&Value::StringValue(env!("CARGO_PKG_NAME").to_string()))
+            );
+        }
+
+        let spans = collector.spans(trace_id);
+        let [span] = spans.as_slice() else {
+            panic!("expected one span, got {spans:?}");
+        };
+        assert_eq!(span.name, "requestToken");
+        assert_eq!(hex(&span.parent_span_id), "00f067aa0ba902b7");
+        assert_eq!(span.kind, SpanKind::Server as i32);
+        assert_eq!(
+            attribute(&span.attributes, "rpc.method"),
+            Some(&Value::StringValue("requestToken".to_string()))
+        );
+        assert_eq!(
+            attribute(&span.attributes, "http.response.status_code"),
+            Some(&Value::IntValue(400))
+        );
+        assert_eq!(
+            attribute(&span.attributes, "auth.cached"),
+            Some(&Value::BoolValue(true))
+        );
+        let status = span.status.as_ref().unwrap();
+        assert_eq!(status.code, StatusCode::Error as i32);
+        assert_eq!(status.message, "invalid_grant");
+        assert!(span.start_time_unix_nano <= span.end_time_unix_nano);
+    }
+
+    #[tokio::test]
+    async fn test_export_to_collector() {
+        let collector = collector::start();
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let call = Span::server(
+            "requestToken",
+            Some(&format!("00-{trace_id}-00f067aa0ba902b7-01")),
+            true,
+        );
+        let token_request = Request::post(format!("http://{}/token", collector.address))
+            .body(Vec::new())
+            .unwrap();
+        call.scope(HttpClient::Reqwest(Reqwest::default()).send(token_request))
+            .await
+            .unwrap();
+        drop(call);
+        flush().await;
+
+        let spans = collector.spans(trace_id);
+        let call = spans
+            .iter()
+            .find(|span| span.name == "requestToken")
+            .unwrap();
+        let http = spans.iter().find(|span| span.name == "POST").unwrap();
+        assert_eq!(http.parent_span_id, call.span_id);
+        assert_eq!(http.kind, SpanKind::Client as i32);
+
+        let traceparent = format!("00-{trace_id}-{}-01", hex(&http.span_id));
+        assert!(
+            collector
+                .headers("/token")
+                .iter()
+                .any(|headers| headers[TRACEPARENT] == traceparent.as_str())
+        );
+    }
+}
@@ -0,0 +1,4 @@
+pub mod auth_server;
+pub mod collector;
+pub mod http;
+pub mod keys;
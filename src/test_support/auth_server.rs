@@ -0,0 +1,443 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use oauth2::{
+    AuthUrl, ClientId, DeviceAuthorizationUrl, Scope, TokenUrl, url::Url, url::form_urlencoded,
+};
+use serde_json::{Value, json};
+use tokio::task::JoinHandle;
+
+use crate::oauth2::provider::InputParameters;
+
+use super::{
+    http::{Handler, serve},
+    keys::{SigningAlgorithm, SigningKeys, at_hash, random_string},
+};
+
+pub const CLIENT_ID: &str = "mock-client";
+pub const RESOURCE_AUDIENCE: &str = "api://mock-resource";
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    Approve,
+    Deny,
+}
+
+struct DeviceGrant {
+    device_code: String,
+    client_id: String,
+    scope: String,
+    nonce: Option<String>,
+    decision: Option<Decision>,
+    expires_at: Instant,
+}
+
+struct State {
+    issuer: String,
+    keys: SigningKeys,
+    alg: SigningAlgorithm,
+    user: Value,
+    extra_id_token_claims: Value,
+    echo_nonce: bool,
+    interval: u64,
+    expires_in: u64,
+    token_lifetime: u64,
+    auto_decision: Option<Decision>,
+    grants: HashMap<String, DeviceGrant>,
+    refresh_tokens: HashMap<String, (String, String)>,
+    access_tokens: HashSet<String>,
+    hits: HashMap<String, usize>,
+}
+
+/// An in-process OAuth2/OpenID Connect provider listening on localhost.
+///
+/// It serves discovery, JWKS, device authorization, token (device code and
+/// refresh grants), revocation and userinfo. The end user is simulated with
+/// [`AuthServer::approve`]/[`AuthServer::deny`] or an automatic decision.
+pub struct AuthServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for AuthServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+impl AuthServer {
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(State {
+            issuer: String::new(),
+            keys: SigningKeys::generate(),
+            alg: SigningAlgorithm::Rs256,
+            user: json!({
+                "sub": "user-1",
+                "name": "Mock User",
+                "email": "mock.user@example.com",
+                "preferred_username": "mock.user",
+                "tid": "tenant-1",
+            }),
+            extra_id_token_claims: json!({}),
+            echo_nonce: true,
+            interval: 1,
+            expires_in: 30,
+            token_lifetime: 3600,
+            auto_decision: None,
+            grants: HashMap::new(),
+            refresh_tokens: HashMap::new(),
+            access_tokens: HashSet::new(),
+            hits: HashMap::new(),
+        }));
+
+        let inner = state.clone();
+        let handler: Handler = Arc::new(move |request| handle(&inner, request));
+        let (addr, handle) = serve(handler).await.expect("mock server bind");
+        state.lock().unwrap().issuer = format!("http://{addr}");
+
+        Self {
+            addr,
+            state,
+            handle,
+        }
+    }
+
+    pub fn issuer(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn url(&self, path: &str) -> Url {
+        Url::parse(&format!("{}{path}", self.issuer())).unwrap()
+    }
+
+    /// Provider parameters pointing at this server, as a client would send them.
+    pub fn provider(&self) -> InputParameters {
+        InputParameters {
+            process: Some(String::from("Mock Process")),
+            provider: Some(String::from("MockProvider")),
+            authorization_endpoint: Some(AuthUrl::from_url(self.url("/authorize"))),
+            token_endpoint: Some(TokenUrl::from_url(self.url("/token"))),
+            device_auth_endpoint: Some(DeviceAuthorizationUrl::from_url(self.url("/devicecode"))),
+            scopes: Some(vec![
+                Scope::new("openid".into()),
+                Scope::new("offline_access".into()),
+            ]),
+            client_id: Some(ClientId::new(CLIENT_ID.into())),
+            ..Default::default()
+        }
+    }
+
+    pub fn approve(&self, user_code: &str) {
+        self.decide(user_code, Decision::Approve);
+    }
+
+    pub fn deny(&self, user_code: &str) {
+        self.decide(user_code, Decision::Deny);
+    }
+
+    fn decide(&self, user_code: &str, decision: Decision) {
+        if let Some(grant) = self.state.lock().unwrap().grants.get_mut(user_code) {
+            grant.decision = Some(decision);
+        }
+    }
+
+    /// Applies `decision` to every device code issued from now on.
+    pub fn set_auto_decision(&self, decision: Option<Decision>) {
+        self.state.lock().unwrap().auto_decision = decision;
+    }
+
+    pub fn set_signing_algorithm(&self, alg: SigningAlgorithm) {
+        self.state.lock().unwrap().alg = alg;
+    }
+
+    pub fn set_token_lifetime(&self, seconds: u64) {
+        self.state.lock().unwrap().token_lifetime = seconds;
+    }
+
+    /// Claims merged into (and overriding) every ID token issued afterwards.
+    pub fn set_id_token_claims(&self, claims: Value) {
+        self.state.lock().unwrap().extra_id_token_claims = claims;
+    }
+
+    /// Whether the `nonce` sent with a device authorization ends up in the
+    /// ID token, which RFC 8628 leaves out and most providers ignore.
+    pub fn set_echo_nonce(&self, echo: bool) {
+        self.state.lock().unwrap().echo_nonce = echo;
+    }
+
+    pub fn jwks(&self) -> Value {
+        self.state.lock().unwrap().keys.jwks()
+    }
+
+    pub fn rotate_keys(&self) {
+        self.state.lock().unwrap().keys.rotate();
+    }
+
+    /// Number of requests served for `path`.
+    pub fn hits(&self, path: &str) -> usize {
+        *self.state.lock().unwrap().hits.get(path).unwrap_or(&0)
+    }
+
+    /// Signs arbitrary claims with the current key for `alg`.
+    pub fn sign(&self, alg: SigningAlgorithm, header: Value, claims: &Value) -> String {
+        self.state.lock().unwrap().keys.sign(alg, header, claims)
+    }
+
+    /// Issues an ID token for the configured user, as the token endpoint would.
+    pub fn id_token(&self, nonce: Option<&str>, access_token: Option<&str>) -> String {
+        let state = self.state.lock().unwrap();
+        state.id_token(CLIENT_ID, nonce, access_token)
+    }
+
+    /// Issues an RFC 9068 JWT access token for the configured user.
+    pub fn access_token(&self, scope: &str) -> String {
+        let mut state = self.state.lock().unwrap();
+        state.access_token(CLIENT_ID, scope)
+    }
+}
+
+impl State {
+    fn now() -> i64 {
+        Utc::now().timestamp()
+    }
+
+    fn id_token(&self, client_id: &str, nonce: Option<&str>, access_token: Option<&str>) -> String {
+        let now = Self::now();
+        let mut claims = json!({
+            "iss": self.issuer,
+            "aud": client_id,
+            "iat": now,
+            "exp": now + self.token_lifetime as i64,
+            "auth_time": now,
+        });
+        let object = claims.as_object_mut().unwrap();
+        if let Value::Object(user) = &self.user {
+            object.extend(user.clone());
+        }
+        if let Some(nonce) = nonce {
+            object.insert("nonce".into(), nonce.into());
+        }
+        if let Some(access_token) = access_token {
+            object.insert("at_hash".into(), at_hash(access_token).into());
+        }
+        if let Value::Object(extra) = &self.extra_id_token_claims {
+            object.extend(extra.clone());
+        }
+        self.keys.sign(self.alg, json!({}), &claims)
+    }
+
+    fn access_token(&mut self, client_id: &str, scope: &str) -> String {
+        let now = Self::now();
+        let claims = json!({
+            "iss": self.issuer,
+            "sub": self.user["sub"],
+            "aud": RESOURCE_AUDIENCE,
+            "client_id": client_id,
+            "scope": scope,
+            "iat": now,
+            "exp": now + self.token_lifetime as i64,
+            "jti": random_string(16),
+        });
+        let token = self.keys.sign(self.alg, json!({"typ": "at+jwt"}), &claims);
+        self.access_tokens.insert(token.clone());
+        token
+    }
+
+    fn issue_tokens(&mut self, client_id: &str, scope: &str, nonce: Option<&str>) -> Value {
+        let access_token = self.access_token(client_id, scope);
+        let refresh_token = random_string(32);
+        self.refresh_tokens.insert(
+            refresh_token.clone(),
+            (client_id.to_string(), scope.to_string()),
+        );
+        let mut response = json!({
+            "token_type": "Bearer",
+            "access_token": access_token,
+            "refresh_token": refresh_token,
+            "expires_in": self.token_lifetime,
+            "scope": scope,
+        });
+        if scope.split(' ').any(|s| s == "openid") {
+            response["id_token"] = self.id_token(client_id, nonce, Some(&access_token)).into();
+        }
+        response
+    }
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Vec<u8>> {
+    let mut response = Response::new(body.to_string().into_bytes());
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert("content-type", HeaderValue::from_static("application/json"));
+    response
+}
+
+fn oauth_error(error: &str, description: &str) -> Response<Vec<u8>> {
+    json_response(
+        StatusCode::BAD_REQUEST,
+        json!({"error": error, "error_description": description}),
+    )
+}
+
+fn form(body: &[u8]) -> HashMap<String, String> {
+    form_urlencoded::parse(body).into_owned().collect()
+}
+
+fn handle(state: &Mutex<State>, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+    let mut state = state.lock().unwrap();
+    let path = request.uri().path().to_string();
+    *state.hits.entry(path.clone()).or_default() += 1;
+
+    match (request.method(), path.as_str()) {
+        (&Method::GET, "/.well-known/openid-configuration") => discovery(&state),
+        (&Method::GET, "/jwks") => {
+            let mut response = json_response(StatusCode::OK, state.keys.jwks());
+            response
+                .headers_mut()
+                .insert("cache-control", HeaderValue::from_static("max-age=300"));
+            response
+        }
+        (&Method::POST, "/devicecode") => device_authorization(&mut state, request.body()),
+        (&Method::POST, "/token") => token(&mut state, request.body()),
+        (&Method::POST, "/revoke") => {
+            if let Some(token) = form(request.body()).get("token") {
+                state.refresh_tokens.remove(token);
+                state.access_tokens.remove(token);
+            }
+            Response::new(Vec::new())
+        }
+        (&Method::GET, "/userinfo") => userinfo(&state, &request),
+        _ => {
+            let mut response = Response::new(b"Not Found".to_vec());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            response
+        }
+    }
+}
+
+fn discovery(state: &State) -> Response<Vec<u8>> {
+    let issuer = &state.issuer;
+    json_response(
+        StatusCode::OK,
+        json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}/authorize"),
+            "token_endpoint": format!("{issuer}/token"),
+            "device_authorization_endpoint": format!("{issuer}/devicecode"),
+            "revocation_endpoint": format!("{issuer}/revoke"),
+            "userinfo_endpoint": format!("{issuer}/userinfo"),
+            "jwks_uri": format!("{issuer}/jwks"),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256", "ES256"],
+            "scopes_supported": ["openid", "profile", "email", "offline_access"],
+        }),
+    )
+}
+
+fn device_authorization(state: &mut State, body: &[u8]) -> Response<Vec<u8>> {
+    let params = form(body);
+    let Some(client_id) = params.get("client_id") else {
+        return oauth_error("invalid_client", "client_id is required");
+    };
+
+    let user_code = random_string(8).to_uppercase();
+    let device_code = random_string(32);
+    let verification_uri = format!("{}/device", state.issuer);
+    state.grants.insert(
+        user_code.clone(),
+        DeviceGrant {
+            device_code: device_code.clone(),
+            client_id: client_id.clone(),
+            scope: params.get("scope").cloned().unwrap_or_default(),
+            nonce: params.get("nonce").filter(|_| state.echo_nonce).cloned(),
+            decision: state.auto_decision,
+            expires_at: Instant::now() + Duration::from_secs(state.expires_in),
+        },
+    );
+
+    json_response(
+        StatusCode::OK,
+        json!({
+            "device_code": device_code,
+            "user_code": user_code,
+            "verification_uri": verification_uri,
+            "verification_uri_complete": format!("{verification_uri}?user_code={user_code}"),
+            "expires_in": state.expires_in,
+            "interval": state.interval,
+        }),
+    )
+}
+
+fn token(state: &mut State, body: &[u8]) -> Response<Vec<u8>> {
+    let params = form(body);
+    match params.get("grant_type").map(String::as_str) {
+        Some(DEVICE_CODE_GRANT) => {
+            let device_code = params.get("device_code").cloned().unwrap_or_default();
+            let Some((user_code, grant)) = state
+                .grants
+                .iter()
+                .find(|(_, grant)| grant.device_code == device_code)
+            else {
+                return oauth_error("invalid_grant", "Unknown device code.");
+            };
+            let user_code = user_code.clone();
+
+            if grant.expires_at <= Instant::now() {
+                return oauth_error("expired_token", "The device code has expired.");
+            }
+            match grant.decision {
+                None => oauth_error("authorization_pending", "The user has not yet approved."),
+                Some(Decision::Deny) => {
+                    state.grants.remove(&user_code);
+                    oauth_error("access_denied", "The user denied the request.")
+                }
+                Some(Decision::Approve) => {
+                    let grant = state.grants.remove(&user_code).unwrap();
+                    let tokens =
+                        state.issue_tokens(&grant.client_id, &grant.scope, grant.nonce.as_deref());
+                    json_response(StatusCode::OK, tokens)
+                }
+            }
+        }
+        Some("refresh_token") => {
+            let refresh_token = params.get("refresh_token").cloned().unwrap_or_default();
+            match state.refresh_tokens.remove(&refresh_token) {
+                Some((client_id, scope)) => {
+                    let tokens = state.issue_tokens(&client_id, &scope, None);
+                    json_response(StatusCode::OK, tokens)
+                }
+                None => oauth_error("invalid_grant", "The refresh token is invalid."),
+            }
+        }
+        _ => oauth_error("unsupported_grant_type", "Unsupported grant type."),
+    }
+}
+
+fn userinfo(state: &State, request: &Request<Vec<u8>>) -> Response<Vec<u8>> {
+    let token = request
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match token {
+        Some(token) if state.access_tokens.contains(token) => {
+            json_response(StatusCode::OK, state.user.clone())
+        }
+        _ => {
+            let mut response = Response::new(Vec::new());
+            *response.status_mut() = StatusCode::UNAUTHORIZED;
+            response
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use http::{HeaderMap, Response};
+use opentelemetry_proto::tonic::{
+    collector::trace::v1::ExportTraceServiceRequest, trace::v1::Span,
+};
+use reqwest::Url;
+
+use super::http::serve;
+use crate::telemetry::otlp;
+
+/// Path the service exports spans to.
+pub const TRACES_PATH: &str = "/v1/traces";
+
+/// A request the collector received.
+pub struct Received {
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+/// An OTLP/HTTP collector, which also plays any provider a test sends
+/// requests to. The tracer provider is global, so every test shares it.
+pub struct Collector {
+    pub address: SocketAddr,
+    received: Arc<Mutex<Vec<Received>>>,
+}
+
+static COLLECTOR: OnceLock<Collector> = OnceLock::new();
+
+/// Starts the collector on its own runtime, which outlives any test's, and
+/// exports spans to it.
+pub fn start() -> &'static Collector {
+    COLLECTOR.get_or_init(|| {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let requests = received.clone();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let (address, server) = serve(Arc::new(move |request| {
+                    let (parts, body) = request.into_parts();
+                    requests.lock().unwrap().push(Received {
+                        path: parts.uri.path().to_string(),
+                        headers: parts.headers,
+                        body,
+                    });
+                    Response::new(b"{}".to_vec())
+                }))
+                .await
+                .unwrap();
+                tx.send(address).unwrap();
+                let _ = server.await;
+            });
+        });
+        let address = rx.recv().unwrap();
+        otlp::start(Some(
+            Url::parse(&format!("http://{address}{TRACES_PATH}")).unwrap(),
+        ));
+        Collector { address, received }
+    })
+}
+
+impl Collector {
+    /// Headers of the requests sent to `path`.
+    pub fn headers(&self, path: &str) -> Vec<HeaderMap> {
+        let received = self.received.lock().unwrap();
+        received
+            .iter()
+            .filter(|request| request.path == path)
+            .map(|request| request.headers.clone())
+            .collect()
+    }
+
+    /// Bodies of the span exports.
+    pub fn exports(&self) -> Vec<Vec<u8>> {
+        let received = self.received.lock().unwrap();
+        received
+            .iter()
+            .filter(|request| request.path == TRACES_PATH)
+            .map(|request| request.body.clone())
+            .collect()
+    }
+
+    /// The exported spans of the trace `trace_id`, in hex.
+    pub fn spans(&self, trace_id: &str) -> Vec<Span> {
+        self.exports()
+            .iter()
+            .map(|body| serde_json::from_slice::<ExportTraceServiceRequest>(body).unwrap())
+            .flat_map(|request| request.resource_spans)
+            .flat_map(|resource| resource.scope_spans)
+            .flat_map(|scope| scope.spans)
+            .filter(|span| hex(&span.trace_id) == trace_id)
+            .collect()
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
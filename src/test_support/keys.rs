@@ -0,0 +1,140 @@
+use std::sync::OnceLock;
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use p256::ecdsa::{Signature, SigningKey, signature::Signer};
+use rand::{Rng, distributions::Alphanumeric, rngs::OsRng};
+use rsa::{Pkcs1v15Sign, RsaPrivateKey, traits::PublicKeyParts};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+/// Generating an RSA key in a debug build takes seconds, so every server in
+/// the test process starts from the same one. Rotation creates fresh keys.
+static SHARED_RSA_KEY: OnceLock<RsaPrivateKey> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SigningAlgorithm {
+    Rs256,
+    Es256,
+}
+
+impl SigningAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SigningAlgorithm::Rs256 => "RS256",
+            SigningAlgorithm::Es256 => "ES256",
+        }
+    }
+}
+
+/// An RSA and a P-256 key pair, published together in the JWKS.
+pub struct SigningKeys {
+    rsa: RsaPrivateKey,
+    rsa_kid: String,
+    ec: SigningKey,
+    ec_kid: String,
+}
+
+pub fn random_string(len: usize) -> String {
+    OsRng
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+pub fn base64url(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+impl SigningKeys {
+    pub fn generate() -> Self {
+        let rsa = SHARED_RSA_KEY
+            .get_or_init(|| RsaPrivateKey::new(&mut OsRng, 2048).expect("RSA key generation"))
+            .clone();
+        Self {
+            rsa,
+            rsa_kid: format!("rsa-{}", random_string(8)),
+            ec: SigningKey::random(&mut OsRng),
+            ec_kid: format!("ec-{}", random_string(8)),
+        }
+    }
+
+    /// Replaces both key pairs (and their `kid`s) with freshly generated ones.
+    pub fn rotate(&mut self) {
+        self.rsa = RsaPrivateKey::new(&mut OsRng, 2048).expect("RSA key generation");
+        self.rsa_kid = format!("rsa-{}", random_string(8));
+        self.ec = SigningKey::random(&mut OsRng);
+        self.ec_kid = format!("ec-{}", random_string(8));
+    }
+
+    pub fn kid(&self, alg: SigningAlgorithm) -> &str {
+        match alg {
+            SigningAlgorithm::Rs256 => &self.rsa_kid,
+            SigningAlgorithm::Es256 => &self.ec_kid,
+        }
+    }
+
+    pub fn jwks(&self) -> Value {
+        let point = self.ec.verifying_key().to_encoded_point(false);
+        json!({
+            "keys": [
+                {
+                    "kty": "RSA",
+                    "use": "sig",
+                    "alg": "RS256",
+                    "kid": self.rsa_kid,
+                    "n": base64url(&self.rsa.n().to_bytes_be()),
+                    "e": base64url(&self.rsa.e().to_bytes_be()),
+                },
+                {
+                    "kty": "EC",
+                    "use": "sig",
+                    "alg": "ES256",
+                    "crv": "P-256",
+                    "kid": self.ec_kid,
+                    "x": base64url(point.x().expect("uncompressed point")),
+                    "y": base64url(point.y().expect("uncompressed point")),
+                }
+            ]
+        })
+    }
+
+    /// Signs `claims` as a compact JWS. `header` is merged over the default
+    /// `alg`/`kid`/`typ` header, so tests can forge unusual headers.
+    pub fn sign(&self, alg: SigningAlgorithm, header: Value, claims: &Value) -> String {
+        let mut full_header = json!({
+            "alg": alg.as_str(),
+            "kid": self.kid(alg),
+            "typ": "JWT",
+        });
+        if let (Some(target), Value::Object(extra)) = (full_header.as_object_mut(), header) {
+            target.extend(extra);
+        }
+
+        let signing_input = format!(
+            "{}.{}",
+            base64url(full_header.to_string().as_bytes()),
+            base64url(claims.to_string().as_bytes())
+        );
+        let signature = match alg {
+            SigningAlgorithm::Rs256 => self
+                .rsa
+                .sign(
+                    Pkcs1v15Sign::new::<Sha256>(),
+                    &Sha256::digest(signing_input.as_bytes()),
+                )
+                .expect("RSA signing"),
+            SigningAlgorithm::Es256 => {
+                let signature: Signature = self.ec.sign(signing_input.as_bytes());
+                signature.to_bytes().to_vec()
+            }
+        };
+        format!("{signing_input}.{}", base64url(&signature))
+    }
+}
+
+/// The `at_hash` claim: the left half of the SHA-256 of the access token.
+pub fn at_hash(access_token: &str) -> String {
+    let digest = Sha256::digest(access_token.as_bytes());
+    base64url(&digest[..digest.len() / 2])
+}